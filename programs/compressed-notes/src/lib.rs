@@ -1,39 +1,192 @@
 use anchor_lang::{
     prelude::*, 
-    solana_program::keccak
+    Discriminator,
+    system_program,
+    solana_program::{
+        compute_units::sol_remaining_compute_units,
+        ed25519_program, hash, keccak,
+        instruction::Instruction,
+        program::{invoke, set_return_data},
+        pubkey,
+        sysvar::instructions::{load_current_index_checked, load_instruction_at_checked},
+    },
 };
 use spl_account_compression::{
+    Node,
     Noop,
-    program::SplAccountCompression,
     cpi::{
-        accounts::{Initialize, Modify, VerifyLeaf},
-        init_empty_merkle_tree, verify_leaf, replace_leaf, append, 
+        accounts::{CloseTree, Initialize, Modify},
+        init_empty_merkle_tree, replace_leaf, append, close_empty_tree,
     },
+    canopy::fill_in_proof_from_canopy,
+    concurrent_tree_wrapper::{merkle_tree_prove_leaf, ProveLeafArgs},
+    state::{merkle_tree_get_size, ConcurrentMerkleTreeHeader, CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1},
+    events::{AccountCompressionEvent, ApplicationDataEvent, ApplicationDataEventV1},
     wrap_application_data_v1, 
 };
+use std::{borrow::Cow, mem::size_of};
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
 
 declare_id!("2CCvZS82NbYtLDuQgegGJB9pspjMizama2tQQy8Vu6Ps");
 
-// STRUCTS GO HERE
+// Logs a diagnostic message, only in builds with the debug-logs feature so release builds don't
+// spend compute on it. Messages explaining why an instruction failed use msg! in every build.
+macro_rules! debug_msg {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "debug-logs")]
+        msg!($($arg)*);
+    };
+}
+
+// Max length of a note in bytes
+pub const MAX_NOTE_LEN: usize = 512;
+
+// Max length of a note assembled in a scratch account, the cap on the rent and log size of a long note
+pub const MAX_SCRATCH_NOTE_LEN: usize = 4096;
+
+// Max length of a note title in bytes
+pub const MAX_TITLE_LEN: usize = 64;
+
+// Max number of tags on a note
+pub const MAX_TAGS: usize = 5;
+
+// Max length of a note tag in bytes
+pub const MAX_TAG_LEN: usize = 32;
+
+// Max length of a note content type in bytes
+pub const MAX_CONTENT_TYPE_LEN: usize = 32;
+
+// Content type logged for notes appended without one
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain";
+
+// Max number of notes in a batch append, each append is a CPI so this keeps the batch within the compute budget
+pub const MAX_BATCH: usize = 8;
+
+// Number of bits in a note filter, kept small enough for the filter to be created in a single instruction
+pub const FILTER_BITS: usize = 8192;
+
+// Number of bits set in a note filter for each leaf
+pub const FILTER_HASHES: usize = 3;
+
+// Rough compute cost of verifying and replacing a leaf, not counting the proof
+pub const UPDATE_BASE_COST: u64 = 20_000;
+
+// Rough compute cost per level of the tree of verifying and replacing a leaf
+pub const UPDATE_COST_PER_LEVEL: u64 = 2_500;
+
+// Max number of notes updated in one update_notes instruction, each update costs about as much
+// compute as a single update_note
+pub const MAX_UPDATE_BATCH: usize = 4;
+
+// Max number of notes checked in one verify_notes instruction, each proof is checked in this
+// program without a CPI so checks are cheaper than updates
+pub const MAX_VERIFY_BATCH: usize = 16;
+
+// Max number of notes an owner can have in a tree with owner quotas enabled
+pub const MAX_NOTES_PER_OWNER: u64 = 16;
+
+// Max number of trees an admin can list in their tree registry
+pub const MAX_REGISTRY_TREES: usize = 32;
+
+// Vetted (max_depth, max_buffer_size, canopy_depth) presets for the create_*_tree instructions.
+// The canopies leave proofs of 10 nodes, which fit in a transaction alongside other instructions.
+pub const SMALL_TREE_PRESET: (u32, u32, u32) = (14, 64, 4); // 16,384 notes
+pub const MEDIUM_TREE_PRESET: (u32, u32, u32) = (20, 256, 10); // 1,048,576 notes
+pub const LARGE_TREE_PRESET: (u32, u32, u32) = (24, 1024, 14); // 16,777,216 notes
+
+// Max depth of a tree this program supports. The compression program takes leaf indices as u32
+// in its instructions, so every leaf of a tree of depth 31 still has an index that fits, and each
+// instruction here takes a u32 index for the same reason. Counters of leaves, which can reach
+// 2^max_depth, are u64.
+pub const MAX_SUPPORTED_DEPTH: u32 = 31;
+
+// (max_depth, max_buffer_size) pairs supported by spl-account-compression
+pub const SUPPORTED_TREE_CONFIGS: [(u32, u32); 34] = [
+    (3, 8),
+    (5, 8),
+    (6, 16),
+    (7, 16),
+    (8, 16),
+    (9, 16),
+    (10, 32),
+    (11, 32),
+    (12, 32),
+    (13, 32),
+    (14, 64),
+    (14, 256),
+    (14, 1024),
+    (14, 2048),
+    (15, 64),
+    (16, 64),
+    (17, 64),
+    (18, 64),
+    (19, 64),
+    (20, 64),
+    (20, 256),
+    (20, 1024),
+    (20, 2048),
+    (24, 64),
+    (24, 256),
+    (24, 512),
+    (24, 1024),
+    (24, 2048),
+    (26, 512),
+    (26, 1024),
+    (26, 2048),
+    (30, 512),
+    (30, 1024),
+    (30, 2048),
+];
+
+// Every supported config must have leaf indices that fit in a u32
+const _: () = {
+    let mut i = 0;
+    while i < SUPPORTED_TREE_CONFIGS.len() {
+        assert!(SUPPORTED_TREE_CONFIGS[i].0 <= MAX_SUPPORTED_DEPTH);
+        i += 1;
+    }
+};
 
 #[program]
 pub mod compressed_notes {
     use super::*;
 
     // Instruction for creating a new note tree.
+    //
+    // The merkle tree account must already be allocated by the client with room for the canopy.
+    // A canopy caches the top `canopy_depth` levels of the tree on-chain, so proofs only need
+    // `max_depth - canopy_depth` nodes. Each extra level doubles the cached nodes, costing
+    // `(2^(canopy_depth + 1) - 2) * 32` bytes of rent in total.
+    //
+    // Instructions that verify or replace a leaf take its proof as remaining accounts, one
+    // readonly account per node ordered from the leaf up, and fail with ProofLengthMismatch
+    // unless exactly `max_depth - canopy_depth` nodes are passed.
     pub fn create_note_tree(
-        ctx: Context<NoteAccounts>,
+        ctx: Context<CreateNoteTreeAccounts>,
         max_depth: u32,       // Max depth of the merkle tree
         max_buffer_size: u32, // Max buffer size of the merkle tree
+        canopy_depth: u32,    // Number of upper levels of the tree cached on-chain
     ) -> Result<()> {
+        check_tree_config(max_depth, max_buffer_size)?;
+        if canopy_depth > max_depth {
+            return err!(CompressedNotesError::InvalidCanopyDepth);
+        }
+        // The account must be sized exactly for the tree and its canopy
+        if ctx.accounts.merkle_tree.data_len()
+            != merkle_tree_account_size(max_depth, max_buffer_size, canopy_depth)
+        {
+            return err!(CompressedNotesError::TreeAccountSizeMismatch);
+        }
+        check_rent_exempt(&ctx.accounts.merkle_tree)?;
+        // Fail before the init CPI, which gives a cryptic error for an initialized tree
+        if is_tree_initialized(&ctx.accounts.merkle_tree)? {
+            return err!(CompressedNotesError::TreeAlreadyInitialized);
+        }
+
         // Get the address for the merkle tree account
         let merkle_tree = ctx.accounts.merkle_tree.key();
 
-        // Define the seeds for pda signing
-        let signer_seeds: &[&[&[u8]]] = &[&[
-            merkle_tree.as_ref(), // The address of the merkle tree account as a seed
-            &[*ctx.bumps.get("tree_authority").unwrap()], // The bump seed for the pda
-        ]];
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
 
         // Create cpi context for init_empty_merkle_tree instruction.
         let cpi_ctx = CpiContext::new_with_signer(
@@ -48,138 +201,4795 @@ pub mod compressed_notes {
 
         // CPI to initialize an empty merkle tree with given max depth and buffer size
         init_empty_merkle_tree(cpi_ctx, max_depth, max_buffer_size)?;
+
+        // The creator of the tree is its first admin
+        ctx.accounts.admin_config.admin = ctx.accounts.owner.key();
+
+        // List the tree in the creator's registry, if they keep one
+        if let Some(tree_registry) = &mut ctx.accounts.tree_registry {
+            tree_registry.register(ctx.accounts.merkle_tree.key())?;
+        }
         Ok(())
     }
 
-    // Instruction for appending a note to a tree.
-    pub fn append_note(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
-        // Hash the "note message" which will be stored as leaf node in the merkle tree
-        let leaf_node =
-            keccak::hashv(&[note.as_bytes(), ctx.accounts.owner.key().as_ref()]).to_bytes();
-        // Create a new "note log" using the leaf node hash and note.
-        let note_log = NoteLog::new(leaf_node.clone(), ctx.accounts.owner.key().clone(), note);
-        // Log the "note log" data using noop program
-        wrap_application_data_v1(note_log.try_to_vec()?, &ctx.accounts.log_wrapper)?;
+    // Instructions for creating a note tree with one of the presets, for users who don't want to
+    // choose the tree parameters themselves. The account must be allocated for the preset.
+    pub fn create_small_tree(ctx: Context<CreateNoteTreeAccounts>) -> Result<()> {
+        let (max_depth, max_buffer_size, canopy_depth) = SMALL_TREE_PRESET;
+        create_note_tree(ctx, max_depth, max_buffer_size, canopy_depth)
+    }
+
+    pub fn create_medium_tree(ctx: Context<CreateNoteTreeAccounts>) -> Result<()> {
+        let (max_depth, max_buffer_size, canopy_depth) = MEDIUM_TREE_PRESET;
+        create_note_tree(ctx, max_depth, max_buffer_size, canopy_depth)
+    }
+
+    pub fn create_large_tree(ctx: Context<CreateNoteTreeAccounts>) -> Result<()> {
+        let (max_depth, max_buffer_size, canopy_depth) = LARGE_TREE_PRESET;
+        create_note_tree(ctx, max_depth, max_buffer_size, canopy_depth)
+    }
+
+    // Instruction for creating a note tree only if it doesn't exist yet, for use in deploy scripts.
+    //
+    // An existing tree must be a note tree of this program and match the requested depth and buffer
+    // size.
+    // Emits NoteTreeEnsured with whether the tree was created.
+    pub fn ensure_note_tree(
+        ctx: Context<EnsureNoteTreeAccounts>,
+        max_depth: u32,       // Max depth of the merkle tree
+        max_buffer_size: u32, // Max buffer size of the merkle tree
+        canopy_depth: u32,    // Number of upper levels of the tree cached on-chain, only used when creating
+    ) -> Result<()> {
         // Get the address for the merkle tree account
         let merkle_tree = ctx.accounts.merkle_tree.key();
-        // Define the seeds for pda signing
-        let signer_seeds: &[&[&[u8]]] = &[&[
-            merkle_tree.as_ref(), // The address of the merkle tree account as a seed
-            &[*ctx.bumps.get("tree_authority").unwrap()], // The bump seed for the pda
-        ]];
-        // Create a new cpi context and append the leaf node to the merkle tree.
+
+        if is_tree_initialized(&ctx.accounts.merkle_tree)? {
+            let data = ctx.accounts.merkle_tree.try_borrow_data()?;
+            let header = load_tree_header(&data)?;
+            if !is_tree_of(&ctx.accounts.merkle_tree, &ctx.accounts.tree_authority.key()) {
+                return err!(CompressedNotesError::NotANoteTree);
+            }
+            if header.get_max_depth() != max_depth || header.get_max_buffer_size() != max_buffer_size {
+                return err!(CompressedNotesError::TreeParamsMismatch);
+            }
+            // A note tree gets its admin config when created, so a config without an admin was only
+            // just created for a tree made some other way, e.g. a messages tree
+            let admin_config = &ctx.accounts.admin_config;
+            if admin_config.admin == Pubkey::default() && !admin_config.renounced {
+                return err!(CompressedNotesError::NotANoteTree);
+            }
+
+            emit!(NoteTreeEnsured {
+                tree: merkle_tree,
+                created: false,
+            });
+            return Ok(());
+        }
+
+        check_tree_config(max_depth, max_buffer_size)?;
+        if canopy_depth > max_depth {
+            return err!(CompressedNotesError::InvalidCanopyDepth);
+        }
+        // The account must be sized exactly for the tree and its canopy
+        if ctx.accounts.merkle_tree.data_len()
+            != merkle_tree_account_size(max_depth, max_buffer_size, canopy_depth)
+        {
+            return err!(CompressedNotesError::TreeAccountSizeMismatch);
+        }
+        check_rent_exempt(&ctx.accounts.merkle_tree)?;
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        // Create cpi context for init_empty_merkle_tree instruction.
         let cpi_ctx = CpiContext::new_with_signer(
             ctx.accounts.compression_program.to_account_info(), // The spl account compression program
-            Modify {
+            Initialize {
                 authority: ctx.accounts.tree_authority.to_account_info(), // The authority for the merkle tree, using a PDA
-                merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to be modified
+                merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to be initialized
                 noop: ctx.accounts.log_wrapper.to_account_info(), // The noop program to log data
             },
             signer_seeds, // The seeds for pda signing
         );
+
+        // CPI to initialize an empty merkle tree with given max depth and buffer size
+        init_empty_merkle_tree(cpi_ctx, max_depth, max_buffer_size)?;
+
+        // The creator of the tree is its first admin
+        ctx.accounts.admin_config.admin = ctx.accounts.owner.key();
+
+        emit!(NoteTreeEnsured {
+            tree: merkle_tree,
+            created: true,
+        });
+        Ok(())
+    }
+
+    // Instruction for handing the admin role for a tree to a new key, such as a multisig.
+    //
+    // The new admin can't be the default key, which is also the system program. No one can sign for
+    // it, so the tree would be locked like a renounced one without being marked renounced, which
+    // only renounce_admin is for.
+    pub fn transfer_admin(ctx: Context<AdminConfigAccounts>, new_admin: Pubkey) -> Result<()> {
+        if new_admin == Pubkey::default() || new_admin == system_program::ID {
+            return err!(CompressedNotesError::InvalidNewAdmin);
+        }
+        ctx.accounts.admin_config.admin = new_admin;
+        Ok(())
+    }
+
+    // Instruction for the admin to give up the admin role for a tree, permanently.
+    //
+    // This is one-way and can't be undone. The admin is set to the system program, which no one can
+    // sign for, so every admin-gated instruction fails with Unauthorized from then on. The tree is
+    // locked with the settings it has at the time, including its fee, allowlist, quota and pause
+    // state, and can no longer be closed. Renouncing a paused tree leaves it paused for good.
+    pub fn renounce_admin(ctx: Context<AdminConfigAccounts>) -> Result<()> {
+        let admin_config = &mut ctx.accounts.admin_config;
+        admin_config.admin = system_program::ID;
+        admin_config.renounced = true;
+        Ok(())
+    }
+
+    // Instruction for the admin to grow an admin config created before its latest fields were added.
+    //
+    // A config in an older layout is too short to load as an AdminConfig, so every instruction of
+    // its tree fails until it is upgraded. The account is reallocated to LEN with the new bytes
+    // zeroed, which gives the added fields their defaults: keccak leaves, no fee, no log mirror,
+    // and quotas, the allowlist and pausing off. The admin, the first field of every layout, keeps its value and
+    // pays the rent for the extra bytes. Upgrading a config already in the current layout does
+    // nothing.
+    pub fn upgrade_admin_config(ctx: Context<UpgradeAdminConfigAccounts>) -> Result<()> {
+        let admin_config = ctx.accounts.admin_config.to_account_info();
+        {
+            let data = admin_config.try_borrow_data()?;
+            if data.len() < 8 + 32 || data[..8] != AdminConfig::DISCRIMINATOR {
+                return err!(ErrorCode::AccountDiscriminatorMismatch);
+            }
+            if data[8..8 + 32] != ctx.accounts.admin.key().to_bytes() {
+                return err!(CompressedNotesError::Unauthorized);
+            }
+        }
+        if admin_config.data_len() >= AdminConfig::LEN {
+            return Ok(());
+        }
+
+        let minimum_balance = Rent::get()?.minimum_balance(AdminConfig::LEN);
+        let shortfall = minimum_balance.saturating_sub(admin_config.lamports());
+        if shortfall > 0 {
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.admin.to_account_info(),
+                    to: admin_config.clone(),
+                },
+            );
+            system_program::transfer(cpi_ctx, shortfall)?;
+        }
+        admin_config.realloc(AdminConfig::LEN, true)?;
+
+        // The upgraded config must load in the current layout
+        AdminConfig::try_deserialize(&mut &admin_config.try_borrow_data()?[..])?;
+        Ok(())
+    }
+
+    // Instruction for turning per-owner note quotas on or off for a tree.
+    //
+    // With quotas on, every append must pass the owner's quota account and is rejected once the
+    // owner has MAX_NOTES_PER_OWNER notes in the tree. Deleting a note frees a slot. Notes appended
+    // with quotas off are not counted, so quotas can only be turned on while the tree is empty.
+    pub fn set_owner_quota(ctx: Context<AdminConfigAccounts>, enabled: bool) -> Result<()> {
+        if enabled && !is_tree_empty(&ctx.accounts.merkle_tree)? {
+            return err!(CompressedNotesError::TreeNotEmpty);
+        }
+        ctx.accounts.admin_config.quota_enabled = enabled;
+        Ok(())
+    }
+
+    // Instruction for creating the registry listing the trees created by an admin.
+    //
+    // Trees are only listed when the registry is passed to create_note_tree, so it should be
+    // created before the trees it lists. The registry grows by one key per tree, paid by the admin.
+    pub fn create_tree_registry(_ctx: Context<CreateTreeRegistryAccounts>) -> Result<()> {
+        // The registry starts with no trees
+        Ok(())
+    }
+
+    // Instruction for reserving room for `additional` more trees in an admin's registry.
+    //
+    // The registry otherwise grows by one key per created tree, so growing it ahead of time moves
+    // the rent out of create_note_tree. Listed trees are kept, and the registry never shrinks
+    // below the trees it lists.
+    pub fn grow_registry(ctx: Context<GrowRegistryAccounts>, _additional: u32) -> Result<()> {
+        // The registry was already reallocated by the realloc constraint
+        let capacity = TreeRegistry::capacity(ctx.accounts.tree_registry.to_account_info().data_len());
+        if capacity > MAX_REGISTRY_TREES {
+            return err!(CompressedNotesError::RegistryFull);
+        }
+        Ok(())
+    }
+
+    // Instruction for removing a tree from an admin's registry, e.g. once the tree is closed.
+    //
+    // The registry keeps its size, so the freed slot stays reserved for the next listed tree
+    // along with any room reserved by grow_registry.
+    pub fn deregister_tree(ctx: Context<DeregisterTreeAccounts>) -> Result<()> {
+        ctx.accounts.tree_registry.deregister(&ctx.accounts.merkle_tree.key())
+    }
+
+    // Instruction for the admin to select the hash notes are hashed into leaves with.
+    //
+    // Only the leaf preimage is affected, the compression program still hashes the nodes of the
+    // tree with keccak. Leaves already in the tree would stop verifying, so the hash can only be
+    // changed while the tree is empty.
+    pub fn set_hash_algo(ctx: Context<AdminConfigAccounts>, hash_algo: u8) -> Result<()> {
+        if hash_algo != HASH_ALGO_KECCAK && hash_algo != HASH_ALGO_SHA256 {
+            return err!(CompressedNotesError::InvalidHashAlgo);
+        }
+        if !is_tree_empty(&ctx.accounts.merkle_tree)? {
+            return err!(CompressedNotesError::TreeNotEmpty);
+        }
+        ctx.accounts.admin_config.hash_algo = hash_algo;
+        Ok(())
+    }
+
+    // Instruction for turning the owner allowlist on or off for a tree.
+    //
+    // With the allowlist on, every append must pass the owner's AllowedOwner account, which only
+    // the admin can create with add_to_allowlist. Trees are permissionless with it off, the default.
+    pub fn set_allowlist(ctx: Context<AdminConfigAccounts>, enabled: bool) -> Result<()> {
+        ctx.accounts.admin_config.allowlist_enabled = enabled;
+        Ok(())
+    }
+
+    // Instruction for pausing or unpausing a tree in an emergency.
+    //
+    // While paused, every instruction that appends, updates or deletes notes fails with
+    // ProgramPaused. Verifying and proving notes stay allowed.
+    pub fn set_paused(ctx: Context<AdminConfigAccounts>, paused: bool) -> Result<()> {
+        ctx.accounts.admin_config.paused = paused;
+        Ok(())
+    }
+
+    // Instruction for setting the fee charged per appended note, and the treasury it is paid to.
+    //
+    // A zero fee turns fees off, the default. With a fee set, appends must pass the treasury.
+    pub fn set_fee(ctx: Context<AdminConfigAccounts>, fee_lamports: u64, treasury: Pubkey) -> Result<()> {
+        let admin_config = &mut ctx.accounts.admin_config;
+        admin_config.fee_lamports = fee_lamports;
+        admin_config.treasury = treasury;
+        Ok(())
+    }
+
+    // Instruction for setting the program the logs of the tree may be mirrored to.
+    //
+    // Instructions that log accept log_wrapper_mirror only when it is this program, which is invoked
+    // with the same instruction data as the noop program, so it must accept any data without
+    // accounts. The default key, the initial value, turns mirroring off.
+    pub fn set_log_mirror(ctx: Context<AdminConfigAccounts>, log_mirror: Pubkey) -> Result<()> {
+        ctx.accounts.admin_config.log_mirror = log_mirror;
+        Ok(())
+    }
+
+    // Instruction for the admin to allow an owner to append to a tree with the allowlist on.
+    pub fn add_to_allowlist(_ctx: Context<AddToAllowlistAccounts>) -> Result<()> {
+        // The owner is allowed for as long as the account exists
+        Ok(())
+    }
+
+    // Instruction for the admin to stop an owner appending to a tree, refunding the allowlist rent.
+    //
+    // Notes the owner already appended are kept, and the owner can still update and delete them.
+    pub fn remove_from_allowlist(_ctx: Context<RemoveFromAllowlistAccounts>) -> Result<()> {
+        // The allowed owner account is closed by the close constraint
+        Ok(())
+    }
+
+    // Instruction for creating the quota account that counts an owner's notes in a tree.
+    pub fn create_owner_quota(_ctx: Context<CreateOwnerQuotaAccounts>) -> Result<()> {
+        // The owner starts with no notes
+        Ok(())
+    }
+
+    // Instruction for appending a note to a tree.
+    //
+    // Tags are only written to the NoteLog for indexers to filter on. They aren't part of the leaf
+    // hash, so unlike the note they aren't tamper-evident and can't be proven against the tree.
+    // The parent is the index of the note this one replies to, also only logged, for indexers to
+    // build threads from. It must be an index already appended to the tree.
+    // The content type is a MIME hint for clients rendering the note, e.g. "text/markdown". It is
+    // also only logged, and defaults to DEFAULT_CONTENT_TYPE when omitted.
+    //
+    // The index and leaf of the note are set as return data, packed as 40 bytes: the index as a
+    // little-endian u64 followed by the 32 byte leaf. They are all a client needs to keep to update
+    // or delete the note later, without parsing the log.
+    // When log_wrapper_mirror is passed, the NoteLog is also logged a second time through it, for
+    // integrators that index the log twice, as every other note instruction that logs does. It must
+    // be the log mirror the admin set with set_log_mirror.
+    // The nonce tells apart notes with the same text. When set, it is hashed into the leaf with
+    // hash_nonced_note and logged, and the note is updated, deleted, transferred and verified with
+    // the nonced variants of those instructions. Nonced notes can't be archived.
+    pub fn append_note(
+        ctx: Context<NoteAccounts>,
+        note: String,
+        tags: Vec<String>,
+        parent: Option<u64>,
+        content_type: Option<String>,
+        nonce: Option<u64>,
+    ) -> Result<()> {
+        let content_type = content_type.unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string());
+        // A note without a title hashes the same as a note with an empty title
+        append_note_leaf(ctx, String::new(), note, tags, parent, content_type, None, nonce, true)
+    }
+
+    // Instruction for checking that a set of note accounts is wired correctly, without modifying anything.
+    //
+    // Takes the accounts of append_note and fails on the first account that is wrong, with that
+    // account's own error: ConstraintSeeds for a tree authority, note counter or admin config of
+    // another tree, WrongAccountOwner for a merkle tree not owned by the compression program,
+    // NotOurTree for a tree of another authority, ProgramPaused for a paused tree, and
+    // InvalidProgramId for the wrong log wrapper or compression program. The tree header is also
+    // loaded, so a tree that was never initialized fails too. Pass none of the optional accounts.
+    pub fn preflight(ctx: Context<NoteAccounts>) -> Result<()> {
+        let data = ctx.accounts.merkle_tree.try_borrow_data()?;
+        load_tree_header(&data)?;
+        Ok(())
+    }
+
+    // Instruction for appending a note without logging it, which saves the cost of the log.
+    //
+    // Only the leaf is appended, so indexers reading the NoteLog won't see the note. The leaf
+    // still shows up in the change log the compression program emits, which is all an indexer
+    // needs if it tracks leaves rather than note contents.
+    pub fn append_note_nolog(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
+        append_note_leaf(ctx, String::new(), note, Vec::new(), None, DEFAULT_CONTENT_TYPE.to_string(), None, None, false)
+    }
+
+    // Instruction for appending a note with a title to a tree.
+    pub fn append_titled_note(ctx: Context<NoteAccounts>, title: String, note: String) -> Result<()> {
+        append_note_leaf(ctx, title, note, Vec::new(), None, DEFAULT_CONTENT_TYPE.to_string(), None, None, true)
+    }
+
+    // Instruction for appending a note that expires at a unix timestamp.
+    //
+    // The tree can't delete notes by itself, so expiry is enforced when the note is accessed. The
+    // expiry is hashed into the leaf, and the note is updated and verified with update_expiring_note
+    // and verify_expiring_note, which fail with NoteExpired once it has passed. From then on anyone
+    // can delete the note with delete_expired_note. An expiry that has already passed is rejected.
+    pub fn append_expiring_note(ctx: Context<NoteAccounts>, note: String, expires_at: i64) -> Result<()> {
+        append_note_leaf(ctx, String::new(), note, Vec::new(), None, DEFAULT_CONTENT_TYPE.to_string(), Some(expires_at), None, true)
+    }
+
+    // Instruction for appending a note and then giving it a title in the same instruction.
+    //
+    // The root changes with the append, so the client can't know the root to replace against.
+    // It is read from the tree account after the append instead, and the remaining accounts must
+    // hold the proof of the new leaf against that root.
+    pub fn append_then_tag<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        note: String,
+        title: String,
+    ) -> Result<()> {
+        validate_note(&note)?;
+        validate_title(&title)?;
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        // Append the untitled note
+        let leaf = hash_note(&note, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        let note_log = NoteLog::new(leaf, owner, ctx.accounts.merkle_tree.key(), note.clone(), index)?;
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+        append(modify_ctx(ctx.accounts, signer_seeds), leaf)?;
+
+        count_appended_notes(&mut ctx.accounts.note_counter, index, 1)?;
+
+        emit!(NoteAppended {
+            tree: merkle_tree,
+            index,
+            owner,
+            leaf,
+        });
+
+        // The root after the append, which the title is replaced against
+        let root = get_current_root(&ctx.accounts.merkle_tree)?;
+
+        let new_leaf = hash_titled_note(&title, &note, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+        let note_log = NoteLog {
+            title: normalize_note(&title).into_owned(),
+            prev_leaf: leaf,
+            ..NoteLog::new(new_leaf, owner, ctx.accounts.merkle_tree.key(), note, index)?
+        };
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // Indexes are bounded by the max depth of the tree, so always fit in a u32
+        let leaf_index = index as u32;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+            .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+        // CPI to replace the untitled leaf with the titled one
+        replace_leaf(cpi_ctx, root, leaf, new_leaf, leaf_index)?;
+
+        record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index)?;
+        emit!(NoteUpdated {
+            tree: merkle_tree,
+            index,
+            owner,
+            leaf: new_leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for creating the duplicate filter used by append_unique_note.
+    pub fn create_note_filter(_ctx: Context<CreateNoteFilterAccounts>) -> Result<()> {
+        // The filter starts with every bit clear
+        Ok(())
+    }
+
+    // Instruction for appending a note, rejecting notes that have likely been appended before.
+    //
+    // Each appended leaf sets FILTER_HASHES bits of the tree's note filter, and a leaf whose bits are
+    // all already set is rejected with DuplicateNote. The filter is a bloom filter, so a note that was
+    // never appended can still be rejected: after `n` unique appends the chance of a false positive
+    // is about `(1 - e^(-FILTER_HASHES * n / FILTER_BITS))^FILTER_HASHES`, roughly 0.5% after 500
+    // notes and 3% after 1000. Bits are never cleared, so a deleted note also stays rejected.
+    // Callers that hit a false positive can fall back to append_note, which doesn't use the filter.
+    pub fn append_unique_note(ctx: Context<UniqueNoteAccounts>, note: String) -> Result<()> {
+        validate_note(&note)?;
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+        // Hash the "note message" which will be stored as leaf node in the merkle tree
+        let leaf_node = hash_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        // Reject the note if it has likely been appended, otherwise record it
+        ctx.accounts.note_filter.insert(&leaf_node)?;
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        // Create a new "note log" using the leaf node hash and note.
+        let note_log = NoteLog::new(leaf_node, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), note, index)?;
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+        // Create a new cpi context and append the leaf node to the merkle tree.
+        let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds);
         // CPI to append the leaf node to the merkle tree
         append(cpi_ctx, leaf_node)?;
+
+        count_appended_notes(&mut ctx.accounts.note_counter, index, 1)?;
+
+        record_latest_note(&mut ctx.accounts.latest_note, leaf_node, index)?;
+        emit!(NoteAppended {
+            tree: merkle_tree,
+            index,
+            owner: ctx.accounts.owner.key(),
+            leaf: leaf_node,
+        });
         Ok(())
     }
 
-    pub fn update_note(
-        ctx: Context<NoteAccounts>,
+    // Instruction for appending a note owned by `note_owner`, with a separate payer covering the fees.
+    pub fn append_note_for(
+        ctx: Context<AppendNoteForAccounts>,
+        note: String,
+        note_owner: Pubkey, // The owner the note is bound to, does not need to sign
+    ) -> Result<()> {
+        validate_note(&note)?;
+        // The note owner must be allowed and is charged the quota, not the payer
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+        // The payer covers the append fee
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.payer, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+        // Hash the note against its owner rather than the payer
+        let leaf_node = hash_note(&note, &note_owner, &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        // Create a new "note log" using the leaf node hash and note.
+        let note_log = NoteLog {
+            appended_by: ctx.accounts.payer.key(),
+            ..NoteLog::new(leaf_node, note_owner, ctx.accounts.merkle_tree.key(), note, index)?
+        };
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+        // Create a new cpi context and append the leaf node to the merkle tree.
+        let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds);
+        // CPI to append the leaf node to the merkle tree
+        append(cpi_ctx, leaf_node)?;
+
+        count_appended_notes(&mut ctx.accounts.note_counter, index, 1)?;
+
+        record_latest_note(&mut ctx.accounts.latest_note, leaf_node, index)?;
+        emit!(NoteAppended {
+            tree: merkle_tree,
+            index,
+            owner: note_owner,
+            leaf: leaf_node,
+        });
+        Ok(())
+    }
+
+    // Instruction for appending several notes to a tree in one instruction.
+    //
+    // Appends need no proof, so the notes are appended one after another. Returns the index of the
+    // first note and the number appended, the note at position i of the batch is at start_index + i.
+    pub fn batch_append_notes(ctx: Context<NoteAccounts>, notes: Vec<String>) -> Result<BatchAppendResult> {
+        if notes.len() > MAX_BATCH {
+            return err!(CompressedNotesError::BatchTooLarge);
+        }
+        for note in notes.iter() {
+            validate_note(note)?;
+        }
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, notes.len() as u64)?;
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, notes.len() as u64)?;
+
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        // The notes are appended starting at the current leaf count
+        let start_index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        let count = notes.len() as u32;
+        for (offset, note) in notes.into_iter().enumerate() {
+            let index = start_index
+                .checked_add(offset as u64)
+                .ok_or(CompressedNotesError::ArithmeticOverflow)?;
+            // Each leaf is bound to the owner and tree, same as append_note
+            let leaf_node = hash_note(&note, &ctx.accounts.owner.key(), &merkle_tree, ctx.accounts.admin_config.hash_algo);
+            // Log the "note log" data using noop program
+            let note_log = NoteLog::new(leaf_node, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), note, index)?;
+            wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+            // CPI to append the leaf node to the merkle tree
+            append(modify_ctx(ctx.accounts, signer_seeds), leaf_node)?;
+
+            record_latest_note(&mut ctx.accounts.latest_note, leaf_node, index)?;
+            emit!(NoteAppended {
+                tree: merkle_tree,
+                index,
+                owner: ctx.accounts.owner.key(),
+                leaf: leaf_node,
+            });
+        }
+
+        count_appended_notes(&mut ctx.accounts.note_counter, start_index, count.into())?;
+
+        Ok(BatchAppendResult { start_index, count })
+    }
+
+    // Instruction for updating several notes in one instruction, all or none of them.
+    //
+    // The remaining accounts hold the proof of each item in order, each as long as proof_accounts
+    // expects. Every replace changes the root, but the tree fast-forwards a proof taken at an earlier
+    // root through the changes since, so the items can all use the root read before the batch as long
+    // as it is still in the change log buffer.
+    pub fn update_notes<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        items: Vec<UpdateItem>,
+    ) -> Result<()> {
+        if items.len() > MAX_UPDATE_BATCH {
+            return err!(CompressedNotesError::BatchTooLarge);
+        }
+
+        let proof_len = expected_proof_len(&ctx.accounts.merkle_tree)?;
+        if ctx.remaining_accounts.len() != proof_len * items.len() {
+            msg!("Expected {} proof nodes for each of the {} items, got {} in total", proof_len, items.len(), ctx.remaining_accounts.len());
+            return err!(CompressedNotesError::ProofLengthMismatch);
+        }
+
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let hash_algo = ctx.accounts.admin_config.hash_algo;
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        for (position, item) in items.into_iter().enumerate() {
+            check_leaf_index(&ctx.accounts.merkle_tree, item.index)?;
+            // The proof of this item, empty when the canopy caches the whole proof
+            let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, &ctx.remaining_accounts[position * proof_len..(position + 1) * proof_len])?;
+            if item.old_note == item.new_note {
+                return err!(CompressedNotesError::NotesAreIdentical);
+            }
+            // Each update needs its own compute, so check before every item
+            check_update_budget(&ctx.accounts.merkle_tree)?;
+            validate_note(&item.new_note)?;
+
+            let old_leaf = hash_note(&item.old_note, &owner, &merkle_tree, hash_algo);
+            let new_leaf = hash_note(&item.new_note, &owner, &merkle_tree, hash_algo);
+
+            // Verify Leaf, against the current tree which includes the earlier items
+            prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, item.root, old_leaf, item.index)?;
+
+            // Log out for indexers
+            let note_log = NoteLog {
+                prev_leaf: old_leaf,
+                ..NoteLog::new(new_leaf, owner, ctx.accounts.merkle_tree.key(), item.new_note, item.index.into())?
+            };
+            // Log the "note log" data using noop program
+            wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+            // replace leaf
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds).with_remaining_accounts(proof_nodes); // The proof nodes of this item
+            replace_leaf(cpi_ctx, item.root, old_leaf, new_leaf, item.index)?;
+
+            record_latest_note(&mut ctx.accounts.latest_note, new_leaf, item.index.into())?;
+            emit!(NoteUpdated {
+                tree: merkle_tree,
+                index: item.index.into(),
+                owner,
+                leaf: new_leaf,
+            });
+        }
+        Ok(())
+    }
+
+    // Instruction for updating a note.
+    //
+    // Returns the root of the tree after the update, which Anchor sets as the return data of the
+    // instruction, so a program calling this through a CPI can chain further changes without
+    // reading the tree account.
+    pub fn update_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
         index: u32,
         root: [u8; 32],
         old_note: String,
         new_note: String,
-    ) -> Result<()> {
-        let old_leaf =
-            keccak::hashv(&[old_note.as_bytes(), ctx.accounts.owner.key().as_ref()]).to_bytes();
+    ) -> Result<[u8; 32]> {
+        update_titled_note(ctx, index, root, String::new(), old_note, String::new(), new_note)
+    }
+
+    // Instruction for updating the title and note of a titled note, returning the new root like update_note.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_titled_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        old_title: String,
+        old_note: String,
+        new_title: String,
+        new_note: String,
+    ) -> Result<[u8; 32]> {
+        if old_title == new_title && old_note == new_note {
+            return err!(CompressedNotesError::NotesAreIdentical);
+        }
+        check_update_budget(&ctx.accounts.merkle_tree)?;
+        // Only the new note is checked, the old note is already in the tree
+        validate_note(&new_note)?;
+        validate_title(&new_title)?;
+
+        // The old leaf is hashed with the signer's key, not a key the caller passes in. Knowing the
+        // note and its owner is not enough to update it: any other signer hashes a different leaf,
+        // which fails to verify, so only the owner can replace their note.
+        let old_leaf = hash_titled_note(&old_title, &old_note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
 
         let merkle_tree = ctx.accounts.merkle_tree.key();
 
-        // Define the seeds for pda signing
-        let signer_seeds: &[&[&[u8]]] = &[&[
-            merkle_tree.as_ref(), // The address of the merkle tree account as a seed
-            &[*ctx.bumps.get("tree_authority").unwrap()], // The bump seed for the pda
-        ]];
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
 
-        // Verify Leaf
-        {
-            if old_note == new_note {
-                msg!("Notes are the same!");
-                return Ok(());
-            }
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
 
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.compression_program.to_account_info(), // The spl account compression program
-                VerifyLeaf {
-                    merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to be modified
-                },
-                signer_seeds, // The seeds for pda signing
-            );
-            // Verify or Fails
-            verify_leaf(cpi_ctx, root, old_leaf, index)?;
-        }
+        // Verify Leaf, in this program so a bad proof can be reported with the index it was for.
+        // The old leaf is hashed once, and this same leaf is passed to replace_leaf and logged as
+        // prev_leaf, so the leaf that was verified is always the leaf that gets replaced.
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
 
-        let new_leaf =
-            keccak::hashv(&[new_note.as_bytes(), ctx.accounts.owner.key().as_ref()]).to_bytes();
+        let new_leaf = hash_titled_note(&new_title, &new_note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
 
         // Log out for indexers
-        let note_log = NoteLog::new(new_leaf.clone(), ctx.accounts.owner.key().clone(), new_note);
+        let note_log = NoteLog {
+            title: normalize_note(&new_title).into_owned(),
+            prev_leaf: old_leaf,
+            ..NoteLog::new(new_leaf, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), new_note, index.into())?
+        };
         // Log the "note log" data using noop program
-        wrap_application_data_v1(note_log.try_to_vec()?, &ctx.accounts.log_wrapper)?;
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
 
         // replace leaf
         {
-            let cpi_ctx = CpiContext::new_with_signer(
-                ctx.accounts.compression_program.to_account_info(), // The spl account compression program
-                Modify {
-                    authority: ctx.accounts.tree_authority.to_account_info(), // The authority for the merkle tree, using a PDA
-                    merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to be modified
-                    noop: ctx.accounts.log_wrapper.to_account_info(), // The noop program to log data
-                },
-                signer_seeds, // The seeds for pda signing
-            );
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+                .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
             // CPI to append the leaf node to the merkle tree
             replace_leaf(cpi_ctx, root, old_leaf, new_leaf, index)?;
         }
+        debug_msg!("Replaced leaf {} with {} at index {}", Pubkey::from(old_leaf), Pubkey::from(new_leaf), index);
 
-        Ok(())
+        record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index.into())?;
+        emit!(NoteUpdated {
+            tree: merkle_tree,
+            index: index.into(),
+            owner: ctx.accounts.owner.key(),
+            leaf: new_leaf,
+        });
+        get_current_root(&ctx.accounts.merkle_tree)
     }
-}
 
-#[derive(AnchorSerialize)]
-pub struct NoteLog {
-    leaf_node: [u8; 32],  // The leaf node hash
-    owner: Pubkey,        // Pubkey of the note owner
-    note: String,         // The note message
-}
+    // Instruction for updating a note appended with append_expiring_note, keeping its expiry.
+    //
+    // Fails with NoteExpired once the expiry has passed. Returns the new root like update_note.
+    pub fn update_expiring_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        old_note: String,
+        new_note: String,
+        expires_at: i64,
+    ) -> Result<[u8; 32]> {
+        if old_note == new_note {
+            return err!(CompressedNotesError::NotesAreIdentical);
+        }
+        check_not_expired(expires_at)?;
+        check_update_budget(&ctx.accounts.merkle_tree)?;
+        // Only the new note is checked, the old note is already in the tree
+        validate_note(&new_note)?;
 
-impl NoteLog {
-    // Constructs a new note from given leaf node and message
-    pub fn new(leaf_node: [u8; 32], owner: Pubkey, note: String) -> Self {
-        Self { leaf_node, owner, note }
-    }
-}
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let hash_algo = ctx.accounts.admin_config.hash_algo;
+        let old_leaf = hash_expiring_note(&old_note, &owner, &merkle_tree, expires_at, hash_algo);
+        let new_leaf = hash_expiring_note(&new_note, &owner, &merkle_tree, expires_at, hash_algo);
 
-#[derive(Accounts)]
-pub struct NoteAccounts<'info> {
-    // The payer for the transaction
-    #[account(mut)]
-    pub owner: Signer<'info>,
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
 
-    // The pda authority for the merkle tree, only used for signing
-    #[account(
-        seeds = [merkle_tree.key().as_ref()],
-        bump,
-    )]
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
+
+        // Log out for indexers
+        let note_log = NoteLog {
+            prev_leaf: old_leaf,
+            expires_at: Some(expires_at),
+            ..NoteLog::new(new_leaf, owner, merkle_tree, new_note, index.into())?
+        };
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // replace leaf
+        {
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+                .with_remaining_accounts(proof_nodes); // The proof nodes, less any cached by the canopy
+            replace_leaf(cpi_ctx, root, old_leaf, new_leaf, index)?;
+        }
+
+        record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index.into())?;
+        emit!(NoteUpdated {
+            tree: merkle_tree,
+            index: index.into(),
+            owner,
+            leaf: new_leaf,
+        });
+        get_current_root(&ctx.accounts.merkle_tree)
+    }
+
+    // Instruction for updating a note appended with a nonce, keeping its nonce.
+    //
+    // The old note is checked with the same nonce, so the update replaces one particular note of
+    // several with the same text. Returns the new root like update_note.
+    pub fn update_nonced_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        old_note: String,
+        new_note: String,
+        nonce: u64,
+    ) -> Result<[u8; 32]> {
+        if old_note == new_note {
+            return err!(CompressedNotesError::NotesAreIdentical);
+        }
+        check_update_budget(&ctx.accounts.merkle_tree)?;
+        // Only the new note is checked, the old note is already in the tree
+        validate_note(&new_note)?;
+
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let hash_algo = ctx.accounts.admin_config.hash_algo;
+        let old_leaf = hash_nonced_note(&old_note, &owner, &merkle_tree, nonce, hash_algo);
+        let new_leaf = hash_nonced_note(&new_note, &owner, &merkle_tree, nonce, hash_algo);
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
+
+        // Log out for indexers
+        let note_log = NoteLog {
+            prev_leaf: old_leaf,
+            nonce: Some(nonce),
+            ..NoteLog::new(new_leaf, owner, merkle_tree, new_note, index.into())?
+        };
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // replace leaf
+        {
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+                .with_remaining_accounts(proof_nodes); // The proof nodes, less any cached by the canopy
+            replace_leaf(cpi_ctx, root, old_leaf, new_leaf, index)?;
+        }
+
+        record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index.into())?;
+        emit!(NoteUpdated {
+            tree: merkle_tree,
+            index: index.into(),
+            owner,
+            leaf: new_leaf,
+        });
+        get_current_root(&ctx.accounts.merkle_tree)
+    }
+
+    // Instruction for updating a note only if its leaf is still the one the client last read.
+    //
+    // The expected leaf is the leaf_node of the NoteLog or event the client read the note from,
+    // hashed by the same hash_note used for every write. If another update replaced the leaf since
+    // the given root, the tree can't fast-forward the proof and this fails with ConcurrentModification,
+    // so the client can reload the note instead of overwriting the other update.
+    pub fn update_note_if_unchanged<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        expected_leaf: [u8; 32],
+        new_note: String,
+    ) -> Result<()> {
+        check_update_budget(&ctx.accounts.merkle_tree)?;
+        validate_note(&new_note)?;
+
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        let new_leaf = hash_note(&new_note, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+        if new_leaf == expected_leaf {
+            return err!(CompressedNotesError::NotesAreIdentical);
+        }
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+        // Compare the leaf on the tree to the expected leaf
+        if !leaf_proves(&ctx.accounts.merkle_tree, &proof_nodes, root, expected_leaf, index)? {
+            msg!("Leaf {} is no longer the expected leaf", index);
+            return err!(CompressedNotesError::ConcurrentModification);
+        }
+
+        // Log out for indexers
+        let note_log = NoteLog {
+            prev_leaf: expected_leaf,
+            ..NoteLog::new(new_leaf, owner, ctx.accounts.merkle_tree.key(), new_note, index.into())?
+        };
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // replace leaf
+        {
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+                .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+            // CPI to swap the expected leaf for the new one
+            replace_leaf(cpi_ctx, root, expected_leaf, new_leaf, index)?;
+        }
+
+        record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index.into())?;
+        emit!(NoteUpdated {
+            tree: merkle_tree,
+            index: index.into(),
+            owner,
+            leaf: new_leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for a delegate to update a note on behalf of its owner.
+    //
+    // The owner doesn't sign the transaction, but signs a message for this exact update with an
+    // Ed25519 program instruction placed right before this one. The message is the keccak hash of
+    // ("update_note_delegated", tree, index as le u32, old leaf, new leaf, nonce as le u64), see
+    // hash_delegated_update. The nonce is read from the owner's DelegateNonce for the tree and
+    // bumped by every delegated update, so a signature can't be replayed once the note changes
+    // back to the same old leaf. The leaves are still bound to the owner, so the note stays theirs.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_note_delegated<'info>(
+        ctx: Context<'_, '_, '_, 'info, DelegatedUpdateAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        old_note: String,
+        new_note: String,
+        owner_sig: [u8; 64],
+        owner: Pubkey,
+    ) -> Result<()> {
+        if old_note == new_note {
+            return err!(CompressedNotesError::NotesAreIdentical);
+        }
+        check_update_budget(&ctx.accounts.merkle_tree)?;
+        validate_note(&new_note)?;
+
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        let old_leaf = hash_note(&old_note, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+        let new_leaf = hash_note(&new_note, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+
+        // The owner must have signed this update at the current nonce, the delegate only submits it
+        let delegate_nonce = &mut ctx.accounts.delegate_nonce;
+        let message = hash_delegated_update(&merkle_tree, index, &old_leaf, &new_leaf, delegate_nonce.nonce);
+        verify_owner_signature(&ctx.accounts.instructions, &owner, &owner_sig, &message)?;
+        delegate_nonce.nonce = delegate_nonce
+            .nonce
+            .checked_add(1)
+            .ok_or(CompressedNotesError::ArithmeticOverflow)?;
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+        // Verify Leaf, in this program so a bad proof can be reported with the index it was for
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
+
+        // Log out for indexers
+        let note_log = NoteLog {
+            prev_leaf: old_leaf,
+            appended_by: ctx.accounts.delegate.key(),
+            ..NoteLog::new(new_leaf, owner, ctx.accounts.merkle_tree.key(), new_note, index.into())?
+        };
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // replace leaf
+        {
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+                .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+            // CPI to replace the leaf node with the updated note
+            replace_leaf(cpi_ctx, root, old_leaf, new_leaf, index)?;
+        }
+
+        record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index.into())?;
+        emit!(NoteUpdated {
+            tree: merkle_tree,
+            index: index.into(),
+            owner,
+            leaf: new_leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for appending a note of raw bytes, for binary data that isn't valid UTF-8.
+    //
+    // The bytes are hashed the same way as the bytes of a text note, so a byte note that happens to
+    // be valid UTF-8 in NFC can also be managed by the text note instructions. The bytes are never
+    // normalized, as they aren't taken to be text.
+    pub fn append_note_bytes(ctx: Context<NoteAccounts>, data: Vec<u8>) -> Result<()> {
+        validate_note_bytes(&data)?;
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+        // Hash the raw bytes which will be stored as leaf node in the merkle tree
+        let leaf_node = hash_note_bytes(&data, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        // Create a new "raw note log" using the leaf node hash and bytes.
+        let note_log = RawNoteLog::new(leaf_node, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), data, index)?;
+        // Log the "raw note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+        // CPI to append the leaf node to the merkle tree
+        append(modify_ctx(ctx.accounts, signer_seeds), leaf_node)?;
+
+        count_appended_notes(&mut ctx.accounts.note_counter, index, 1)?;
+
+        record_latest_note(&mut ctx.accounts.latest_note, leaf_node, index)?;
+        emit!(NoteAppended {
+            tree: merkle_tree,
+            index,
+            owner: ctx.accounts.owner.key(),
+            leaf: leaf_node,
+        });
+        Ok(())
+    }
+
+    // Instruction for writing the next chunk of a long note into the owner's scratch account.
+    //
+    // Notes longer than a transaction are assembled across several writes, each appending `data`
+    // to the bytes already written, and are appended by append_note_from_account. The scratch
+    // account is created on the first write, and the assembled note can't grow past
+    // MAX_SCRATCH_NOTE_LEN.
+    pub fn write_note_scratch(ctx: Context<NoteScratchAccounts>, data: Vec<u8>) -> Result<()> {
+        let note_scratch = &mut ctx.accounts.note_scratch;
+        if note_scratch.data.len() + data.len() > MAX_SCRATCH_NOTE_LEN {
+            return err!(CompressedNotesError::NoteTooLong);
+        }
+        note_scratch.data.extend_from_slice(&data);
+        Ok(())
+    }
+
+    // Instruction for discarding the owner's scratch account without appending it, returning its rent
+    pub fn close_note_scratch(_ctx: Context<CloseNoteScratchAccounts>) -> Result<()> {
+        Ok(())
+    }
+
+    // Instruction for appending the note assembled in the owner's scratch account as a byte note.
+    //
+    // The leaf is the same as append_note_bytes with the assembled bytes, and the scratch account
+    // is closed to the owner once the note is appended. Notes longer than MAX_NOTE_LEN can't be
+    // passed to the byte note instructions, so they can only be read from the log.
+    pub fn append_note_from_account(ctx: Context<NoteAccounts>) -> Result<()> {
+        let note_scratch = ctx.accounts.note_scratch.as_ref().ok_or(CompressedNotesError::NoteScratchMissing)?;
+        let data = note_scratch.data.clone();
+        // Empty notes are reserved for the tombstones logged by delete_note
+        if data.is_empty() {
+            return err!(CompressedNotesError::EmptyNote);
+        }
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+        // Hash the assembled bytes which will be stored as leaf node in the merkle tree
+        let leaf_node = hash_note_bytes(&data, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        // Create a new "raw note log" using the leaf node hash and bytes.
+        let note_log = RawNoteLog::new(leaf_node, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), data, index)?;
+        // Log the "raw note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+        // CPI to append the leaf node to the merkle tree
+        append(modify_ctx(ctx.accounts, signer_seeds), leaf_node)?;
+
+        count_appended_notes(&mut ctx.accounts.note_counter, index, 1)?;
+
+        record_latest_note(&mut ctx.accounts.latest_note, leaf_node, index)?;
+        // The note is in the log now, so the scratch account's rent goes back to the owner
+        if let Some(note_scratch) = &ctx.accounts.note_scratch {
+            note_scratch.close(ctx.accounts.owner.to_account_info())?;
+        }
+        emit!(NoteAppended {
+            tree: merkle_tree,
+            index,
+            owner: ctx.accounts.owner.key(),
+            leaf: leaf_node,
+        });
+        Ok(())
+    }
+
+    // Instruction for appending a note compressed by the client, to save log space for long notes.
+    //
+    // The program never inflates the bytes. They are hashed and logged as they are, so the leaf is
+    // the same as a byte note of the compressed bytes and can be managed by the byte note
+    // instructions. `algo` is only logged in the CompressedNoteLog, telling indexers how to inflate
+    // the bytes, and must be one of the COMPRESSION_ALGO constants.
+    pub fn append_compressed_note(ctx: Context<NoteAccounts>, compressed: Vec<u8>, algo: u8) -> Result<()> {
+        if algo != COMPRESSION_ALGO_RLE && algo != COMPRESSION_ALGO_ZSTD {
+            return err!(CompressedNotesError::InvalidCompressionAlgo);
+        }
+        validate_note_bytes(&compressed)?;
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+        // Hash the compressed bytes which will be stored as leaf node in the merkle tree
+        let leaf_node = hash_note_bytes(&compressed, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        // Create a new "compressed note log" using the leaf node hash and compressed bytes.
+        let note_log = CompressedNoteLog::new(leaf_node, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), algo, compressed, index)?;
+        // Log the "compressed note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+        // CPI to append the leaf node to the merkle tree
+        append(modify_ctx(ctx.accounts, signer_seeds), leaf_node)?;
+
+        count_appended_notes(&mut ctx.accounts.note_counter, index, 1)?;
+
+        record_latest_note(&mut ctx.accounts.latest_note, leaf_node, index)?;
+        emit!(NoteAppended {
+            tree: merkle_tree,
+            index,
+            owner: ctx.accounts.owner.key(),
+            leaf: leaf_node,
+        });
+        Ok(())
+    }
+
+    // Instruction for appending a leaf hashed off-chain, so the note never appears on-chain.
+    //
+    // The NoteLog has an empty note. The program can't verify the preimage of the leaf, so the leaf
+    // isn't appended as it is but wrapped with hash_raw_leaf, binding it to the signer and tree.
+    // Otherwise anyone could append the hash_note leaf of a note of another owner, or a tombstone or
+    // empty leaf. The wrapped leaf is what is logged as leaf_node, so indexers match it to the leaf
+    // hashed off-chain with hash_raw_leaf. It can't be updated or deleted by the note instructions,
+    // which rehash the note they are given, and is deleted with delete_leaf instead.
+    pub fn append_leaf(ctx: Context<NoteAccounts>, leaf: [u8; 32]) -> Result<()> {
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+        let leaf = hash_raw_leaf(&leaf, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        // Create a new "note log" with the given leaf and no note
+        let note_log = NoteLog::new(leaf, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), String::new(), index)?;
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+        // CPI to append the leaf node to the merkle tree
+        append(modify_ctx(ctx.accounts, signer_seeds), leaf)?;
+
+        count_appended_notes(&mut ctx.accounts.note_counter, index, 1)?;
+
+        record_latest_note(&mut ctx.accounts.latest_note, leaf, index)?;
+        emit!(NoteAppended {
+            tree: merkle_tree,
+            index,
+            owner: ctx.accounts.owner.key(),
+            leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for updating a note of raw bytes.
+    pub fn update_note_bytes<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        old_data: Vec<u8>,
+        new_data: Vec<u8>,
+    ) -> Result<()> {
+        if old_data == new_data {
+            return err!(CompressedNotesError::NotesAreIdentical);
+        }
+        check_update_budget(&ctx.accounts.merkle_tree)?;
+        validate_note_bytes(&new_data)?;
+
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        let old_leaf = hash_note_bytes(&old_data, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+        // Verify Leaf, in this program so a bad proof can be reported with the index it was for
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
+
+        let new_leaf = hash_note_bytes(&new_data, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+
+        // Log out for indexers
+        let note_log = RawNoteLog::new(new_leaf, owner, merkle_tree, new_data, index.into())?;
+        // Log the "raw note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // replace leaf
+        {
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+                .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+            // CPI to replace the old leaf with the new one
+            replace_leaf(cpi_ctx, root, old_leaf, new_leaf, index)?;
+        }
+
+        record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index.into())?;
+        emit!(NoteUpdated {
+            tree: merkle_tree,
+            index: index.into(),
+            owner,
+            leaf: new_leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for deleting a note, replacing its leaf with the deleted leaf of the tree.
+    //
+    // Deleting a note that was already deleted fails with NoteAlreadyDeleted, found by checking the
+    // deleted leaf at the index when the note itself doesn't verify.
+    pub fn delete_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        // Deleted notes are logged with an empty note, so an empty note here means
+        // the client is trying to delete a leaf that has already been deleted
+        if note.is_empty() {
+            return err!(CompressedNotesError::NoteAlreadyDeleted);
+        }
+
+        // The leaf is bound to the owner's key, so only the owner can produce a leaf that verifies
+        let leaf = hash_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        delete_note_leaf(ctx, index, root, leaf, None)
+    }
+
+    // Instruction for deleting a leaf appended with append_leaf, given the leaf as it was hashed
+    // off-chain. It is wrapped with hash_raw_leaf again, so only the owner who appended it can delete it.
+    pub fn delete_leaf<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        leaf: [u8; 32],
+    ) -> Result<()> {
+        let leaf = hash_raw_leaf(&leaf, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        delete_note_leaf(ctx, index, root, leaf, None)
+    }
+
+    // Instruction for deleting a note appended with a nonce, hashed with the same nonce.
+    pub fn delete_nonced_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+        nonce: u64,
+    ) -> Result<()> {
+        if note.is_empty() {
+            return err!(CompressedNotesError::NoteAlreadyDeleted);
+        }
+
+        let leaf = hash_nonced_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), nonce, ctx.accounts.admin_config.hash_algo);
+        delete_note_leaf(ctx, index, root, leaf, Some(nonce))
+    }
+
+    // Instruction for deleting an expired note on behalf of its owner, which anyone can call.
+    //
+    // The note is deleted the same way as with delete_note, and fails with NoteNotExpired while
+    // its expiry hasn't passed yet.
+    pub fn delete_expired_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, DeleteExpiredNoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+        expires_at: i64,
+    ) -> Result<()> {
+        if Clock::get()?.unix_timestamp <= expires_at {
+            return err!(CompressedNotesError::NoteNotExpired);
+        }
+
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let leaf = hash_expiring_note(&note, &owner, &merkle_tree, expires_at, ctx.accounts.admin_config.hash_algo);
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, leaf, index)?;
+
+        // Log out a tombstone for indexers, the empty note marks the leaf as deleted
+        let tombstone = deleted_leaf(&merkle_tree);
+        let note_log = NoteLog {
+            prev_leaf: leaf,
+            expires_at: Some(expires_at),
+            appended_by: ctx.accounts.caller.key(),
+            ..NoteLog::new(tombstone, owner, merkle_tree, String::new(), index.into())?
+        };
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // replace leaf
+        let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+            .with_remaining_accounts(proof_nodes); // The proof nodes, less any cached by the canopy
+        // CPI to replace the leaf node with the deleted leaf
+        replace_leaf(cpi_ctx, root, leaf, tombstone, index)?;
+
+        uncount_deleted_note(&mut ctx.accounts.note_counter)?;
+        refund_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota)?;
+
+        emit!(NoteDeleted {
+            tree: merkle_tree,
+            index: index.into(),
+            owner,
+            leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for appending a note into the slot of a deleted note, reusing it instead of
+    // growing the tree.
+    //
+    // The slot must hold the deleted leaf, checked against the root and proof before the replace,
+    // and fails with SlotOccupied otherwise. A stale root or wrong proof also fails with SlotOccupied,
+    // since the slot can't be shown to be deleted.
+    pub fn append_at<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        validate_note(&note)?;
+        check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+        charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+        let tombstone = deleted_leaf(&merkle_tree);
+        if !leaf_proves(&ctx.accounts.merkle_tree, &proof_nodes, root, tombstone, index)? {
+            msg!("Leaf {} is not deleted against root {}", index, Pubkey::from(root));
+            return err!(CompressedNotesError::SlotOccupied);
+        }
+
+        let leaf = hash_note(&note, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+        let note_log = NoteLog {
+            prev_leaf: tombstone,
+            ..NoteLog::new(leaf, owner, merkle_tree, note, index.into())?
+        };
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+        {
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+                .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+            // CPI to replace the deleted leaf with the note
+            replace_leaf(cpi_ctx, root, tombstone, leaf, index)?;
+        }
+
+        // The slot was already counted by next_index when it was first appended
+        let note_counter = &mut ctx.accounts.note_counter;
+        note_counter.count = note_counter.count.checked_add(1).ok_or(CompressedNotesError::ArithmeticOverflow)?;
+
+        record_latest_note(&mut ctx.accounts.latest_note, leaf, index.into())?;
+        emit!(NoteAppended {
+            tree: merkle_tree,
+            index: index.into(),
+            owner,
+            leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for transferring a note to a new owner, re-hashing the leaf under the new owner's key.
+    //
+    // With owner quotas on, the note moves to the new owner's quota, which must be passed as the
+    // recipient quota, and the transfer is rejected if it would put the new owner over the quota.
+    pub fn transfer_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+        new_owner: Pubkey,
+    ) -> Result<()> {
+        transfer_note_leaf(ctx, index, root, note, new_owner, None)
+    }
+
+    // Instruction for transferring a note appended with a nonce, keeping its nonce.
+    pub fn transfer_nonced_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+        new_owner: Pubkey,
+        nonce: u64,
+    ) -> Result<()> {
+        transfer_note_leaf(ctx, index, root, note, new_owner, Some(nonce))
+    }
+
+    // Instruction for moving a note to another tree, for owners leaving a tree that is full.
+    //
+    // The note is proven in the source tree and replaced with its deleted leaf there, then appended
+    // to the destination tree. Leaves are bound to their tree, so the note is re-hashed with the
+    // destination tree key and hash algo. The remaining accounts hold the proof in the source tree.
+    // The move counts as an append to the destination, against its allowlist, quota and fee.
+    pub fn migrate_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, MigrateNoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        validate_note(&note)?;
+        check_allowlist(&ctx.accounts.dest_admin_config, &ctx.accounts.dest_allowed_owner)?;
+        charge_owner_quota(&ctx.accounts.dest_admin_config, &mut ctx.accounts.dest_owner_quota, 1)?;
+        charge_append_fee(&ctx.accounts.dest_admin_config, &ctx.accounts.owner, &ctx.accounts.dest_treasury, &ctx.accounts.system_program, 1)?;
+
+        let owner = ctx.accounts.owner.key();
+        let source_tree = ctx.accounts.source_merkle_tree.key();
+        let dest_tree = ctx.accounts.dest_merkle_tree.key();
+
+        check_leaf_index(&ctx.accounts.source_merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.source_merkle_tree, ctx.remaining_accounts)?;
+
+        // Verify Leaf, in this program so a bad proof can be reported with the index it was for
+        let source_leaf = hash_note(&note, &owner, &source_tree, ctx.accounts.source_admin_config.hash_algo);
+        prove_note_leaf(&ctx.accounts.source_merkle_tree, &proof_nodes, root, source_leaf, index)?;
+
+        // Log out a tombstone for indexers of the source tree
+        let tombstone = deleted_leaf(&source_tree);
+        let note_log = NoteLog {
+            prev_leaf: source_leaf,
+            ..NoteLog::new(tombstone, owner, source_tree, String::new(), index.into())?
+        };
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // Replace the leaf in the source tree with the deleted leaf
+        {
+            let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&source_tree, &ctx.bumps.source_tree_authority)];
+            let cpi_ctx = modify_tree_ctx(
+                &ctx.accounts.compression_program,
+                &ctx.accounts.source_tree_authority,
+                &ctx.accounts.source_merkle_tree,
+                &ctx.accounts.log_wrapper,
+                signer_seeds,
+            )
+            .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+            replace_leaf(cpi_ctx, root, source_leaf, tombstone, index)?;
+        }
+
+        uncount_deleted_note(&mut ctx.accounts.source_note_counter)?;
+        refund_owner_quota(&ctx.accounts.source_admin_config, &mut ctx.accounts.source_owner_quota)?;
+
+        emit!(NoteDeleted {
+            tree: source_tree,
+            index: index.into(),
+            owner,
+            leaf: source_leaf,
+        });
+
+        // Log the note for indexers of the destination tree
+        let dest_leaf = hash_note(&note, &owner, &dest_tree, ctx.accounts.dest_admin_config.hash_algo);
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let dest_index = get_leaf_count(&ctx.accounts.dest_merkle_tree)?;
+        let note_log = NoteLog::new(dest_leaf, owner, dest_tree, note, dest_index)?;
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+        // Append the leaf to the destination tree
+        {
+            let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&dest_tree, &ctx.bumps.dest_tree_authority)];
+            let cpi_ctx = modify_tree_ctx(
+                &ctx.accounts.compression_program,
+                &ctx.accounts.dest_tree_authority,
+                &ctx.accounts.dest_merkle_tree,
+                &ctx.accounts.log_wrapper,
+                signer_seeds,
+            );
+            append(cpi_ctx, dest_leaf)?;
+        }
+
+        count_appended_notes(&mut ctx.accounts.dest_note_counter, dest_index, 1)?;
+
+        record_latest_note(&mut ctx.accounts.dest_latest_note, dest_leaf, dest_index)?;
+        emit!(NoteAppended {
+            tree: dest_tree,
+            index: dest_index,
+            owner,
+            leaf: dest_leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for checking that a note exists at an index without modifying the tree.
+    pub fn verify_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, VerifyNoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        let leaf = hash_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, leaf, index)
+    }
+
+    // Instruction for checking that a note appended with append_expiring_note exists and hasn't expired.
+    pub fn verify_expiring_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, VerifyNoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+        expires_at: i64,
+    ) -> Result<()> {
+        check_not_expired(expires_at)?;
+        let leaf = hash_expiring_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), expires_at, ctx.accounts.admin_config.hash_algo);
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, leaf, index)
+    }
+
+    // Instruction for checking that a note appended with a nonce exists, hashed with the same nonce.
+    pub fn verify_nonced_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, VerifyNoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+        nonce: u64,
+    ) -> Result<()> {
+        let leaf = hash_nonced_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), nonce, ctx.accounts.admin_config.hash_algo);
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, leaf, index)
+    }
+
+    // Instruction for checking that a note exists, setting the return data to a single byte 1 if it does.
+    //
+    // Fails with ProofVerificationFailed if it doesn't. Nothing needs to sign and the proof is checked
+    // in this program, so anyone can call it, including other programs through a CPI.
+    pub fn note_exists<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteExistsAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        let leaf = hash_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, leaf, index)?;
+
+        set_return_data(&[1]);
+        Ok(())
+    }
+
+    // Instruction for checking that several notes of one owner exist, returning a bitmask of which do.
+    //
+    // Bit i of the mask is set if item i verified. The proofs are concatenated in the remaining
+    // accounts, and each item gives the offset its proof starts at, so a proof can't be misread
+    // as another item's. A proof out of range of the remaining accounts, an index past the last
+    // leaf or a root no longer in the buffer fails the whole instruction, since those are client
+    // errors rather than notes that don't exist.
+    pub fn verify_notes<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteExistsAccounts<'info>>,
+        items: Vec<VerifyItem>,
+    ) -> Result<u32> {
+        if items.len() > MAX_VERIFY_BATCH {
+            return err!(CompressedNotesError::BatchTooLarge);
+        }
+        let owner = ctx.accounts.owner.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let proof_len = expected_proof_len(&ctx.accounts.merkle_tree)?;
+
+        let mut verified = 0u32;
+        for (position, item) in items.iter().enumerate() {
+            check_leaf_index(&ctx.accounts.merkle_tree, item.index)?;
+            let start = usize::from(item.proof_offset);
+            let proof = ctx
+                .remaining_accounts
+                .get(start..start + proof_len)
+                .ok_or(CompressedNotesError::ProofLengthMismatch)?;
+            let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, proof)?;
+
+            let leaf = hash_note(&item.note, &owner, &merkle_tree, ctx.accounts.admin_config.hash_algo);
+            if leaf_proves(&ctx.accounts.merkle_tree, &proof_nodes, item.root, leaf, item.index)? {
+                verified |= 1 << position;
+            }
+        }
+        Ok(verified)
+    }
+
+    // Instruction for proving that a note belongs to a candidate owner, returning the owner.
+    //
+    // The owner is hashed into the leaf but can't be read back from it, so the verifier passes
+    // the owner it expects. The owner is returned only if the leaf of the note and that owner is
+    // at the index, and any other owner fails with ProofVerificationFailed.
+    pub fn verify_and_get_owner<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteExistsAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<Pubkey> {
+        let owner = ctx.accounts.owner.key();
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+        let leaf = hash_note(&note, &owner, &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, leaf, index)?;
+        Ok(owner)
+    }
+
+    // Instruction for computing the leaf a note would be appended as, returned as return data.
+    //
+    // Lets clients hash a note exactly as the program does, for example to check whether they
+    // already appended it, without reimplementing the leaf format. Nothing is written or verified.
+    pub fn compute_leaf(ctx: Context<NoteExistsAccounts>, note: String) -> Result<()> {
+        let leaf = hash_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo);
+
+        set_return_data(&leaf);
+        Ok(())
+    }
+
+    // Instruction for previewing the root an update_note would leave, without modifying the tree.
+    //
+    // The old note is verified as by update_note, then the root is recomputed with the new leaf from
+    // the same proof and set as the return data. It is the root after the update when `root` is the
+    // current root. Against an older root, the compression program fast-forwards the proof through
+    // the later changes first, which the preview can't see.
+    pub fn simulate_update<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteExistsAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        old_note: String,
+        new_note: String,
+    ) -> Result<()> {
+        // Checked the same way as by update_note, on the normalized note
+        if old_note == new_note {
+            return err!(CompressedNotesError::NotesAreIdentical);
+        }
+        validate_note(&new_note)?;
+        let owner = ctx.accounts.owner.key();
+        let tree = ctx.accounts.merkle_tree.key();
+        let old_leaf = hash_note(&old_note, &owner, &tree, ctx.accounts.admin_config.hash_algo);
+        let new_leaf = hash_note(&new_note, &owner, &tree, ctx.accounts.admin_config.hash_algo);
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
+
+        let proof = load_full_proof(&ctx.accounts.merkle_tree, &proof_nodes, index)?;
+        set_return_data(&recompute_root(new_leaf, &proof, index));
+        Ok(())
+    }
+
+    // Instruction for logging the NoteLog of a note in the tree again, for indexers that missed it.
+    //
+    // Anyone can call it, but the note must be proven against the tree, so only the log of a note
+    // that is in the tree can be emitted. The tree is not modified. Only active untitled notes can be
+    // re-emitted, since the leaf is hashed as by append_note.
+    pub fn reemit_note_log<'info>(
+        ctx: Context<'_, '_, '_, 'info, ReemitNoteLogAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        let owner = ctx.accounts.owner.key();
+        let tree = ctx.accounts.merkle_tree.key();
+        let leaf = hash_note(&note, &owner, &tree, ctx.accounts.admin_config.hash_algo);
+
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, leaf, index)?;
+
+        let note_log = NoteLog::new(leaf, owner, tree, note, index.into())?;
+        // Log the "note log" data using noop program
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+        Ok(())
+    }
+
+    // Instruction for reading the parameters of a tree, so clients can size proofs without parsing
+    // the account.
+    //
+    // Sets the return data to the max depth, max buffer size and canopy depth, each a little endian
+    // u32. Fails with MalformedTreeAccount if the account is too short for its header and tree.
+    pub fn get_tree_config(ctx: Context<TreeConfigAccounts>) -> Result<()> {
+        let data = ctx.accounts.merkle_tree.try_borrow_data()?;
+        let header = load_tree_header(&data)?;
+        let canopy_depth = get_canopy_depth(&data, &header)?;
+
+        let mut config = Vec::with_capacity(12);
+        config.extend_from_slice(&header.get_max_depth().to_le_bytes());
+        config.extend_from_slice(&header.get_max_buffer_size().to_le_bytes());
+        config.extend_from_slice(&canopy_depth.to_le_bytes());
+        set_return_data(&config);
+        Ok(())
+    }
+
+    // Instruction for emitting a snapshot of a tree's state, for dashboards polling tree health.
+    //
+    // Read-only and permissionless. Fails with MalformedTreeAccount if the account is too short for
+    // its header and tree.
+    pub fn emit_stats(ctx: Context<TreeConfigAccounts>) -> Result<()> {
+        let merkle_tree = &ctx.accounts.merkle_tree;
+        let (max_depth, buffer_size, authority) = {
+            let data = merkle_tree.try_borrow_data()?;
+            let header = load_tree_header(&data)?;
+            // Check the whole tree fits, so the reads below can't run past the account
+            get_canopy_depth(&data, &header)?;
+            let buffer_size = u64::from_le_bytes(read_tree_bytes(&data, TREE_OFFSET + 16)?);
+            let authority = Pubkey::from(read_tree_bytes::<32>(&data, HEADER_AUTHORITY_OFFSET)?);
+            (header.get_max_depth(), buffer_size, authority)
+        };
+
+        emit!(TreeStats {
+            tree: merkle_tree.key(),
+            root: get_current_root(merkle_tree)?,
+            rightmost_index: get_leaf_count(merkle_tree)?,
+            max_depth,
+            buffer_size,
+            authority,
+        });
+        Ok(())
+    }
+
+    // Instruction for attesting that the owner owns a note at an index, for other programs to consume.
+    //
+    // On success the leaf is returned, which Anchor sets as the return data of the instruction,
+    // so a program calling this through a CPI can read it with get_return_data. The attestation is
+    // also emitted as an OwnershipProven event for off-chain consumers.
+    pub fn prove_note_ownership<'info>(
+        ctx: Context<'_, '_, '_, 'info, VerifyNoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<[u8; 32]> {
+        let owner = ctx.accounts.owner.key();
+        let tree = ctx.accounts.merkle_tree.key();
+        let leaf = hash_note(&note, &owner, &tree, ctx.accounts.admin_config.hash_algo);
+
+        verify_note(ctx, index, root, note)?;
+
+        emit!(OwnershipProven {
+            owner,
+            tree,
+            index: index.into(),
+            leaf,
+            root,
+        });
+        Ok(leaf)
+    }
+
+    // Instruction for archiving a note, a soft delete that keeps the note recoverable.
+    pub fn archive_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        set_note_status(ctx, index, root, note, NOTE_STATUS_ACTIVE, NOTE_STATUS_ARCHIVED)
+    }
+
+    // Instruction for restoring an archived note.
+    pub fn unarchive_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        set_note_status(ctx, index, root, note, NOTE_STATUS_ARCHIVED, NOTE_STATUS_ACTIVE)
+    }
+
+    // Instruction for closing an empty note tree and returning its rent to the admin.
+    //
+    // The compression program only closes a tree whose root is the root of an empty tree, so only
+    // a tree no note was ever appended to can be closed. Deleted notes leave the deleted leaf of
+    // the tree behind rather than an empty leaf, so a tree with all of its notes deleted is not
+    // empty and fails with TreeNotEmpty like any other.
+    pub fn close_note_tree(ctx: Context<CloseNoteTreeAccounts>) -> Result<()> {
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        if !is_tree_empty(&ctx.accounts.merkle_tree)? {
+            if ctx.accounts.note_counter.count == 0 {
+                msg!("Every note of the tree was deleted, but deleted leaves keep the tree from being empty");
+            }
+            return err!(CompressedNotesError::TreeNotEmpty);
+        }
+
+        let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        // Create cpi context for close_empty_tree instruction.
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.compression_program.to_account_info(), // The spl account compression program
+            CloseTree {
+                authority: ctx.accounts.tree_authority.to_account_info(), // The authority for the merkle tree, using a PDA
+                merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to be closed
+                recipient: ctx.accounts.admin.to_account_info(), // The admin receives the rent
+            },
+            signer_seeds, // The seeds for pda signing
+        );
+
+        // CPI to close the merkle tree account
+        close_empty_tree(cpi_ctx)?;
+        Ok(())
+    }
+
+    // Instruction for creating a new messages tree.
+    //
+    // A messages tree has its own authority pda, derived with the b"messages" seed, so message
+    // instructions can't append to a note tree and note instructions can't touch a messages tree.
+    pub fn create_messages_tree(
+        ctx: Context<CreateMessagesTreeAccounts>,
+        max_depth: u32,       // Max depth of the merkle tree
+        max_buffer_size: u32, // Max buffer size of the merkle tree
+        canopy_depth: u32,    // Number of upper levels of the tree cached on-chain
+    ) -> Result<()> {
+        check_tree_config(max_depth, max_buffer_size)?;
+        if canopy_depth > max_depth {
+            return err!(CompressedNotesError::InvalidCanopyDepth);
+        }
+        // The account must be sized exactly for the tree and its canopy
+        if ctx.accounts.merkle_tree.data_len()
+            != merkle_tree_account_size(max_depth, max_buffer_size, canopy_depth)
+        {
+            return err!(CompressedNotesError::TreeAccountSizeMismatch);
+        }
+        check_rent_exempt(&ctx.accounts.merkle_tree)?;
+        // Fail before the init CPI, which gives a cryptic error for an initialized tree
+        if is_tree_initialized(&ctx.accounts.merkle_tree)? {
+            return err!(CompressedNotesError::TreeAlreadyInitialized);
+        }
+
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        let signer_seeds: &[&[&[u8]]] = &[&messages_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        // Create cpi context for init_empty_merkle_tree instruction.
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.compression_program.to_account_info(), // The spl account compression program
+            Initialize {
+                authority: ctx.accounts.tree_authority.to_account_info(), // The authority for the merkle tree, using a PDA
+                merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to be initialized
+                noop: ctx.accounts.log_wrapper.to_account_info(), // The noop program to log data
+            },
+            signer_seeds, // The seeds for pda signing
+        );
+
+        // CPI to initialize an empty merkle tree with given max depth and buffer size
+        init_empty_merkle_tree(cpi_ctx, max_depth, max_buffer_size)?;
+        Ok(())
+    }
+
+    // Instruction for sending a message from the sender to the recipient.
+    pub fn append_message(ctx: Context<MessageAccounts>, message: String) -> Result<()> {
+        validate_note(&message)?;
+        // Hash the message, binding it to both the sender and the recipient
+        let leaf_node = hash_message(
+            &message,
+            &ctx.accounts.sender.key(),
+            &ctx.accounts.recipient.key(),
+            &ctx.accounts.merkle_tree.key(),
+        );
+        // The leaf is appended at the current leaf count, i.e. its position before the append
+        let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+        // Create a new "message log" using the leaf node hash and message.
+        let message_log = MessageLog::new(
+            leaf_node,
+            ctx.accounts.sender.key(),
+            ctx.accounts.recipient.key(),
+            message,
+            index,
+        )?;
+        // Log the "message log" data using noop program
+        wrap_log(message_log.try_to_vec()?, &ctx.accounts.log_wrapper, &None)?;
+        // Get the address for the merkle tree account
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let signer_seeds: &[&[&[u8]]] = &[&messages_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+        // Create a new cpi context and append the leaf node to the merkle tree.
+        let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds);
+        // CPI to append the leaf node to the merkle tree
+        append(cpi_ctx, leaf_node)?;
+        Ok(())
+    }
+
+    // Instruction for the sender to edit a message they sent.
+    pub fn update_message<'info>(
+        ctx: Context<'_, '_, '_, 'info, MessageAccounts<'info>>,
+        index: u32,
+        root: [u8; 32],
+        old_message: String,
+        new_message: String,
+    ) -> Result<()> {
+        if old_message == new_message {
+            return err!(CompressedNotesError::NotesAreIdentical);
+        }
+        validate_note(&new_message)?;
+
+        let sender = ctx.accounts.sender.key();
+        let recipient = ctx.accounts.recipient.key();
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        let old_leaf = hash_message(&old_message, &sender, &recipient, &merkle_tree);
+
+        let signer_seeds: &[&[&[u8]]] = &[&messages_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+        check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+        let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+        // Verify Leaf, in this program so a bad proof can be reported with the index it was for
+        prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
+
+        let new_leaf = hash_message(&new_message, &sender, &recipient, &merkle_tree);
+
+        // Log out for indexers
+        let message_log = MessageLog::new(new_leaf, sender, recipient, new_message, index.into())?;
+        // Log the "message log" data using noop program
+        wrap_log(message_log.try_to_vec()?, &ctx.accounts.log_wrapper, &None)?;
+
+        // replace leaf
+        {
+            let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+                .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+            // CPI to replace the old message leaf with the new one
+            replace_leaf(cpi_ctx, root, old_leaf, new_leaf, index)?;
+        }
+
+        Ok(())
+    }
+}
+
+// The leaf of a slot in the tree that was never written
+pub const EMPTY_LEAF: [u8; 32] = [0u8; 32];
+
+// The id the compression program is expected at. Builds with the localnet feature expect a local
+// build of spl-account-compression deployed at LOCALNET_COMPRESSION_PROGRAM_ID instead, as shown in
+// Anchor.toml. The noop program is expected at its canonical id either way.
+#[cfg(not(feature = "localnet"))]
+pub const COMPRESSION_PROGRAM_ID: Pubkey = spl_account_compression::ID;
+#[cfg(feature = "localnet")]
+pub const COMPRESSION_PROGRAM_ID: Pubkey = LOCALNET_COMPRESSION_PROGRAM_ID;
+
+// The address a local build of the compression program is deployed at for localnet builds
+pub const LOCALNET_COMPRESSION_PROGRAM_ID: Pubkey = pubkey!("6ueg4ATZHeq8kiviRgeJoZaxATzVQncrnDbxbcFGy5cr");
+
+// The compression program at COMPRESSION_PROGRAM_ID. The spl-account-compression CPI helpers
+// invoke the program account they are given, so they work with either id.
+#[derive(Clone)]
+pub struct CompressionProgram;
+
+impl Id for CompressionProgram {
+    fn id() -> Pubkey {
+        COMPRESSION_PROGRAM_ID
+    }
+}
+
+// Whether the program is the log mirror set for the tree with set_log_mirror. No program is while
+// the mirror is the default key.
+fn is_log_mirror(admin_config: &AdminConfig, program: &Pubkey) -> bool {
+    admin_config.log_mirror != Pubkey::default() && admin_config.log_mirror == *program
+}
+
+// Logs the data through the noop program, and through the log mirror too when one is passed,
+// invoking it directly with the same instruction data the noop program is invoked with
+fn wrap_log<'info>(
+    data: Vec<u8>,
+    log_wrapper: &Program<'info, Noop>,
+    log_wrapper_mirror: &Option<UncheckedAccount<'info>>,
+) -> Result<()> {
+    if let Some(log_wrapper_mirror) = log_wrapper_mirror {
+        let event = AccountCompressionEvent::ApplicationData(ApplicationDataEvent::V1(ApplicationDataEventV1 {
+            application_data: data.clone(),
+        }));
+        let instruction = Instruction {
+            program_id: log_wrapper_mirror.key(),
+            accounts: vec![],
+            data: event.try_to_vec()?,
+        };
+        invoke(&instruction, &[log_wrapper_mirror.to_account_info()])?;
+    }
+    wrap_application_data_v1(data, log_wrapper)
+}
+
+// Deletes the note at the leaf, replacing it with the deleted leaf of the tree. The nonce of a
+// nonced note is only logged with the tombstone, the leaf is already hashed with it.
+fn delete_note_leaf<'info>(
+    ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+    index: u32,
+    root: [u8; 32],
+    leaf: [u8; 32],
+    nonce: Option<u64>,
+) -> Result<()> {
+    let merkle_tree = ctx.accounts.merkle_tree.key();
+
+    let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+    check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+    let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+    // Verify Leaf, checked without a CPI so a note deleted earlier can fail with its own error
+    let tombstone = deleted_leaf(&merkle_tree);
+    if !leaf_proves(&ctx.accounts.merkle_tree, &proof_nodes, root, leaf, index)? {
+        if leaf_proves(&ctx.accounts.merkle_tree, &proof_nodes, root, tombstone, index)?
+            || leaf_proves(&ctx.accounts.merkle_tree, &proof_nodes, root, EMPTY_LEAF, index)?
+        {
+            return err!(CompressedNotesError::NoteAlreadyDeleted);
+        }
+        msg!("Proof for leaf {} does not verify against root {}", index, Pubkey::from(root));
+        return err!(CompressedNotesError::ProofVerificationFailed);
+    }
+
+    // Log out a tombstone for indexers, the empty note marks the leaf as deleted
+    let note_log = NoteLog {
+        prev_leaf: leaf,
+        nonce,
+        ..NoteLog::new(tombstone, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), String::new(), index.into())?
+    };
+    // Log the "note log" data using noop program
+    wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+    // replace leaf
+    {
+        let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+            .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+        // CPI to replace the leaf node with the deleted leaf
+        replace_leaf(cpi_ctx, root, leaf, tombstone, index)?;
+    }
+
+    uncount_deleted_note(&mut ctx.accounts.note_counter)?;
+    refund_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota)?;
+
+    emit!(NoteDeleted {
+        tree: merkle_tree,
+        index: index.into(),
+        owner: ctx.accounts.owner.key(),
+        leaf,
+    });
+    Ok(())
+}
+
+// Transfers the note to the new owner, re-hashing its leaf with the same nonce, if any.
+fn transfer_note_leaf<'info>(
+    ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+    index: u32,
+    root: [u8; 32],
+    note: String,
+    new_owner: Pubkey,
+    nonce: Option<u64>,
+) -> Result<()> {
+    if new_owner == ctx.accounts.owner.key() {
+        return err!(CompressedNotesError::TransferToSameOwner);
+    }
+
+    let merkle_tree = ctx.accounts.merkle_tree.key();
+    let hash_algo = ctx.accounts.admin_config.hash_algo;
+    let hash = |owner: &Pubkey| match nonce {
+        Some(nonce) => hash_nonced_note(&note, owner, &merkle_tree, nonce, hash_algo),
+        None => hash_note(&note, owner, &merkle_tree, hash_algo),
+    };
+
+    // The current leaf is bound to the current owner, who must sign
+    let old_leaf = hash(&ctx.accounts.owner.key());
+    // The same note bound to the new owner
+    let new_leaf = hash(&new_owner);
+
+    // Move the note to the new owner's quota, so receiving notes can't get around it
+    if ctx.accounts.admin_config.quota_enabled {
+        if let Some(recipient_quota) = &ctx.accounts.recipient_quota {
+            let (expected, _) = Pubkey::find_program_address(
+                &[b"quota", merkle_tree.as_ref(), new_owner.as_ref()],
+                &crate::ID,
+            );
+            if recipient_quota.key() != expected {
+                return err!(CompressedNotesError::WrongRecipientQuota);
+            }
+        }
+        charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.recipient_quota, 1)?;
+        refund_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota)?;
+    }
+
+    let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+    check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+    let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+    // Verify Leaf, in this program so a bad proof can be reported with the index it was for
+    prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
+
+    // Log out for indexers, with the new owner
+    let note_log = NoteLog {
+        prev_leaf: old_leaf,
+        appended_by: ctx.accounts.owner.key(),
+        nonce,
+        ..NoteLog::new(new_leaf, new_owner, ctx.accounts.merkle_tree.key(), note, index.into())?
+    };
+    // Log the "note log" data using noop program
+    wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+    // And the transfer on its own, for indexers that only follow ownership changes
+    let transfer_log = TransferLog::new(ctx.accounts.owner.key(), new_owner, index.into(), new_leaf)?;
+    wrap_log(transfer_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+    // replace leaf
+    {
+        let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+            .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+        // CPI to replace the leaf node with the new owner's leaf
+        replace_leaf(cpi_ctx, root, old_leaf, new_leaf, index)?;
+    }
+
+    // The cache is the sender's, who wrote the new owner's leaf
+    record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index.into())?;
+    emit!(NoteUpdated {
+        tree: merkle_tree,
+        index: index.into(),
+        owner: new_owner,
+        leaf: new_leaf,
+    });
+    Ok(())
+}
+
+// Appends a note leaf to the tree, logging the note with its title, tags and content type.
+// Notes with an expiry are hashed with hash_expiring_note and notes with a nonce with
+// hash_nonced_note, neither of which has a title. A note is given an expiry or a nonce, never both.
+// The index and leaf are set as return data, in the layout documented on append_note.
+#[allow(clippy::too_many_arguments)]
+fn append_note_leaf(
+    ctx: Context<NoteAccounts>,
+    title: String,
+    note: String,
+    tags: Vec<String>,
+    parent: Option<u64>,
+    content_type: String,
+    expires_at: Option<i64>,
+    nonce: Option<u64>,
+    log_note: bool,
+) -> Result<()> {
+    validate_note(&note)?;
+    validate_title(&title)?;
+    validate_tags(&tags)?;
+    validate_content_type(&content_type)?;
+    check_allowlist(&ctx.accounts.admin_config, &ctx.accounts.allowed_owner)?;
+    charge_owner_quota(&ctx.accounts.admin_config, &mut ctx.accounts.owner_quota, 1)?;
+    charge_append_fee(&ctx.accounts.admin_config, &ctx.accounts.owner, &ctx.accounts.treasury, &ctx.accounts.system_program, 1)?;
+    // Hash the "note message" which will be stored as leaf node in the merkle tree
+    let leaf_node = match (expires_at, nonce) {
+        (Some(expires_at), _) => {
+            check_not_expired(expires_at)?;
+            hash_expiring_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), expires_at, ctx.accounts.admin_config.hash_algo)
+        }
+        (None, Some(nonce)) => hash_nonced_note(&note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), nonce, ctx.accounts.admin_config.hash_algo),
+        (None, None) => hash_titled_note(&title, &note, &ctx.accounts.owner.key(), &ctx.accounts.merkle_tree.key(), ctx.accounts.admin_config.hash_algo),
+    };
+    // The leaf is appended at the current leaf count, i.e. its position before the append
+    let index = get_leaf_count(&ctx.accounts.merkle_tree)?;
+    // A reply can only be to a note appended before it
+    if parent.is_some_and(|parent| parent >= index) {
+        return err!(CompressedNotesError::InvalidParentIndex);
+    }
+    if log_note {
+        // Create a new "note log" using the leaf node hash and note.
+        let note_log = NoteLog {
+            title: normalize_note(&title).into_owned(),
+            tags,
+            parent_index: parent,
+            content_type,
+            expires_at,
+            nonce,
+            ..NoteLog::new(leaf_node, ctx.accounts.owner.key(), ctx.accounts.merkle_tree.key(), note, index)?
+        };
+        // Log the "note log" data using noop program, and again through the mirror if passed
+        wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+    }
+    // Get the address for the merkle tree account
+    let merkle_tree = ctx.accounts.merkle_tree.key();
+    let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+    // CPI to append the leaf node to the merkle tree
+    append(modify_ctx(ctx.accounts, signer_seeds), leaf_node)?;
+    debug_msg!("Appended leaf {} at index {}", Pubkey::from(leaf_node), index);
+    set_return_data(&[index.to_le_bytes().as_slice(), leaf_node.as_slice()].concat());
+
+    count_appended_notes(&mut ctx.accounts.note_counter, index, 1)?;
+
+    record_latest_note(&mut ctx.accounts.latest_note, leaf_node, index)?;
+    emit!(NoteAppended {
+        tree: merkle_tree,
+        index,
+        owner: ctx.accounts.owner.key(),
+        leaf: leaf_node,
+    });
+    Ok(())
+}
+
+// Moves a note between statuses, replacing its leaf with the leaf for the new status
+fn set_note_status<'info>(
+    ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+    index: u32,
+    root: [u8; 32],
+    note: String,
+    old_status: u8,
+    new_status: u8,
+) -> Result<()> {
+    let owner = ctx.accounts.owner.key();
+    let merkle_tree = ctx.accounts.merkle_tree.key();
+
+    // The old leaf only verifies if the note currently has the old status
+    let old_leaf = hash_note_with_status(&note, &owner, &merkle_tree, old_status, ctx.accounts.admin_config.hash_algo);
+    let new_leaf = hash_note_with_status(&note, &owner, &merkle_tree, new_status, ctx.accounts.admin_config.hash_algo);
+
+    let signer_seeds: &[&[&[u8]]] = &[&tree_authority_seeds(&merkle_tree, &ctx.bumps.tree_authority)];
+
+    check_leaf_index(&ctx.accounts.merkle_tree, index)?;
+    let proof_nodes = proof_accounts(&ctx.accounts.merkle_tree, ctx.remaining_accounts)?;
+
+    // Verify Leaf, in this program so a bad proof can be reported with the index it was for
+    prove_note_leaf(&ctx.accounts.merkle_tree, &proof_nodes, root, old_leaf, index)?;
+
+    // Log out for indexers, with the new status
+    let note_log = NoteLog {
+        status: new_status,
+        prev_leaf: old_leaf,
+        ..NoteLog::new(new_leaf, owner, ctx.accounts.merkle_tree.key(), note, index.into())?
+    };
+    // Log the "note log" data using noop program
+    wrap_log(note_log.try_to_vec()?, &ctx.accounts.log_wrapper, &ctx.accounts.log_wrapper_mirror)?;
+
+    // replace leaf
+    {
+        let cpi_ctx = modify_ctx(ctx.accounts, signer_seeds)
+            .with_remaining_accounts(proof_nodes.clone()); // The proof nodes, less any cached by the canopy
+        // CPI to replace the leaf node with the leaf for the new status
+        replace_leaf(cpi_ctx, root, old_leaf, new_leaf, index)?;
+    }
+
+    record_latest_note(&mut ctx.accounts.latest_note, new_leaf, index.into())?;
+    emit!(NoteUpdated {
+        tree: merkle_tree,
+        index: index.into(),
+        owner,
+        leaf: new_leaf,
+    });
+    Ok(())
+}
+
+// Checks the owner is allowed to append, when the tree has the owner allowlist enabled. The
+// account constraints check the allowed owner account belongs to the owner of the note.
+fn check_allowlist(admin_config: &AdminConfig, allowed_owner: &Option<Account<AllowedOwner>>) -> Result<()> {
+    if admin_config.allowlist_enabled && allowed_owner.is_none() {
+        return err!(CompressedNotesError::OwnerNotAllowed);
+    }
+    Ok(())
+}
+
+// Counts appended notes against the owner's quota, when the tree has owner quotas enabled
+fn charge_owner_quota(
+    admin_config: &AdminConfig,
+    owner_quota: &mut Option<Account<OwnerQuota>>,
+    notes: u64,
+) -> Result<()> {
+    if !admin_config.quota_enabled {
+        return Ok(());
+    }
+    let owner_quota = owner_quota
+        .as_mut()
+        .ok_or(CompressedNotesError::OwnerQuotaMissing)?;
+    let count = owner_quota
+        .count
+        .checked_add(notes)
+        .ok_or(CompressedNotesError::ArithmeticOverflow)?;
+    if count > MAX_NOTES_PER_OWNER {
+        return err!(CompressedNotesError::QuotaExceeded);
+    }
+    owner_quota.count = count;
+    Ok(())
+}
+
+// Counts notes appended to the tree from the index, failing rather than wrapping the counter
+fn count_appended_notes(note_counter: &mut NoteCounter, index: u64, notes: u64) -> Result<()> {
+    let count = note_counter
+        .count
+        .checked_add(notes)
+        .ok_or(CompressedNotesError::ArithmeticOverflow)?;
+    let next_index = index
+        .checked_add(notes)
+        .ok_or(CompressedNotesError::ArithmeticOverflow)?;
+    note_counter.count = count;
+    note_counter.next_index = next_index;
+    Ok(())
+}
+
+// Stops counting a deleted note, failing rather than wrapping if the counter has no notes
+fn uncount_deleted_note(note_counter: &mut NoteCounter) -> Result<()> {
+    note_counter.count = note_counter
+        .count
+        .checked_sub(1)
+        .ok_or(CompressedNotesError::ArithmeticOverflow)?;
+    Ok(())
+}
+
+// Caches the leaf the owner last appended or updated, when the owner passes their latest note account
+fn record_latest_note(latest_note: &mut Option<Account<LatestNote>>, leaf: [u8; 32], index: u64) -> Result<()> {
+    if let Some(latest_note) = latest_note {
+        latest_note.leaf = leaf;
+        latest_note.index = index;
+        latest_note.updated_at = Clock::get()?.unix_timestamp;
+    }
+    Ok(())
+}
+
+// Transfers the tree's append fee from the owner to the treasury, when the tree charges one.
+// It is called before the append CPI, so the fee is only paid for notes that get appended.
+fn charge_append_fee<'info>(
+    admin_config: &AdminConfig,
+    owner: &Signer<'info>,
+    treasury: &Option<UncheckedAccount<'info>>,
+    system_program: &Program<'info, System>,
+    notes: u64,
+) -> Result<()> {
+    let fee_lamports = admin_config.fee_lamports;
+    if fee_lamports == 0 {
+        return Ok(());
+    }
+    let treasury = treasury
+        .as_ref()
+        .ok_or(CompressedNotesError::TreasuryMissing)?;
+    let cpi_ctx = CpiContext::new(
+        system_program.to_account_info(),
+        system_program::Transfer {
+            from: owner.to_account_info(),
+            to: treasury.to_account_info(),
+        },
+    );
+    let fee = fee_lamports
+        .checked_mul(notes)
+        .ok_or(CompressedNotesError::ArithmeticOverflow)?;
+    system_program::transfer(cpi_ctx, fee)
+}
+
+// Frees a slot in the owner's quota for a note deleted or transferred away, when one is passed.
+// Quotas can only be turned on for an empty tree and transferred notes move to the new owner's
+// quota, so every note of the owner is counted and the count can't go below zero.
+fn refund_owner_quota(
+    admin_config: &AdminConfig,
+    owner_quota: &mut Option<Account<OwnerQuota>>,
+) -> Result<()> {
+    if !admin_config.quota_enabled {
+        return Ok(());
+    }
+    if let Some(owner_quota) = owner_quota {
+        owner_quota.count = owner_quota
+            .count
+            .checked_sub(1)
+            .ok_or(CompressedNotesError::ArithmeticOverflow)?;
+    }
+    Ok(())
+}
+
+// Derives the tree authority pda of a merkle tree and its bump, for clients and tests. The
+// tree_authority constraints derive the same address from the same seeds, with the merkle tree as
+// the only seed, and instructions sign with tree_authority_seeds and the bump Anchor found.
+pub fn tree_authority_pda(merkle_tree: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[merkle_tree.as_ref()], &crate::ID)
+}
+
+// Seeds for signing as the tree authority pda of a merkle tree, with the bump Anchor found
+fn tree_authority_seeds<'a>(merkle_tree: &'a Pubkey, bump: &'a u8) -> [&'a [u8]; 2] {
+    [merkle_tree.as_ref(), std::slice::from_ref(bump)]
+}
+
+// Derives the authority pda of a messages tree and its bump, for clients and tests. The extra seed
+// keeps it apart from the tree authority of a note tree.
+pub fn messages_authority_pda(merkle_tree: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"messages", merkle_tree.as_ref()], &crate::ID)
+}
+
+// Seeds for signing as the authority pda of a messages tree, with the bump Anchor found
+fn messages_authority_seeds<'a>(merkle_tree: &'a Pubkey, bump: &'a u8) -> [&'a [u8]; 3] {
+    [b"messages", merkle_tree.as_ref(), std::slice::from_ref(bump)]
+}
+
+// The accounts an instruction modifies a note or messages tree with, so the cpi contexts can be
+// built from any of the instruction account structs
+trait TreeAccounts<'info> {
+    fn compression_program(&self) -> AccountInfo<'info>;
+    fn tree_authority(&self) -> AccountInfo<'info>;
+    fn merkle_tree(&self) -> AccountInfo<'info>;
+    fn log_wrapper(&self) -> AccountInfo<'info>;
+}
+
+// Implements TreeAccounts for account structs naming the accounts the same as NoteAccounts
+macro_rules! impl_tree_accounts {
+    ($($accounts:ident),* $(,)?) => {
+        $(
+            impl<'info> TreeAccounts<'info> for $accounts<'info> {
+                fn compression_program(&self) -> AccountInfo<'info> {
+                    self.compression_program.to_account_info()
+                }
+                fn tree_authority(&self) -> AccountInfo<'info> {
+                    self.tree_authority.to_account_info()
+                }
+                fn merkle_tree(&self) -> AccountInfo<'info> {
+                    self.merkle_tree.to_account_info()
+                }
+                fn log_wrapper(&self) -> AccountInfo<'info> {
+                    self.log_wrapper.to_account_info()
+                }
+            }
+        )*
+    };
+}
+
+impl_tree_accounts!(
+    NoteAccounts,
+    UniqueNoteAccounts,
+    AppendNoteForAccounts,
+    DelegatedUpdateAccounts,
+    DeleteExpiredNoteAccounts,
+    MessageAccounts,
+);
+
+// Builds the cpi context for modifying the tree of an instruction, signed by the tree authority pda
+fn modify_ctx<'a, 'b, 'c, 'info>(
+    accounts: &impl TreeAccounts<'info>,
+    signer_seeds: &'a [&'b [&'c [u8]]], // The seeds from tree_authority_seeds
+) -> CpiContext<'a, 'b, 'c, 'info, Modify<'info>> {
+    CpiContext::new_with_signer(
+        accounts.compression_program(), // The spl account compression program
+        Modify {
+            authority: accounts.tree_authority(), // The authority for the merkle tree, using a PDA
+            merkle_tree: accounts.merkle_tree(), // The merkle tree account to be modified
+            noop: accounts.log_wrapper(), // The noop program to log data
+        },
+        signer_seeds, // The seeds for pda signing
+    )
+}
+
+// Builds the cpi context for modifying one of the trees of migrate_note, whose accounts hold a
+// tree and tree authority on each side
+fn modify_tree_ctx<'a, 'b, 'c, 'info>(
+    compression_program: &AccountInfo<'info>,
+    tree_authority: &AccountInfo<'info>,
+    merkle_tree: &AccountInfo<'info>,
+    log_wrapper: &AccountInfo<'info>,
+    signer_seeds: &'a [&'b [&'c [u8]]], // The seeds from tree_authority_seeds
+) -> CpiContext<'a, 'b, 'c, 'info, Modify<'info>> {
+    CpiContext::new_with_signer(
+        compression_program.clone(),
+        Modify {
+            authority: tree_authority.clone(),
+            merkle_tree: merkle_tree.clone(),
+            noop: log_wrapper.clone(),
+        },
+        signer_seeds,
+    )
+}
+
+// Checks that a note can be appended to a tree. A note of only whitespace counts as empty, since
+// it would show as a blank entry just the same. Byte notes are not text, so only zero length is.
+fn validate_note(note: &str) -> Result<()> {
+    if note.trim().is_empty() {
+        return err!(CompressedNotesError::EmptyNote);
+    }
+    // Length is measured in bytes of the normalized note, which is what counts against the log limit
+    validate_note_bytes(normalize_note(note).as_bytes())
+}
+
+// Normalizes note text to NFC, so notes that only differ in how their characters are composed,
+// such as an "é" typed as one character or as an "e" and a combining accent, hash to the same
+// leaf. Notes are hashed and logged normalized, so the logged text may differ from the text that
+// was submitted. Text already in NFC, which includes all ASCII, is returned as it is.
+fn normalize_note(note: &str) -> Cow<'_, str> {
+    if is_nfc_quick(note.chars()) == IsNormalized::Yes {
+        return Cow::Borrowed(note);
+    }
+    Cow::Owned(note.nfc().collect())
+}
+
+// Checks that a note of raw bytes can be appended to a tree
+fn validate_note_bytes(data: &[u8]) -> Result<()> {
+    // Empty notes are reserved for the tombstones logged by delete_note
+    if data.is_empty() {
+        return err!(CompressedNotesError::EmptyNote);
+    }
+    if data.len() > MAX_NOTE_LEN {
+        return err!(CompressedNotesError::NoteTooLong);
+    }
+    Ok(())
+}
+
+// Checks that a note title is within the size limit, titles are optional so they may be empty.
+// The length is that of the normalized title, which is what is logged.
+fn validate_title(title: &str) -> Result<()> {
+    if normalize_note(title).len() > MAX_TITLE_LEN {
+        return err!(CompressedNotesError::TitleTooLong);
+    }
+    Ok(())
+}
+
+// Checks that the tags of a note fit in the log
+fn validate_tags(tags: &[String]) -> Result<()> {
+    if tags.len() > MAX_TAGS {
+        return err!(CompressedNotesError::TooManyTags);
+    }
+    if tags.iter().any(|tag| tag.len() > MAX_TAG_LEN) {
+        return err!(CompressedNotesError::TagTooLong);
+    }
+    Ok(())
+}
+
+// Checks that the content type of a note fits in the log
+fn validate_content_type(content_type: &str) -> Result<()> {
+    if content_type.len() > MAX_CONTENT_TYPE_LEN {
+        return err!(CompressedNotesError::ContentTypeTooLong);
+    }
+    Ok(())
+}
+
+// Hashes the preimage of a leaf with the hash algorithm selected for the tree. This only picks how
+// the note is hashed into its leaf, the compression program always hashes the nodes of the tree
+// above the leaves with keccak.
+fn hash_leaf(values: &[&[u8]], hash_algo: u8) -> [u8; 32] {
+    match hash_algo {
+        HASH_ALGO_SHA256 => hash::hashv(values).to_bytes(),
+        _ => keccak::hashv(values).to_bytes(),
+    }
+}
+
+// Hashes a note into its leaf, binding it to its owner and to the tree it is stored in,
+// so the same note by the same owner produces a different leaf in every tree.
+// Every note leaf starts its preimage with NOTE_LEAF_TAG.
+fn hash_note(note: &str, owner: &Pubkey, tree: &Pubkey, hash_algo: u8) -> [u8; 32] {
+    hash_titled_note("", note, owner, tree, hash_algo)
+}
+
+// Hashes a titled note into its leaf. An empty title hashes the same as hash_note, so an untitled
+// note is a note with an empty title. Other titles are hashed under TITLED_NOTE_LEAF_TAG with
+// their length first, so no two splits of the same text into a title and note share a leaf. The
+// title and note are normalized apart, the way each is logged. Instructions that only take a note
+// (delete_note, transfer_note) prove untitled notes, so a titled note has its title cleared with
+// update_titled_note first.
+fn hash_titled_note(title: &str, note: &str, owner: &Pubkey, tree: &Pubkey, hash_algo: u8) -> [u8; 32] {
+    let note = normalize_note(note);
+    if title.is_empty() {
+        return hash_leaf(&[NOTE_LEAF_TAG, note.as_bytes(), owner.as_ref(), tree.as_ref()], hash_algo);
+    }
+    let title = normalize_note(title);
+    let title_len = (title.len() as u32).to_le_bytes();
+    hash_leaf(
+        &[TITLED_NOTE_LEAF_TAG, &title_len, title.as_bytes(), note.as_bytes(), owner.as_ref(), tree.as_ref()],
+        hash_algo,
+    )
+}
+
+// Hashes a note into its leaf for the given status. Active notes hash the same as any other note,
+// other statuses append the status byte so the leaf changes with the status.
+fn hash_note_with_status(note: &str, owner: &Pubkey, tree: &Pubkey, status: u8, hash_algo: u8) -> [u8; 32] {
+    if status == NOTE_STATUS_ACTIVE {
+        return hash_note(note, owner, tree, hash_algo);
+    }
+    let note = normalize_note(note);
+    hash_leaf(&[NOTE_LEAF_TAG, note.as_bytes(), owner.as_ref(), tree.as_ref(), &[status]], hash_algo)
+}
+
+// Hashes a note with an expiry into its leaf. The preimage starts with EXPIRING_NOTE_LEAF_TAG
+// instead of NOTE_LEAF_TAG, so an expiring note can't be updated or verified as a note without one.
+fn hash_expiring_note(note: &str, owner: &Pubkey, tree: &Pubkey, expires_at: i64, hash_algo: u8) -> [u8; 32] {
+    let note = normalize_note(note);
+    hash_leaf(&[EXPIRING_NOTE_LEAF_TAG, note.as_bytes(), owner.as_ref(), tree.as_ref(), &expires_at.to_le_bytes()], hash_algo)
+}
+
+// Hashes a note with a nonce into its leaf. The preimage starts with NONCED_NOTE_LEAF_TAG, so
+// notes with the same text and different nonces have different leaves, and none equals the leaf
+// of the note without a nonce.
+fn hash_nonced_note(note: &str, owner: &Pubkey, tree: &Pubkey, nonce: u64, hash_algo: u8) -> [u8; 32] {
+    let note = normalize_note(note);
+    hash_leaf(&[NONCED_NOTE_LEAF_TAG, note.as_bytes(), owner.as_ref(), tree.as_ref(), &nonce.to_le_bytes()], hash_algo)
+}
+
+// Checks that a note with an expiry is still live, which it is up to and including its expiry
+fn check_not_expired(expires_at: i64) -> Result<()> {
+    let now = Clock::get()?.unix_timestamp;
+    if now > expires_at {
+        msg!("Note expired at {}, it is now {}", expires_at, now);
+        return err!(CompressedNotesError::NoteExpired);
+    }
+    Ok(())
+}
+
+// Wraps a leaf hashed off-chain for append_leaf. The preimage starts with RAW_LEAF_TAG, so a
+// wrapped leaf can't be the leaf of a note, a tombstone or the empty leaf.
+fn hash_raw_leaf(leaf: &[u8; 32], owner: &Pubkey, tree: &Pubkey, hash_algo: u8) -> [u8; 32] {
+    hash_leaf(&[RAW_LEAF_TAG, leaf, owner.as_ref(), tree.as_ref()], hash_algo)
+}
+
+// Hashes a note of raw bytes into its leaf, the same as a text note with the same bytes in NFC
+fn hash_note_bytes(data: &[u8], owner: &Pubkey, tree: &Pubkey, hash_algo: u8) -> [u8; 32] {
+    hash_leaf(&[NOTE_LEAF_TAG, data, owner.as_ref(), tree.as_ref()], hash_algo)
+}
+
+// Hashes a delegated update into the message its owner signs, binding the signature to one
+// replacement of one leaf in one tree, at the owner's current delegate nonce
+fn hash_delegated_update(tree: &Pubkey, index: u32, old_leaf: &[u8; 32], new_leaf: &[u8; 32], nonce: u64) -> [u8; 32] {
+    keccak::hashv(&[
+        b"update_note_delegated",
+        tree.as_ref(),
+        &index.to_le_bytes(),
+        old_leaf,
+        new_leaf,
+        &nonce.to_le_bytes(),
+    ])
+    .to_bytes()
+}
+
+// The leaf written in place of a deleted note. It is tagged and bound to the tree, so unlike the
+// empty leaf it can't be the leaf of a note, and a deleted slot can be told apart from an unused one.
+pub fn deleted_leaf(tree: &Pubkey) -> [u8; 32] {
+    keccak::hashv(&[DELETED_LEAF_TAG, tree.as_ref()]).to_bytes()
+}
+
+// Hashes a message into its leaf, binding it to both the sender and the recipient, and to the tree.
+// The preimage starts with MESSAGE_LEAF_TAG, so no message leaf equals a note leaf.
+// Messages are normalized with normalize_note first, like notes.
+fn hash_message(message: &str, sender: &Pubkey, recipient: &Pubkey, tree: &Pubkey) -> [u8; 32] {
+    let message = normalize_note(message);
+    keccak::hashv(&[MESSAGE_LEAF_TAG, message.as_bytes(), sender.as_ref(), recipient.as_ref(), tree.as_ref()]).to_bytes()
+}
+
+// Size of a change log in the tree, (root, path, index, padding)
+fn change_log_size(max_depth: usize) -> usize {
+    size_of::<Node>() * (max_depth + 1) + 8
+}
+
+// Size of the merkle tree account for the given tree parameters, including the header and canopy
+pub fn merkle_tree_account_size(max_depth: u32, max_buffer_size: u32, canopy_depth: u32) -> usize {
+    // (sequence_number, active_index, buffer_size), the change logs, and the rightmost proof,
+    // which has the same size as a change log
+    let tree_size = 24 + change_log_size(max_depth as usize) * (max_buffer_size as usize + 1);
+    // The canopy stores every node of its levels except the root
+    let canopy_size = ((1usize << (canopy_depth + 1)) - 2) * size_of::<Node>();
+    CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 + tree_size + canopy_size
+}
+
+// Fails early for tree parameters the init CPI would reject, or whose leaf indices overflow a u32
+fn check_tree_config(max_depth: u32, max_buffer_size: u32) -> Result<()> {
+    if max_depth > MAX_SUPPORTED_DEPTH {
+        msg!("Max depth {} is over the max supported depth {}", max_depth, MAX_SUPPORTED_DEPTH);
+        return err!(CompressedNotesError::UnsupportedDepth);
+    }
+    if !SUPPORTED_TREE_CONFIGS.contains(&(max_depth, max_buffer_size)) {
+        msg!("Unsupported tree config: max_depth {}, max_buffer_size {}", max_depth, max_buffer_size);
+        return err!(CompressedNotesError::UnsupportedTreeConfig);
+    }
+    Ok(())
+}
+
+// Fails early if the merkle tree account isn't funded to rent exemption for its size
+fn check_rent_exempt(merkle_tree: &AccountInfo) -> Result<()> {
+    let minimum_balance = Rent::get()?.minimum_balance(merkle_tree.data_len());
+    let lamports = merkle_tree.lamports();
+    if lamports < minimum_balance {
+        msg!("Merkle tree account is {} lamports short of rent exemption", minimum_balance - lamports);
+        return err!(CompressedNotesError::InsufficientRent);
+    }
+    Ok(())
+}
+
+// Deserializes and validates the header at the start of the merkle tree account data
+fn load_tree_header(data: &[u8]) -> Result<ConcurrentMerkleTreeHeader> {
+    let header_bytes = data
+        .get(..CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1)
+        .ok_or(CompressedNotesError::MalformedTreeAccount)?;
+    let header = ConcurrentMerkleTreeHeader::try_from_slice(header_bytes)?;
+    header.assert_valid()?;
+    Ok(header)
+}
+
+// Checks whether the tree has been initialized, from the account type at the start of its header
+fn is_tree_initialized(merkle_tree: &AccountInfo) -> Result<bool> {
+    let data = merkle_tree.try_borrow_data()?;
+    let account_type = data
+        .first()
+        .ok_or(CompressedNotesError::MalformedTreeAccount)?;
+    // Newly allocated accounts are zeroed, which is the uninitialized account type
+    Ok(*account_type != 0)
+}
+
+// Reads N bytes at the given offset of the merkle tree account data
+fn read_tree_bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N]> {
+    let bytes = data
+        .get(offset..offset + N)
+        .ok_or(CompressedNotesError::MalformedTreeAccount)?;
+    Ok(bytes.try_into().unwrap())
+}
+
+// Offsets into the tree match the ConcurrentMerkleTree layout of spl-concurrent-merkle-tree 0.3,
+// used by spl-account-compression 0.3, and must be checked again when upgrading it.
+// The tree follows the header and is laid out as sequence_number, active_index, buffer_size (u64 each),
+// then max_buffer_size change logs of (root, path, index, padding),
+// then the rightmost proof of (proof, leaf, index, padding)
+const TREE_OFFSET: usize = CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1;
+// The header authority is private in spl-account-compression, so it is read from after the
+// account type, header version, max_buffer_size and max_depth
+const HEADER_AUTHORITY_OFFSET: usize = 10;
+const CHANGE_LOGS_OFFSET: usize = TREE_OFFSET + 24;
+
+// Returns whether the tree header names the authority as the authority of the tree, i.e. whether the
+// tree was created by this program with that authority. Accounts too short for a header pass, as
+// Anchor runs this before the owner check, which is left to reject them.
+fn is_tree_of(merkle_tree: &AccountInfo, authority: &Pubkey) -> bool {
+    let Ok(data) = merkle_tree.try_borrow_data() else {
+        return true;
+    };
+    read_tree_bytes::<32>(&data, HEADER_AUTHORITY_OFFSET).map_or(true, |bytes| Pubkey::from(bytes) == *authority)
+}
+
+// Reads the number of leaves appended to the tree, which is also the index of the next append
+fn get_leaf_count(merkle_tree: &AccountInfo) -> Result<u64> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = load_tree_header(&data)?;
+
+    let max_depth = header.get_max_depth() as usize;
+    let max_buffer_size = header.get_max_buffer_size() as usize;
+
+    let offset = CHANGE_LOGS_OFFSET
+        + change_log_size(max_depth) * max_buffer_size
+        + size_of::<Node>() * (max_depth + 1);
+    let index = u32::from_le_bytes(read_tree_bytes(&data, offset)?);
+
+    Ok(index.into())
+}
+
+// Reads the current root of the tree, which is the root of the most recent change log
+fn get_current_root(merkle_tree: &AccountInfo) -> Result<[u8; 32]> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = load_tree_header(&data)?;
+
+    let max_depth = header.get_max_depth() as usize;
+    let max_buffer_size = header.get_max_buffer_size() as usize;
+
+    let active_index = u64::from_le_bytes(read_tree_bytes(&data, TREE_OFFSET + 8)?) as usize;
+    if active_index >= max_buffer_size {
+        return err!(CompressedNotesError::MalformedTreeAccount);
+    }
+
+    read_tree_bytes(&data, CHANGE_LOGS_OFFSET + change_log_size(max_depth) * active_index)
+}
+
+// Checks that the instruction before the current one is an Ed25519 program instruction verifying
+// the owner's signature of the message. The Ed25519 program fails the transaction if the signature
+// is invalid, so only the signed key, signature and message need to be checked here.
+fn verify_owner_signature(instructions: &AccountInfo, owner: &Pubkey, signature: &[u8; 64], message: &[u8]) -> Result<()> {
+    let current_index = load_current_index_checked(instructions)?;
+    let ed25519_ix = current_index
+        .checked_sub(1)
+        .map(|index| load_instruction_at_checked(index.into(), instructions))
+        .transpose()?
+        .ok_or(CompressedNotesError::InvalidOwnerSignature)?;
+    if ed25519_ix.program_id != ed25519_program::ID {
+        return err!(CompressedNotesError::InvalidOwnerSignature);
+    }
+
+    // A single signature, followed by its offsets, as laid out by the Ed25519 program
+    let data = &ed25519_ix.data;
+    if data.len() < 16 || data[0] != 1 {
+        return err!(CompressedNotesError::InvalidOwnerSignature);
+    }
+    let read_u16 = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+    let signature_offset = read_u16(2) as usize;
+    let public_key_offset = read_u16(6) as usize;
+    let message_offset = read_u16(10) as usize;
+    let message_size = read_u16(12) as usize;
+    // The signature, key and message must all be stored in the Ed25519 instruction itself
+    if [read_u16(4), read_u16(8), read_u16(14)] != [u16::MAX; 3] {
+        return err!(CompressedNotesError::InvalidOwnerSignature);
+    }
+
+    let signed_key = data.get(public_key_offset..public_key_offset + 32);
+    let signed_signature = data.get(signature_offset..signature_offset + 64);
+    let signed_message = data.get(message_offset..message_offset + message_size);
+    if signed_key != Some(owner.as_ref()) || signed_signature != Some(signature) || signed_message != Some(message) {
+        return err!(CompressedNotesError::InvalidOwnerSignature);
+    }
+    Ok(())
+}
+
+// Checks that a leaf has been appended at the index. A later index would otherwise only fail
+// deep inside the compression program.
+fn check_leaf_index(merkle_tree: &AccountInfo, index: u32) -> Result<()> {
+    let leaf_count = get_leaf_count(merkle_tree)?;
+    if u64::from(index) >= leaf_count {
+        msg!("Index {} is out of range, the tree has {} leaves", index, leaf_count);
+        return err!(CompressedNotesError::IndexOutOfRange);
+    }
+    Ok(())
+}
+
+// Takes the proof nodes of a leaf from the remaining accounts, which every instruction that proves a
+// leaf goes through. The nodes are hashes passed as account keys rather than real accounts, so
+// only their number is checked, against the levels the canopy doesn't cache.
+fn proof_accounts<'info>(merkle_tree: &AccountInfo, remaining_accounts: &[AccountInfo<'info>]) -> Result<Vec<AccountInfo<'info>>> {
+    check_proof_length(merkle_tree, remaining_accounts.len())?;
+    Ok(remaining_accounts.to_vec())
+}
+
+// Checks that the proof has a node for every level of the tree not cached by the canopy.
+// A proof of the wrong length would otherwise only fail deep inside the compression program.
+fn check_proof_length(merkle_tree: &AccountInfo, proof_len: usize) -> Result<()> {
+    let expected_len = expected_proof_len(merkle_tree)?;
+    if proof_len != expected_len {
+        msg!("Expected {} proof nodes, one per level below the canopy, got {}", expected_len, proof_len);
+        return err!(CompressedNotesError::ProofLengthMismatch);
+    }
+    Ok(())
+}
+
+// Number of proof nodes a leaf of the tree needs, the max depth less the canopy depth
+fn expected_proof_len(merkle_tree: &AccountInfo) -> Result<usize> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = load_tree_header(&data)?;
+    let canopy_depth = get_canopy_depth(&data, &header)?;
+
+    Ok(header.get_max_depth().saturating_sub(canopy_depth) as usize)
+}
+
+// Depth of the canopy, from the space left in the account after the tree
+fn get_canopy_depth(data: &[u8], header: &ConcurrentMerkleTreeHeader) -> Result<u32> {
+    // The canopy follows the tree and stores 2^(canopy_depth + 1) - 2 nodes
+    let canopy_len = data
+        .len()
+        .checked_sub(TREE_OFFSET + merkle_tree_get_size(header)?)
+        .ok_or(CompressedNotesError::MalformedTreeAccount)?;
+    Ok((canopy_len / size_of::<Node>() + 2).ilog2() - 1)
+}
+
+// Checks the leaf against the root the same way the verify_leaf instruction does, but without a CPI.
+// An error in a CPI aborts the transaction, so this is the only way to fail with our own error.
+// The proof is not logged, only the index and root needed to tell which update failed.
+fn prove_note_leaf(merkle_tree: &AccountInfo, proof_accounts: &[AccountInfo], root: [u8; 32], leaf: [u8; 32], index: u32) -> Result<()> {
+    if !leaf_proves(merkle_tree, proof_accounts, root, leaf, index)? {
+        msg!("Proof for leaf {} does not verify against root {}", index, Pubkey::from(root));
+        return err!(CompressedNotesError::ProofVerificationFailed);
+    }
+    Ok(())
+}
+
+// Returns whether the proof proves the leaf is at the index, failing only if it can't be checked
+fn leaf_proves(merkle_tree: &AccountInfo, proof_accounts: &[AccountInfo], root: [u8; 32], leaf: [u8; 32], index: u32) -> Result<bool> {
+    // A root that has left the buffer can't be proven against, however valid the proof was
+    if !is_recent_root(merkle_tree, &root)? {
+        msg!("Root {} is no longer in the change log buffer, retry with the current root and proof", Pubkey::from(root));
+        return err!(CompressedNotesError::RootNotInBuffer);
+    }
+
+    let data = merkle_tree.try_borrow_data()?;
+    let header = load_tree_header(&data)?;
+    header.assert_valid_leaf_index(index)?;
+
+    let tree_size = merkle_tree_get_size(&header)?;
+    let tree_bytes = data
+        .get(TREE_OFFSET..TREE_OFFSET + tree_size)
+        .ok_or(CompressedNotesError::MalformedTreeAccount)?;
+    let proof = load_full_proof(merkle_tree, proof_accounts, index)?;
+
+    let args = ProveLeafArgs {
+        current_root: root,
+        leaf,
+        proof_vec: proof,
+        index,
+    };
+    Ok(merkle_tree_prove_leaf(&header, merkle_tree.key(), tree_bytes, &args).is_ok())
+}
+
+// The full proof of the leaf at an index, the proof nodes passed in followed by the nodes cached
+// by the canopy
+fn load_full_proof(merkle_tree: &AccountInfo, proof_accounts: &[AccountInfo], index: u32) -> Result<Vec<Node>> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = load_tree_header(&data)?;
+
+    let tree_size = merkle_tree_get_size(&header)?;
+    let canopy_bytes = data
+        .get(TREE_OFFSET + tree_size..)
+        .ok_or(CompressedNotesError::MalformedTreeAccount)?;
+
+    // The proof nodes, less any cached by the canopy
+    let mut proof: Vec<Node> = proof_accounts.iter().map(|node| node.key().to_bytes()).collect();
+    fill_in_proof_from_canopy(canopy_bytes, header.get_max_depth(), index, &mut proof)?;
+    Ok(proof)
+}
+
+// Recomputes the root from a leaf and its full proof, hashing each pair the way the concurrent
+// merkle tree does, with the node on the left when its index bit at that level is 0
+fn recompute_root(leaf: [u8; 32], proof: &[Node], index: u32) -> [u8; 32] {
+    let mut node = leaf;
+    for (level, sibling) in proof.iter().enumerate() {
+        node = if (index >> level) & 1 == 0 {
+            keccak::hashv(&[&node, sibling]).to_bytes()
+        } else {
+            keccak::hashv(&[sibling, &node]).to_bytes()
+        };
+    }
+    node
+}
+
+// Checks whether the root is one of the recent roots kept in the change log buffer, which are
+// the only roots a proof can still be verified against
+fn is_recent_root(merkle_tree: &AccountInfo, root: &[u8; 32]) -> Result<bool> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = load_tree_header(&data)?;
+
+    let max_depth = header.get_max_depth() as usize;
+    let max_buffer_size = header.get_max_buffer_size() as usize;
+
+    // The buffer_size change logs up to and including the active one are in use
+    let active_index = u64::from_le_bytes(read_tree_bytes(&data, TREE_OFFSET + 8)?) as usize;
+    let buffer_size = u64::from_le_bytes(read_tree_bytes(&data, TREE_OFFSET + 16)?) as usize;
+    if active_index >= max_buffer_size || buffer_size > max_buffer_size {
+        return err!(CompressedNotesError::MalformedTreeAccount);
+    }
+
+    for i in 0..buffer_size {
+        let change_log_index = (active_index + max_buffer_size - i) % max_buffer_size;
+        let change_log_root: [u8; 32] =
+            read_tree_bytes(&data, CHANGE_LOGS_OFFSET + change_log_size(max_depth) * change_log_index)?;
+        if change_log_root == *root {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Estimates the compute cost of verifying and replacing a leaf in a tree of the given depth.
+// Both recompute the root from the full proof, so canopy levels cost the same as proof nodes.
+fn estimate_update_cost(max_depth: u32) -> u64 {
+    UPDATE_BASE_COST + u64::from(max_depth) * UPDATE_COST_PER_LEVEL
+}
+
+// Fails with a descriptive error if an update of the tree would likely run out of compute
+fn check_update_budget(merkle_tree: &AccountInfo) -> Result<()> {
+    let data = merkle_tree.try_borrow_data()?;
+    let max_depth = load_tree_header(&data)?.get_max_depth();
+
+    let cost = estimate_update_cost(max_depth);
+    let remaining = sol_remaining_compute_units();
+    if remaining < cost {
+        msg!(
+            "Updating a leaf at depth {} needs about {} compute units but only {} remain, add a ComputeBudget instruction to raise the limit",
+            max_depth,
+            cost,
+            remaining
+        );
+        return err!(CompressedNotesError::ProofTooExpensive);
+    }
+    Ok(())
+}
+
+// Checks whether every leaf of the tree is empty, by comparing its root to the root of an empty tree
+fn is_tree_empty(merkle_tree: &AccountInfo) -> Result<bool> {
+    let max_depth = load_tree_header(&merkle_tree.try_borrow_data()?)?.get_max_depth();
+
+    let mut empty_root = EMPTY_LEAF;
+    for _ in 0..max_depth {
+        empty_root = keccak::hashv(&[&empty_root, &empty_root]).to_bytes();
+    }
+
+    Ok(get_current_root(merkle_tree)? == empty_root)
+}
+
+// Error codes are assigned in declaration order, so new variants must be added at the end
+#[error_code]
+pub enum CompressedNotesError {
+    #[msg("Note has already been deleted")]
+    NoteAlreadyDeleted, // 6000
+    #[msg("New note is identical to the old note")]
+    NotesAreIdentical, // 6001
+    #[msg("Note is too long")]
+    NoteTooLong, // 6002
+    #[msg("Note cannot be empty or only whitespace")]
+    EmptyNote, // 6003
+    #[msg("Signer is not authorized for this action")]
+    Unauthorized, // 6004
+    #[msg("Note is already owned by the new owner")]
+    TransferToSameOwner, // 6005
+    #[msg("Merkle tree account data is malformed")]
+    MalformedTreeAccount, // 6006
+    #[msg("Canopy depth cannot exceed the max depth of the tree")]
+    InvalidCanopyDepth, // 6007
+    #[msg("Merkle tree account size does not match the tree parameters")]
+    TreeAccountSizeMismatch, // 6008
+    #[msg("Merkle tree still contains notes")]
+    TreeNotEmpty, // 6009
+    #[msg("Too many notes in batch")]
+    BatchTooLarge, // 6010
+    #[msg("Note title is too long")]
+    TitleTooLong, // 6011
+    #[msg("Note has likely already been appended")]
+    DuplicateNote, // 6012
+    #[msg("Note has too many tags")]
+    TooManyTags, // 6013
+    #[msg("Note tag is too long")]
+    TagTooLong, // 6014
+    #[msg("Not enough compute units to update the tree, request more with a ComputeBudget instruction")]
+    ProofTooExpensive, // 6015
+    #[msg("Owner has reached the max number of notes in this tree")]
+    QuotaExceeded, // 6016
+    #[msg("Owner quota account is required when owner quotas are enabled")]
+    OwnerQuotaMissing, // 6017
+    #[msg("Merkle tree account has already been initialized")]
+    TreeAlreadyInitialized, // 6018
+    #[msg("Existing merkle tree does not match the requested depth and buffer size")]
+    TreeParamsMismatch, // 6019
+    #[msg("Proof does not verify the old note against the given root")]
+    ProofVerificationFailed, // 6020
+    #[msg("Number of proof nodes does not match the tree depth less the canopy depth")]
+    ProofLengthMismatch, // 6021
+    #[msg("Owner has not signed this update with an Ed25519 instruction before it")]
+    InvalidOwnerSignature, // 6022
+    #[msg("Root is no longer in the change log buffer of the tree, fetch the current root and proof and retry")]
+    RootNotInBuffer, // 6023
+    #[msg("Note was modified since the expected leaf was read")]
+    ConcurrentModification, // 6024
+    #[msg("Tree registry already lists the max number of trees")]
+    RegistryFull, // 6025
+    #[msg("Tree is not listed in the tree registry")]
+    TreeNotRegistered, // 6026
+    #[msg("Hash algorithm must be HASH_ALGO_KECCAK or HASH_ALGO_SHA256")]
+    InvalidHashAlgo, // 6027
+    #[msg("Max depth and max buffer size are not a supported tree config")]
+    UnsupportedTreeConfig, // 6028
+    #[msg("Merkle tree account is not funded to rent exemption for its size")]
+    InsufficientRent, // 6029
+    #[msg("Owner is not on the allowlist of the tree")]
+    OwnerNotAllowed, // 6030
+    #[msg("Compression algorithm must be one of the COMPRESSION_ALGO constants")]
+    InvalidCompressionAlgo, // 6031
+    #[msg("Index is past the last leaf appended to the tree")]
+    IndexOutOfRange, // 6032
+    #[msg("Tree is paused by its admin")]
+    ProgramPaused, // 6033
+    #[msg("Treasury account is required when the tree charges an append fee")]
+    TreasuryMissing, // 6034
+    #[msg("Slot does not hold a deleted note")]
+    SlotOccupied, // 6035
+    #[msg("Note must be migrated to a different tree")]
+    MigrationToSameTree, // 6036
+    #[msg("Merkle tree account is not owned by the spl account compression program")]
+    WrongAccountOwner, // 6037
+    #[msg("Parent index must be a note already appended to the tree")]
+    InvalidParentIndex, // 6038
+    #[msg("Note content type is too long")]
+    ContentTypeTooLong, // 6039
+    #[msg("Note has expired")]
+    NoteExpired, // 6040
+    #[msg("Note has not expired yet")]
+    NoteNotExpired, // 6041
+    #[msg("Max depth is deeper than MAX_SUPPORTED_DEPTH, leaf indices would overflow a u32")]
+    UnsupportedDepth, // 6042
+    #[msg("Merkle tree was not created by this program, its authority is not this program's tree authority")]
+    NotOurTree, // 6043
+    #[msg("Note scratch account is required to append a note from it")]
+    NoteScratchMissing, // 6044
+    #[msg("Arithmetic overflowed a counter or fee")]
+    ArithmeticOverflow, // 6045
+    #[msg("Recipient quota is not the new owner's quota for this tree")]
+    WrongRecipientQuota, // 6046
+    #[msg("Log mirror is not the log mirror set for this tree")]
+    LogMirrorNotAllowed, // 6047
+    #[msg("Merkle tree was not created as a note tree")]
+    NotANoteTree, // 6048
+    #[msg("New admin can't be the default key or the system program")]
+    InvalidNewAdmin, // 6049
+}
+
+// Version of the log layout, written as the first byte of every log so indexers can tell which
+// layout to parse before reading the rest. It is bumped whenever the fields of NoteLog or the leaf
+// format change, and every version keeps the version byte first. NoteLog fields by version:
+//   1: version, leaf_node, owner, note, created_at
+//   2: adds index
+//   3: adds title, hashed into the leaf
+//   4: adds tags
+//   5: adds status, hashed into the leaf
+//   6: no new fields, leaf preimages are prefixed with domain tags
+//   7: adds tree, after owner
+//   8: adds prev_leaf
+//   9: no new fields, deleted notes are replaced with a per-tree deleted leaf instead of zeros
+//   10: adds parent_index
+//   11: adds content_type
+//   12: adds expires_at, hashed into the leaf of expiring notes
+//   13: adds appended_by
+//   14: adds nonce, hashed into the leaf of nonced notes
+//   15: no new fields, notes, titles and messages are hashed and logged in NFC, with a title and
+//       its note normalized joined. Notes appended before 15 whose text wasn't already in NFC were
+//       hashed as submitted, and no instruction can prove them since all of them normalize first.
+//       Owners migrate such a note by appending it again, and indexers drop the old leaf by index.
+//   16: adds tag, after version, to every log, and MessageLog gains the version byte. Each kind
+//       of log has its own tag, see NOTE_LOG_TAG and the tags below it
+//   17: adds tree, after owner, to RawNoteLog and CompressedNoteLog
+//   18: no new fields, titled notes are hashed under TITLED_NOTE_LEAF_TAG with the length of the
+//       title before it, and the title and note are normalized apart. Untitled notes hash as before.
+// Logs written before version 1 have no version byte and start with the leaf node.
+pub const CURRENT_LOG_VERSION: u8 = 18;
+
+// Tags following the version byte of every log, so indexers can tell the kind of a log apart
+// before parsing it
+pub const NOTE_LOG_TAG: [u8; 8] = *b"note-log";
+pub const RAW_NOTE_LOG_TAG: [u8; 8] = *b"raw-note";
+pub const COMPRESSED_NOTE_LOG_TAG: [u8; 8] = *b"zip-note";
+pub const MESSAGE_LOG_TAG: [u8; 8] = *b"message\0";
+
+// Domain tags prepended to the leaf preimages, so note, message and deleted leaves can never
+// collide and the leaf format can be versioned
+pub const NOTE_LEAF_TAG: &[u8] = b"note:v1";
+pub const TITLED_NOTE_LEAF_TAG: &[u8] = b"titled-note:v1";
+pub const MESSAGE_LEAF_TAG: &[u8] = b"message:v1";
+pub const DELETED_LEAF_TAG: &[u8] = b"deleted:v1";
+pub const EXPIRING_NOTE_LEAF_TAG: &[u8] = b"expiring-note:v1";
+pub const NONCED_NOTE_LEAF_TAG: &[u8] = b"nonced-note:v1";
+pub const RAW_LEAF_TAG: &[u8] = b"leaf:v1";
+
+// Status of a note that is in use
+pub const NOTE_STATUS_ACTIVE: u8 = 0;
+
+// Status of a note that has been archived with archive_note
+pub const NOTE_STATUS_ARCHIVED: u8 = 1;
+
+// One note to update with update_notes
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct UpdateItem {
+    pub index: u32,       // The leaf index of the note
+    pub root: [u8; 32],   // The root the proof of the note was taken at
+    pub old_note: String, // The current note
+    pub new_note: String, // The note to replace it with
+}
+
+// One note to check with verify_notes
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct VerifyItem {
+    pub index: u32,        // The leaf index of the note
+    pub root: [u8; 32],    // The root the proof of the note was taken at
+    pub note: String,      // The note to check for
+    pub proof_offset: u16, // Position of the first proof node of the note in the remaining accounts
+}
+
+// Return data of batch_append_notes
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct BatchAppendResult {
+    pub start_index: u64, // The leaf index of the first note in the batch
+    pub count: u32,       // The number of notes appended
+}
+
+#[derive(AnchorSerialize)]
+pub struct NoteLog {
+    version: u8,               // The log layout version, always CURRENT_LOG_VERSION and always the first byte
+    tag: [u8; 8],              // Always NOTE_LOG_TAG
+    leaf_node: [u8; 32],       // The leaf node hash, for append_leaf the given leaf wrapped with hash_raw_leaf
+    owner: Pubkey,             // Pubkey of the note owner
+    tree: Pubkey,              // The merkle tree the note is in
+    note: String,              // The note message
+    created_at: i64,           // Unix timestamp of when the log was written
+    index: u64,                // The leaf index of the note, for appends this is the position before the append
+    title: String,             // The note title, empty for untitled notes
+    tags: Vec<String>,         // Tags for indexers to filter on, not part of the leaf hash
+    status: u8,                // The note status, NOTE_STATUS_ACTIVE or NOTE_STATUS_ARCHIVED
+    prev_leaf: [u8; 32],       // The leaf replaced by this one, zero for appends, linking the versions of a note
+    parent_index: Option<u64>, // The index of the note this one replies to, not part of the leaf hash
+    content_type: String,      // MIME hint for rendering the note, not part of the leaf hash
+    expires_at: Option<i64>,   // Unix timestamp the note expires at, hashed into the leaf when set
+    appended_by: Pubkey,       // The signer who submitted the log, the owner unless a payer or delegate acted for them
+    nonce: Option<u64>,        // Nonce telling apart notes with the same text, hashed into the leaf when set
+}
+
+// Max serialized size of a NoteLog, for clients budgeting log space. It must be updated along with
+// the fields of NoteLog, each term below is the max Borsh size of one field in order.
+pub const fn max_note_log_size() -> usize {
+    1 // version
+        + 8 // tag
+        + 32 // leaf_node
+        + 32 // owner
+        + 32 // tree
+        + 4 + MAX_NOTE_LEN // note
+        + 8 // created_at
+        + 8 // index
+        + 4 + MAX_TITLE_LEN // title
+        + 4 + MAX_TAGS * (4 + MAX_TAG_LEN) // tags
+        + 1 // status
+        + 32 // prev_leaf
+        + 1 + 8 // parent_index
+        + 4 + MAX_CONTENT_TYPE_LEN // content_type
+        + 1 + 8 // expires_at
+        + 32 // appended_by
+        + 1 + 8 // nonce
+}
+
+impl NoteLog {
+    // Constructs a new note from given leaf node and message, timestamped with the current clock.
+    // The note is taken to be submitted by its owner, set appended_by when someone else signed.
+    // The note is logged normalized, as it was hashed.
+    pub fn new(leaf_node: [u8; 32], owner: Pubkey, tree: Pubkey, note: String, index: u64) -> Result<Self> {
+        Ok(Self {
+            version: CURRENT_LOG_VERSION,
+            tag: NOTE_LOG_TAG,
+            leaf_node,
+            owner,
+            tree,
+            note: normalize_note(&note).into_owned(),
+            created_at: Clock::get()?.unix_timestamp,
+            index,
+            title: String::new(),
+            tags: Vec::new(),
+            status: NOTE_STATUS_ACTIVE,
+            prev_leaf: [0; 32],
+            parent_index: None,
+            content_type: DEFAULT_CONTENT_TYPE.to_string(),
+            expires_at: None,
+            appended_by: owner,
+            nonce: None,
+        })
+    }
+}
+
+#[derive(AnchorSerialize)]
+pub struct RawNoteLog {
+    version: u8,          // The NoteLog layout version the log was written with
+    tag: [u8; 8],         // Always RAW_NOTE_LOG_TAG
+    leaf_node: [u8; 32],  // The leaf node hash
+    owner: Pubkey,        // Pubkey of the note owner
+    tree: Pubkey,         // The merkle tree the note is in
+    data: Vec<u8>,        // The raw note bytes
+    created_at: i64,      // Unix timestamp of when the log was written
+    index: u64,           // The leaf index of the note
+}
+
+impl RawNoteLog {
+    // Constructs a new raw note log from given leaf node and bytes, timestamped with the current clock
+    pub fn new(leaf_node: [u8; 32], owner: Pubkey, tree: Pubkey, data: Vec<u8>, index: u64) -> Result<Self> {
+        Ok(Self {
+            version: CURRENT_LOG_VERSION,
+            tag: RAW_NOTE_LOG_TAG,
+            leaf_node,
+            owner,
+            tree,
+            data,
+            created_at: Clock::get()?.unix_timestamp,
+            index,
+        })
+    }
+}
+
+// Compressed notes are run-length encoded, as pairs of a repeat count from 1 to 255 and a byte
+pub const COMPRESSION_ALGO_RLE: u8 = 0;
+
+// Compressed notes are a single zstd frame
+pub const COMPRESSION_ALGO_ZSTD: u8 = 1;
+
+#[derive(AnchorSerialize)]
+pub struct CompressedNoteLog {
+    version: u8,          // The NoteLog layout version the log was written with
+    tag: [u8; 8],         // Always COMPRESSED_NOTE_LOG_TAG
+    leaf_node: [u8; 32],  // The leaf node hash
+    owner: Pubkey,        // Pubkey of the note owner
+    tree: Pubkey,         // The merkle tree the note is in
+    algo: u8,             // How the note was compressed, one of the COMPRESSION_ALGO constants
+    data: Vec<u8>,        // The compressed note bytes
+    created_at: i64,      // Unix timestamp of when the log was written
+    index: u64,           // The leaf index of the note
+}
+
+impl CompressedNoteLog {
+    // Constructs a new compressed note log from given leaf node and bytes, timestamped with the current clock
+    pub fn new(leaf_node: [u8; 32], owner: Pubkey, tree: Pubkey, algo: u8, data: Vec<u8>, index: u64) -> Result<Self> {
+        Ok(Self {
+            version: CURRENT_LOG_VERSION,
+            tag: COMPRESSED_NOTE_LOG_TAG,
+            leaf_node,
+            owner,
+            tree,
+            algo,
+            data,
+            created_at: Clock::get()?.unix_timestamp,
+            index,
+        })
+    }
+}
+
+// Tag following the version byte of a TransferLog
+pub const TRANSFER_LOG_TAG: [u8; 8] = *b"transfer";
+
+// Logged by transfer_note next to the NoteLog of the new leaf, as an audit trail of ownership changes
+#[derive(AnchorSerialize)]
+pub struct TransferLog {
+    version: u8,          // The log layout version the log was written with
+    tag: [u8; 8],         // Always TRANSFER_LOG_TAG
+    from: Pubkey,         // Pubkey of the previous note owner
+    to: Pubkey,           // Pubkey of the new note owner
+    index: u64,           // The leaf index of the note
+    leaf: [u8; 32],       // The leaf node hash under the new owner
+    ts: i64,              // Unix timestamp of when the log was written
+}
+
+impl TransferLog {
+    // Constructs a new transfer log, timestamped with the current clock
+    pub fn new(from: Pubkey, to: Pubkey, index: u64, leaf: [u8; 32]) -> Result<Self> {
+        Ok(Self {
+            version: CURRENT_LOG_VERSION,
+            tag: TRANSFER_LOG_TAG,
+            from,
+            to,
+            index,
+            leaf,
+            ts: Clock::get()?.unix_timestamp,
+        })
+    }
+}
+
+#[derive(AnchorSerialize)]
+pub struct MessageLog {
+    version: u8,          // The log layout version the log was written with
+    tag: [u8; 8],         // Always MESSAGE_LOG_TAG
+    leaf_node: [u8; 32],  // The leaf node hash
+    sender: Pubkey,       // Pubkey of the message sender
+    recipient: Pubkey,    // Pubkey of the message recipient
+    message: String,      // The message
+    created_at: i64,      // Unix timestamp of when the log was written
+    index: u64,           // The leaf index of the message
+}
+
+impl MessageLog {
+    // Constructs a new message log from given leaf node and message, timestamped with the current clock
+    pub fn new(
+        leaf_node: [u8; 32],
+        sender: Pubkey,
+        recipient: Pubkey,
+        message: String,
+        index: u64,
+    ) -> Result<Self> {
+        Ok(Self {
+            version: CURRENT_LOG_VERSION,
+            tag: MESSAGE_LOG_TAG,
+            leaf_node,
+            sender,
+            recipient,
+            message: normalize_note(&message).into_owned(), // Logged as it was hashed
+            created_at: Clock::get()?.unix_timestamp,
+            index,
+        })
+    }
+}
+
+#[event]
+pub struct NoteTreeEnsured {
+    pub tree: Pubkey,  // The merkle tree account
+    pub created: bool, // Whether the tree was created, false if it already existed
+}
+
+#[event]
+pub struct NoteAppended {
+    pub tree: Pubkey,     // The merkle tree the note was appended to
+    pub index: u64,       // The leaf index of the note
+    pub owner: Pubkey,    // Pubkey of the note owner
+    pub leaf: [u8; 32],   // The leaf node hash
+}
+
+#[event]
+pub struct NoteUpdated {
+    pub tree: Pubkey,     // The merkle tree of the note
+    pub index: u64,       // The leaf index of the note
+    pub owner: Pubkey,    // Pubkey of the note owner
+    pub leaf: [u8; 32],   // The new leaf node hash
+}
+
+#[event]
+pub struct NoteDeleted {
+    pub tree: Pubkey,     // The merkle tree of the note
+    pub index: u64,       // The leaf index of the note
+    pub owner: Pubkey,    // Pubkey of the note owner
+    pub leaf: [u8; 32],   // The leaf node hash that was deleted
+}
+
+#[event]
+pub struct TreeStats {
+    pub tree: Pubkey,         // The merkle tree the stats are for
+    pub root: [u8; 32],       // The current root of the tree
+    pub rightmost_index: u64, // Index of the rightmost proof, i.e. the number of leaves appended
+    pub max_depth: u32,       // Max depth of the tree
+    pub buffer_size: u64,     // Number of change logs in the buffer, up to the max buffer size
+    pub authority: Pubkey,    // The tree authority that can modify the tree
+}
+
+#[event]
+pub struct OwnershipProven {
+    pub owner: Pubkey,    // Pubkey of the note owner
+    pub tree: Pubkey,     // The merkle tree of the note
+    pub index: u64,       // The leaf index of the note
+    pub leaf: [u8; 32],   // The leaf node hash that was proven
+    pub root: [u8; 32],   // The root the leaf was proven against
+}
+
+// Tracks the notes stored in a merkle tree
+#[account]
+#[derive(InitSpace)]
+pub struct NoteCounter {
+    pub count: u64,       // Number of notes in the tree, not counting deleted notes
+    pub next_index: u64,  // The leaf index the next appended note will be stored at
+}
+
+impl NoteCounter {
+    // Space of the account, including the discriminator
+    pub const LEN: usize = 8 + Self::INIT_SPACE;
+}
+
+// A bloom filter of the leaves appended to a merkle tree through append_unique_note
+#[account]
+#[derive(InitSpace)]
+pub struct NoteFilter {
+    pub bits: [u8; FILTER_BITS / 8], // The filter bits, set but never cleared
+}
+
+impl NoteFilter {
+    // Space of the account, including the discriminator
+    pub const LEN: usize = 8 + Self::INIT_SPACE;
+
+    // The bits of the filter for a leaf, taken from the low bits of consecutive pairs of leaf bytes.
+    // Leaves are keccak hashes, so each pair is already uniformly distributed.
+    fn positions(leaf: &[u8; 32]) -> [usize; FILTER_HASHES] {
+        let mut positions = [0; FILTER_HASHES];
+        for (i, position) in positions.iter_mut().enumerate() {
+            let bytes = [leaf[2 * i], leaf[2 * i + 1]];
+            *position = u16::from_le_bytes(bytes) as usize % FILTER_BITS;
+        }
+        positions
+    }
+
+    // Records a leaf in the filter, failing if the leaf has likely been recorded before
+    pub fn insert(&mut self, leaf: &[u8; 32]) -> Result<()> {
+        let positions = Self::positions(leaf);
+        if positions
+            .iter()
+            .all(|&position| self.bits[position / 8] & (1 << (position % 8)) != 0)
+        {
+            return err!(CompressedNotesError::DuplicateNote);
+        }
+        for position in positions {
+            self.bits[position / 8] |= 1 << (position % 8);
+        }
+        Ok(())
+    }
+}
+
+// Leaves are hashed with keccak, the same hash the compression program uses for the tree
+pub const HASH_ALGO_KECCAK: u8 = 0;
+
+// Leaves are hashed with sha256, for integrators that need sha256 leaf preimages
+pub const HASH_ALGO_SHA256: u8 = 1;
+
+// The admin of a merkle tree, who can perform administrative instructions such as closing the tree
+#[account]
+#[derive(InitSpace)]
+pub struct AdminConfig {
+    pub admin: Pubkey,           // The current admin, initially the creator of the tree
+    pub quota_enabled: bool,     // Whether appends are limited to MAX_NOTES_PER_OWNER per owner
+    pub hash_algo: u8,           // The hash notes are hashed into leaves with, HASH_ALGO_KECCAK by default
+    pub allowlist_enabled: bool, // Whether only owners added by the admin can append
+    pub paused: bool,            // Whether appends, updates and deletes are halted
+    pub fee_lamports: u64,       // Fee charged to the owner per appended note, none when zero
+    pub treasury: Pubkey,        // The account append fees are paid to
+    pub renounced: bool,         // Whether the admin role was given up with renounce_admin, for good
+    pub log_mirror: Pubkey,      // The program the logs may also be mirrored to, none while the default key
+}
+
+impl AdminConfig {
+    // Space of the account, including the discriminator
+    pub const LEN: usize = 8 + Self::INIT_SPACE;
+}
+
+// Marks an owner as allowed to append to a merkle tree with the owner allowlist enabled
+#[account]
+#[derive(InitSpace)]
+pub struct AllowedOwner {}
+
+impl AllowedOwner {
+    // Space of the account, including the discriminator
+    pub const LEN: usize = 8 + Self::INIT_SPACE;
+}
+
+// Counts the notes an owner has in a merkle tree with owner quotas enabled
+#[account]
+#[derive(InitSpace)]
+pub struct OwnerQuota {
+    pub count: u64, // Number of notes appended by the owner, not counting deleted notes
+}
+
+impl OwnerQuota {
+    // Space of the account, including the discriminator
+    pub const LEN: usize = 8 + Self::INIT_SPACE;
+}
+
+// Counts the delegated updates an owner signed for a merkle tree, so each signature is used once
+#[account]
+#[derive(InitSpace)]
+pub struct DelegateNonce {
+    pub nonce: u64, // The nonce the owner's next delegated update must be signed for
+}
+
+impl DelegateNonce {
+    // Space of the account, including the discriminator
+    pub const LEN: usize = 8 + Self::INIT_SPACE;
+}
+
+// The note an owner last appended or updated in a merkle tree, so clients can show it without an
+// indexer. This is a convenience cache, not authoritative state: the tree and the note logs are
+// the source of truth, and the cache is only written when the owner passes it. The owner pays
+// rent for LEN (56) bytes, 1,280,640 lamports, when the cache is first created.
+#[account]
+#[derive(InitSpace)]
+pub struct LatestNote {
+    pub leaf: [u8; 32],  // Leaf hash of the latest note
+    pub index: u64,      // Leaf index of the latest note
+    pub updated_at: i64, // Unix timestamp of when the cache was last written
+}
+
+impl LatestNote {
+    // Space of the account, including the discriminator
+    pub const LEN: usize = 8 + Self::INIT_SPACE;
+}
+
+// The scratch account a long note is assembled in, one per owner and tree. It is allocated for
+// MAX_SCRATCH_NOTE_LEN bytes up front, so the owner pays rent for LEN (4108) bytes until the note
+// is appended or the scratch account is closed.
+#[account]
+pub struct NoteScratch {
+    pub data: Vec<u8>, // The note bytes written so far
+}
+
+impl NoteScratch {
+    // Space of the account, including the discriminator
+    pub const LEN: usize = 8 + 4 + MAX_SCRATCH_NOTE_LEN;
+}
+
+// The trees created by an admin, for clients managing many trees
+#[account]
+pub struct TreeRegistry {
+    pub trees: Vec<Pubkey>, // The merkle trees, in the order they were created
+}
+
+impl TreeRegistry {
+    // Space of a registry listing the max number of trees, including the discriminator. The
+    // account starts smaller and is reallocated as trees are listed.
+    pub const LEN: usize = Self::space(MAX_REGISTRY_TREES);
+
+    // Space of a registry listing the given number of trees, including the discriminator
+    pub const fn space(len: usize) -> usize {
+        8 + 4 + 32 * len
+    }
+
+    // Number of trees a registry account of the given size has room for
+    pub fn capacity(data_len: usize) -> usize {
+        data_len.saturating_sub(8 + 4) / 32
+    }
+
+    // Lists a tree, the account must already be reallocated to fit it
+    pub fn register(&mut self, tree: Pubkey) -> Result<()> {
+        if self.trees.len() >= MAX_REGISTRY_TREES {
+            return err!(CompressedNotesError::RegistryFull);
+        }
+        self.trees.push(tree);
+        Ok(())
+    }
+
+    // Removes a tree, the account keeps its size so the slot can be reused
+    pub fn deregister(&mut self, tree: &Pubkey) -> Result<()> {
+        let position = self
+            .trees
+            .iter()
+            .position(|registered| registered == tree)
+            .ok_or(CompressedNotesError::TreeNotRegistered)?;
+        self.trees.remove(position);
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CreateNoteTreeAccounts<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the merkle tree
+    #[account(
+        init,
+        payer = owner,
+        space = NoteCounter::LEN,
+        seeds = [b"counter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the merkle tree
+    #[account(
+        init,
+        payer = owner,
+        space = AdminConfig::LEN,
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The registry of the trees created by the owner, only listed in if passed
+    #[account(
+        mut,
+        seeds = [b"registry", owner.key().as_ref()],
+        bump,
+        // Keeps any room reserved with grow_registry
+        realloc = TreeRegistry::space(tree_registry.trees.len() + 1).max(tree_registry.to_account_info().data_len()),
+        realloc::payer = owner,
+        realloc::zero = false,
+    )]
+    pub tree_registry: Option<Account<'info, TreeRegistry>>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct EnsureNoteTreeAccounts<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the merkle tree, created along with the tree
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = NoteCounter::LEN,
+        seeds = [b"counter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the merkle tree, created along with the tree
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = AdminConfig::LEN,
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AdminConfigAccounts<'info> {
+    // The current admin of the merkle tree
+    pub admin: Signer<'info>,
+
+    // The merkle tree account
+    /// CHECK: Used as a seed for the admin config, and only read to check whether the tree is empty
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin of the merkle tree
+    #[account(
+        mut,
+        has_one = admin @ CompressedNotesError::Unauthorized,
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+}
+
+#[derive(Accounts)]
+pub struct UpgradeAdminConfigAccounts<'info> {
+    // The current admin of the merkle tree, and payer for the additional rent
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    // The merkle tree account
+    /// CHECK: Only the key is used, as a seed for the admin config
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin of the merkle tree, which may be in a layout too old to load as an AdminConfig
+    /// CHECK: The discriminator and admin are checked against the account data before it is grown
+    #[account(
+        mut,
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+        owner = crate::ID,
+    )]
+    pub admin_config: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct NoteAccounts<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = is_tree_of(&merkle_tree, &tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the merkle tree
+    #[account(
+        mut,
+        seeds = [b"counter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the merkle tree, whose config decides whether owner quotas are enabled
+    #[account(
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+        constraint = !admin_config.paused @ CompressedNotesError::ProgramPaused,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The owner's note quota, only required when owner quotas are enabled
+    #[account(
+        mut,
+        seeds = [b"quota", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub owner_quota: Option<Account<'info, OwnerQuota>>,
+
+    // The new owner's note quota, only required by transfer_note when owner quotas are enabled.
+    // The new owner is an instruction argument, so transfer_note checks the address itself
+    #[account(mut)]
+    pub recipient_quota: Option<Account<'info, OwnerQuota>>,
+
+    // The owner's allowlist entry, only required when the owner allowlist is enabled
+    #[account(
+        seeds = [b"allowed", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub allowed_owner: Option<Account<'info, AllowedOwner>>,
+
+    // The owner's latest note cache, created on first use and only written when passed
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = LatestNote::LEN,
+        seeds = [b"latest", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub latest_note: Option<Account<'info, LatestNote>>,
+
+    // The treasury of the tree, only required when the tree charges an append fee
+    /// CHECK: Only receives lamports, and must be the treasury set in the admin config
+    #[account(
+        mut,
+        address = admin_config.treasury,
+    )]
+    pub treasury: Option<UncheckedAccount<'info>>,
+
+    // The owner's scratch account, only required when appending the note assembled in it
+    #[account(
+        mut,
+        seeds = [b"scratch", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub note_scratch: Option<Account<'info, NoteScratch>>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The program the logs are mirrored to, only passed to have them logged a second time
+    /// CHECK: Must be executable and the log mirror set in the admin config, and is only invoked with the log data
+    #[account(
+        executable,
+        constraint = is_log_mirror(&admin_config, &log_wrapper_mirror.key()) @ CompressedNotesError::LogMirrorNotAllowed,
+    )]
+    pub log_wrapper_mirror: Option<UncheckedAccount<'info>>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+
+    // The system program, for creating the latest note cache and paying append fees
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct NoteScratchAccounts<'info> {
+    // The owner assembling the note, and payer for the scratch account
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    // The merkle tree account the note will be appended to
+    /// CHECK: Only the key is used, as a seed for the scratch account
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The owner's scratch account, created on the first write
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = NoteScratch::LEN,
+        seeds = [b"scratch", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub note_scratch: Account<'info, NoteScratch>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CloseNoteScratchAccounts<'info> {
+    // The owner of the scratch account, who receives its rent
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    // The merkle tree account the note would have been appended to
+    /// CHECK: Only the key is used, as a seed for the scratch account
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The owner's scratch account
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"scratch", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub note_scratch: Account<'info, NoteScratch>,
+}
+
+#[derive(Accounts)]
+pub struct CreateOwnerQuotaAccounts<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The owner whose notes are counted
+    /// CHECK: Only the key is used, as a seed for the owner quota
+    pub owner: UncheckedAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: Only the key is used, as a seed for the owner quota
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The owner's note quota for the merkle tree
+    #[account(
+        init,
+        payer = payer,
+        space = OwnerQuota::LEN,
+        seeds = [b"quota", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub owner_quota: Account<'info, OwnerQuota>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AddToAllowlistAccounts<'info> {
+    // The current admin of the merkle tree, and payer for the transaction
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    // The owner being allowed
+    /// CHECK: Only the key is used, as a seed for the allowed owner
+    pub owner: UncheckedAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: Only the key is used, as a seed for the admin config and allowed owner
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin of the merkle tree
+    #[account(
+        has_one = admin @ CompressedNotesError::Unauthorized,
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The owner's allowlist entry for the merkle tree
+    #[account(
+        init,
+        payer = admin,
+        space = AllowedOwner::LEN,
+        seeds = [b"allowed", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub allowed_owner: Account<'info, AllowedOwner>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveFromAllowlistAccounts<'info> {
+    // The current admin of the merkle tree, who receives the rent of the allowlist entry
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    // The owner being removed
+    /// CHECK: Only the key is used, as a seed for the allowed owner
+    pub owner: UncheckedAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: Only the key is used, as a seed for the admin config and allowed owner
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin of the merkle tree
+    #[account(
+        has_one = admin @ CompressedNotesError::Unauthorized,
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The owner's allowlist entry for the merkle tree
+    #[account(
+        mut,
+        close = admin,
+        seeds = [b"allowed", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub allowed_owner: Account<'info, AllowedOwner>,
+}
+
+#[derive(Accounts)]
+pub struct CreateTreeRegistryAccounts<'info> {
+    // The admin whose trees are listed, and payer for the transaction
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    // The admin's tree registry
+    #[account(
+        init,
+        payer = admin,
+        space = TreeRegistry::space(0),
+        seeds = [b"registry", admin.key().as_ref()],
+        bump,
+    )]
+    pub tree_registry: Account<'info, TreeRegistry>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(additional: u32)]
+pub struct GrowRegistryAccounts<'info> {
+    // The admin whose trees are listed, and payer for the additional rent
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    // The admin's tree registry
+    #[account(
+        mut,
+        seeds = [b"registry", admin.key().as_ref()],
+        bump,
+        realloc = TreeRegistry::space(
+            TreeRegistry::capacity(tree_registry.to_account_info().data_len()) + additional as usize
+        ),
+        realloc::payer = admin,
+        realloc::zero = false,
+    )]
+    pub tree_registry: Account<'info, TreeRegistry>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeregisterTreeAccounts<'info> {
+    // The admin whose trees are listed
+    pub admin: Signer<'info>,
+
+    // The merkle tree account to remove
+    /// CHECK: Only the key is used, to find the tree in the registry
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin's tree registry
+    #[account(
+        mut,
+        seeds = [b"registry", admin.key().as_ref()],
+        bump,
+    )]
+    pub tree_registry: Account<'info, TreeRegistry>,
+}
+
+#[derive(Accounts)]
+pub struct CreateNoteFilterAccounts<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    // The merkle tree account
+    /// CHECK: Only the key is used, as a seed for the note filter
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The note filter for the merkle tree
+    #[account(
+        init,
+        payer = owner,
+        space = NoteFilter::LEN,
+        seeds = [b"filter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_filter: Box<Account<'info, NoteFilter>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UniqueNoteAccounts<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = is_tree_of(&merkle_tree, &tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the merkle tree
+    #[account(
+        mut,
+        seeds = [b"counter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the merkle tree, whose config decides whether owner quotas are enabled
+    #[account(
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+        constraint = !admin_config.paused @ CompressedNotesError::ProgramPaused,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The owner's note quota, only required when owner quotas are enabled
+    #[account(
+        mut,
+        seeds = [b"quota", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub owner_quota: Option<Account<'info, OwnerQuota>>,
+
+    // The owner's allowlist entry, only required when the owner allowlist is enabled
+    #[account(
+        seeds = [b"allowed", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub allowed_owner: Option<Account<'info, AllowedOwner>>,
+
+    // The owner's latest note cache, created on first use and only written when passed
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = LatestNote::LEN,
+        seeds = [b"latest", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub latest_note: Option<Account<'info, LatestNote>>,
+
+    // The note filter for the merkle tree
+    #[account(
+        mut,
+        seeds = [b"filter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_filter: Box<Account<'info, NoteFilter>>,
+
+    // The treasury of the tree, only required when the tree charges an append fee
+    /// CHECK: Only receives lamports, and must be the treasury set in the admin config
+    #[account(
+        mut,
+        address = admin_config.treasury,
+    )]
+    pub treasury: Option<UncheckedAccount<'info>>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The program the logs are mirrored to, only passed to have them logged a second time
+    /// CHECK: Must be executable and the log mirror set in the admin config, and is only invoked with the log data
+    #[account(
+        executable,
+        constraint = is_log_mirror(&admin_config, &log_wrapper_mirror.key()) @ CompressedNotesError::LogMirrorNotAllowed,
+    )]
+    pub log_wrapper_mirror: Option<UncheckedAccount<'info>>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+
+    // The system program, for paying append fees
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(note: String, note_owner: Pubkey)]
+pub struct AppendNoteForAccounts<'info> {
+    // The payer for the transaction, who is not the owner of the note
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = is_tree_of(&merkle_tree, &tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the merkle tree
+    #[account(
+        mut,
+        seeds = [b"counter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the merkle tree, whose config decides whether owner quotas are enabled
+    #[account(
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+        constraint = !admin_config.paused @ CompressedNotesError::ProgramPaused,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The owner's note quota, only required when owner quotas are enabled
+    #[account(
+        mut,
+        seeds = [b"quota", merkle_tree.key().as_ref(), note_owner.as_ref()],
+        bump,
+    )]
+    pub owner_quota: Option<Account<'info, OwnerQuota>>,
+
+    // The note owner's allowlist entry, only required when the owner allowlist is enabled
+    #[account(
+        seeds = [b"allowed", merkle_tree.key().as_ref(), note_owner.as_ref()],
+        bump,
+    )]
+    pub allowed_owner: Option<Account<'info, AllowedOwner>>,
+
+    // The note owner's latest note cache, created on first use by the payer and only written when passed
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = LatestNote::LEN,
+        seeds = [b"latest", merkle_tree.key().as_ref(), note_owner.as_ref()],
+        bump,
+    )]
+    pub latest_note: Option<Account<'info, LatestNote>>,
+
+    // The treasury of the tree, only required when the tree charges an append fee
+    /// CHECK: Only receives lamports, and must be the treasury set in the admin config
+    #[account(
+        mut,
+        address = admin_config.treasury,
+    )]
+    pub treasury: Option<UncheckedAccount<'info>>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The program the logs are mirrored to, only passed to have them logged a second time
+    /// CHECK: Must be executable and the log mirror set in the admin config, and is only invoked with the log data
+    #[account(
+        executable,
+        constraint = is_log_mirror(&admin_config, &log_wrapper_mirror.key()) @ CompressedNotesError::LogMirrorNotAllowed,
+    )]
+    pub log_wrapper_mirror: Option<UncheckedAccount<'info>>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+
+    // The system program, for paying append fees
+    pub system_program: Program<'info, System>,
+}
+
+// Also used by compute_leaf, which only needs the keys and hash algorithm of the leaf, and by
+// simulate_update, verify_notes and verify_and_get_owner, which only read the tree
+#[derive(Accounts)]
+pub struct NoteExistsAccounts<'info> {
+    // The owner of the note
+    /// CHECK: Only the key is used, hashed into the note leaf
+    pub owner: UncheckedAccount<'info>,
+
+    // The merkle tree account, read directly since there is no CPI to check it
+    /// CHECK: Owned by the spl account compression program, and the header is validated when loaded
+    #[account(owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin of the merkle tree, whose config selects the hash of the note leaves
+    #[account(
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+}
+
+#[derive(Accounts)]
+pub struct ReemitNoteLogAccounts<'info> {
+    // The owner of the note, who doesn't need to sign
+    /// CHECK: Only the key is used, hashed into the note leaf
+    pub owner: UncheckedAccount<'info>,
+
+    // The merkle tree account, read directly since the tree is not modified
+    /// CHECK: Owned by the spl account compression program, and the header is validated when loaded
+    #[account(owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin of the merkle tree, whose config selects the hash of the note leaves
+    #[account(
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The program the logs are mirrored to, only passed to have them logged a second time
+    /// CHECK: Must be executable and the log mirror set in the admin config, and is only invoked with the log data
+    #[account(
+        executable,
+        constraint = is_log_mirror(&admin_config, &log_wrapper_mirror.key()) @ CompressedNotesError::LogMirrorNotAllowed,
+    )]
+    pub log_wrapper_mirror: Option<UncheckedAccount<'info>>,
+}
+
+#[derive(Accounts)]
+pub struct TreeConfigAccounts<'info> {
+    // The merkle tree account, read directly since there is no CPI to check it
+    /// CHECK: Owned by the spl account compression program, and the header is validated when loaded
+    #[account(owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner)]
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32, root: [u8; 32], old_note: String, new_note: String, owner_sig: [u8; 64], owner: Pubkey)]
+pub struct DelegatedUpdateAccounts<'info> {
+    // The delegate submitting the update, who is not the owner of the note, and payer for the nonce
+    #[account(mut)]
+    pub delegate: Signer<'info>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = is_tree_of(&merkle_tree, &tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin of the merkle tree, whose config selects the hash of the note leaves
+    #[account(
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+        constraint = !admin_config.paused @ CompressedNotesError::ProgramPaused,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The owner's nonce for delegated updates in the tree, created by the first one
+    #[account(
+        init_if_needed,
+        payer = delegate,
+        space = DelegateNonce::LEN,
+        seeds = [b"delegate-nonce", merkle_tree.key().as_ref(), owner.as_ref()],
+        bump,
+    )]
+    pub delegate_nonce: Account<'info, DelegateNonce>,
+
+    // The owner's latest note cache, created on first use by the delegate and only written when passed
+    #[account(
+        init_if_needed,
+        payer = delegate,
+        space = LatestNote::LEN,
+        seeds = [b"latest", merkle_tree.key().as_ref(), owner.as_ref()],
+        bump,
+    )]
+    pub latest_note: Option<Account<'info, LatestNote>>,
+
+    // The instructions sysvar, to find the owner's Ed25519 signature in the transaction
+    /// CHECK: The address is checked to be the instructions sysvar
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The program the logs are mirrored to, only passed to have them logged a second time
+    /// CHECK: Must be executable and the log mirror set in the admin config, and is only invoked with the log data
+    #[account(
+        executable,
+        constraint = is_log_mirror(&admin_config, &log_wrapper_mirror.key()) @ CompressedNotesError::LogMirrorNotAllowed,
+    )]
+    pub log_wrapper_mirror: Option<UncheckedAccount<'info>>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct DeleteExpiredNoteAccounts<'info> {
+    // Whoever is cleaning up the expired note, who need not be its owner
+    pub caller: Signer<'info>,
+
+    // The owner of the note
+    /// CHECK: Only the key is used, hashed into the note leaf and as a seed for the owner quota
+    pub owner: UncheckedAccount<'info>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = is_tree_of(&merkle_tree, &tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the merkle tree
+    #[account(
+        mut,
+        seeds = [b"counter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the merkle tree, whose config selects the hash of the note leaves
+    #[account(
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+        constraint = !admin_config.paused @ CompressedNotesError::ProgramPaused,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The owner's note quota, refunded when passed
+    #[account(
+        mut,
+        seeds = [b"quota", merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub owner_quota: Option<Account<'info, OwnerQuota>>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The program the logs are mirrored to, only passed to have them logged a second time
+    /// CHECK: Must be executable and the log mirror set in the admin config, and is only invoked with the log data
+    #[account(
+        executable,
+        constraint = is_log_mirror(&admin_config, &log_wrapper_mirror.key()) @ CompressedNotesError::LogMirrorNotAllowed,
+    )]
+    pub log_wrapper_mirror: Option<UncheckedAccount<'info>>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateNoteAccounts<'info> {
+    // The owner of the note, and the payer for the transaction
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    // The pda authority for the source tree, only used for signing
+    #[account(
+        seeds = [source_merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub source_tree_authority: SystemAccount<'info>,
+
+    // The merkle tree the note moves out of
+    /// CHECK: Owned by the spl account compression program, and the header is validated when loaded
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = is_tree_of(&source_merkle_tree, &source_tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
+    pub source_merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the source tree
+    #[account(
+        mut,
+        seeds = [b"counter", source_merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub source_note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the source tree, whose config selects the hash of its note leaves
+    #[account(
+        seeds = [b"admin", source_merkle_tree.key().as_ref()],
+        bump,
+        constraint = !source_admin_config.paused @ CompressedNotesError::ProgramPaused,
+    )]
+    pub source_admin_config: Account<'info, AdminConfig>,
+
+    // The owner's note quota in the source tree, refunded when passed
+    #[account(
+        mut,
+        seeds = [b"quota", source_merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub source_owner_quota: Option<Account<'info, OwnerQuota>>,
+
+    // The pda authority for the destination tree, only used for signing
+    #[account(
+        seeds = [dest_merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub dest_tree_authority: SystemAccount<'info>,
+
+    // The merkle tree the note moves to
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = dest_merkle_tree.key() != source_merkle_tree.key() @ CompressedNotesError::MigrationToSameTree,
+        constraint = is_tree_of(&dest_merkle_tree, &dest_tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
+    pub dest_merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the destination tree
+    #[account(
+        mut,
+        seeds = [b"counter", dest_merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub dest_note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the destination tree, whose config applies to the append
+    #[account(
+        seeds = [b"admin", dest_merkle_tree.key().as_ref()],
+        bump,
+        constraint = !dest_admin_config.paused @ CompressedNotesError::ProgramPaused,
+    )]
+    pub dest_admin_config: Account<'info, AdminConfig>,
+
+    // The owner's note quota in the destination tree, only required when owner quotas are enabled
+    #[account(
+        mut,
+        seeds = [b"quota", dest_merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub dest_owner_quota: Option<Account<'info, OwnerQuota>>,
+
+    // The owner's allowlist entry in the destination tree, only required when its allowlist is enabled
+    #[account(
+        seeds = [b"allowed", dest_merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub dest_allowed_owner: Option<Account<'info, AllowedOwner>>,
+
+    // The owner's latest note cache in the destination tree, created on first use and only written when passed
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = LatestNote::LEN,
+        seeds = [b"latest", dest_merkle_tree.key().as_ref(), owner.key().as_ref()],
+        bump,
+    )]
+    pub dest_latest_note: Option<Account<'info, LatestNote>>,
+
+    // The treasury of the destination tree, only required when it charges an append fee
+    /// CHECK: Only receives lamports, and must be the treasury set in the admin config
+    #[account(
+        mut,
+        address = dest_admin_config.treasury,
+    )]
+    pub dest_treasury: Option<UncheckedAccount<'info>>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The program the logs are mirrored to, only passed to have them logged a second time
+    /// CHECK: Must be executable and the log mirror set in both admin configs, and is only invoked with the log data
+    #[account(
+        executable,
+        constraint = is_log_mirror(&source_admin_config, &log_wrapper_mirror.key()) @ CompressedNotesError::LogMirrorNotAllowed,
+        constraint = is_log_mirror(&dest_admin_config, &log_wrapper_mirror.key()) @ CompressedNotesError::LogMirrorNotAllowed,
+    )]
+    pub log_wrapper_mirror: Option<UncheckedAccount<'info>>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+
+    // The system program, for paying append fees
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyNoteAccounts<'info> {
+    // The owner of the note
+    /// CHECK: Only the key is used, hashed into the note leaf
+    pub owner: UncheckedAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The admin of the merkle tree, whose config selects the hash of the note leaves
+    #[account(
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+}
+
+#[derive(Accounts)]
+pub struct CloseNoteTreeAccounts<'info> {
+    // The admin of the merkle tree, who receives the rent
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = is_tree_of(&merkle_tree, &tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The counter of notes in the merkle tree, closed along with the tree
+    #[account(
+        mut,
+        close = admin,
+        seeds = [b"counter", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub note_counter: Account<'info, NoteCounter>,
+
+    // The admin of the merkle tree, closed along with the tree
+    #[account(
+        mut,
+        close = admin,
+        has_one = admin @ CompressedNotesError::Unauthorized,
+        seeds = [b"admin", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub admin_config: Account<'info, AdminConfig>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+}
+
+#[derive(Accounts)]
+pub struct CreateMessagesTreeAccounts<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub sender: Signer<'info>,
+
+    // The pda authority for the messages tree, only used for signing
+    #[account(
+        seeds = [b"messages", merkle_tree.key().as_ref()],
+        bump,
+    )]
     pub tree_authority: SystemAccount<'info>,
 
     // The merkle tree account
     /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+    )]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, CompressionProgram>,
+}
+
+#[derive(Accounts)]
+pub struct MessageAccounts<'info> {
+    // The sender of the message and payer for the transaction
     #[account(mut)]
+    pub sender: Signer<'info>,
+
+    // The recipient of the message
+    /// CHECK: Only the key is used, hashed into the message leaf
+    pub recipient: UncheckedAccount<'info>,
+
+    // The pda authority for the messages tree, only used for signing
+    #[account(
+        seeds = [b"messages", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(
+        mut,
+        owner = COMPRESSION_PROGRAM_ID @ CompressedNotesError::WrongAccountOwner,
+        constraint = is_tree_of(&merkle_tree, &tree_authority.key()) @ CompressedNotesError::NotOurTree,
+    )]
     pub merkle_tree: UncheckedAccount<'info>,
 
     // The noop program to log data
     pub log_wrapper: Program<'info, Noop>,
 
+
     // The spl account compression program
-    pub compression_program: Program<'info, SplAccountCompression>,
-}
\ No newline at end of file
+    pub compression_program: Program<'info, CompressionProgram>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_update_cost_grows_with_depth() {
+        let costs: Vec<u64> = [3, 14, 20, 24].into_iter().map(estimate_update_cost).collect();
+        assert_eq!(costs[0], UPDATE_BASE_COST + 3 * UPDATE_COST_PER_LEVEL);
+        assert!(costs.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn tree_authority_pda_matches_the_signing_seeds() {
+        let tree = Pubkey::new_unique();
+        let (authority, bump) = tree_authority_pda(&tree);
+        assert_eq!((authority, bump), Pubkey::find_program_address(&[tree.as_ref()], &ID));
+
+        let seeds = tree_authority_seeds(&tree, &bump);
+        assert_eq!(Pubkey::create_program_address(&seeds, &ID).unwrap(), authority);
+    }
+
+    #[test]
+    fn messages_authority_pda_differs_from_the_tree_authority() {
+        let tree = Pubkey::new_unique();
+        let (authority, bump) = messages_authority_pda(&tree);
+        assert_ne!(authority, tree_authority_pda(&tree).0);
+
+        let seeds = messages_authority_seeds(&tree, &bump);
+        assert_eq!(Pubkey::create_program_address(&seeds, &ID).unwrap(), authority);
+    }
+
+    #[test]
+    fn count_appended_notes_fails_past_u64_max() {
+        let overflow: Error = CompressedNotesError::ArithmeticOverflow.into();
+
+        let mut note_counter = NoteCounter { count: u64::MAX, next_index: 0 };
+        assert_eq!(count_appended_notes(&mut note_counter, 0, 1).unwrap_err(), overflow);
+
+        let mut note_counter = NoteCounter { count: 0, next_index: 0 };
+        assert_eq!(count_appended_notes(&mut note_counter, u64::MAX, 1).unwrap_err(), overflow);
+        // A failed count leaves the counter as it was
+        assert_eq!(note_counter.count, 0);
+    }
+
+    #[test]
+    fn titled_note_hash_marks_the_end_of_the_title() {
+        let (owner, tree) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let hash = |title, note| hash_titled_note(title, note, &owner, &tree, HASH_ALGO_KECCAK);
+
+        assert_eq!(hash("", "note"), hash_note("note", &owner, &tree, HASH_ALGO_KECCAK));
+        assert_ne!(hash("ab", "c"), hash("a", "bc"));
+        assert_ne!(hash("a", "bc"), hash("", "abc"));
+    }
+
+    #[test]
+    fn max_note_log_size_fits_a_max_populated_note_log() {
+        let note_log = NoteLog {
+            version: CURRENT_LOG_VERSION,
+            tag: NOTE_LOG_TAG,
+            leaf_node: [1; 32],
+            owner: Pubkey::new_unique(),
+            tree: Pubkey::new_unique(),
+            note: "n".repeat(MAX_NOTE_LEN),
+            created_at: i64::MAX,
+            index: u64::MAX,
+            title: "t".repeat(MAX_TITLE_LEN),
+            tags: vec!["g".repeat(MAX_TAG_LEN); MAX_TAGS],
+            status: NOTE_STATUS_ARCHIVED,
+            prev_leaf: [1; 32],
+            parent_index: Some(u64::MAX),
+            content_type: "c".repeat(MAX_CONTENT_TYPE_LEN),
+            expires_at: Some(i64::MAX),
+            appended_by: Pubkey::new_unique(),
+            nonce: Some(u64::MAX),
+        };
+        assert_eq!(note_log.try_to_vec().unwrap().len(), max_note_log_size());
+    }
+
+    // The serialized size of an account, including the discriminator
+    fn account_len(account: &impl AccountSerialize) -> usize {
+        let mut data = Vec::new();
+        account.try_serialize(&mut data).unwrap();
+        data.len()
+    }
+
+    #[test]
+    fn max_populated_accounts_fit_their_len() {
+        let counter = NoteCounter { count: u64::MAX, next_index: u64::MAX };
+        assert!(account_len(&counter) <= NoteCounter::LEN);
+
+        let filter = NoteFilter { bits: [u8::MAX; FILTER_BITS / 8] };
+        assert!(account_len(&filter) <= NoteFilter::LEN);
+
+        let admin_config = AdminConfig {
+            admin: Pubkey::new_unique(),
+            quota_enabled: true,
+            hash_algo: HASH_ALGO_SHA256,
+            allowlist_enabled: true,
+            paused: true,
+            fee_lamports: u64::MAX,
+            treasury: Pubkey::new_unique(),
+            renounced: true,
+            log_mirror: Pubkey::new_unique(),
+        };
+        assert!(account_len(&admin_config) <= AdminConfig::LEN);
+
+        assert!(account_len(&AllowedOwner {}) <= AllowedOwner::LEN);
+        assert!(account_len(&OwnerQuota { count: u64::MAX }) <= OwnerQuota::LEN);
+        assert!(account_len(&DelegateNonce { nonce: u64::MAX }) <= DelegateNonce::LEN);
+
+        let latest_note = LatestNote { leaf: [1; 32], index: u64::MAX, updated_at: i64::MAX };
+        assert!(account_len(&latest_note) <= LatestNote::LEN);
+
+        let scratch = NoteScratch { data: vec![1; MAX_SCRATCH_NOTE_LEN] };
+        assert!(account_len(&scratch) <= NoteScratch::LEN);
+
+        let registry = TreeRegistry { trees: vec![Pubkey::new_unique(); MAX_REGISTRY_TREES] };
+        assert!(account_len(&registry) <= TreeRegistry::LEN);
+    }
+}
@@ -0,0 +1,283 @@
+// The solana-program-test harness shared by the end-to-end tests, which run the note program and
+// spl-account-compression natively. Not every test file uses every helper.
+#![allow(dead_code)]
+
+use anchor_lang::{InstructionData, ToAccountMetas};
+use compressed_notes::{merkle_tree_account_size, tree_authority_pda, COMPRESSION_PROGRAM_ID, NOTE_LEAF_TAG};
+use solana_program_test::{processor, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    keccak,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    pubkey::Pubkey,
+    rent::Rent,
+    signature::{Keypair, Signer},
+    system_instruction, system_program,
+    transaction::Transaction,
+};
+use spl_account_compression::{
+    state::CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1, zero_copy::ZeroCopy, ConcurrentMerkleTree, Node,
+};
+use std::sync::Once;
+
+pub const MAX_DEPTH: usize = 3;
+pub const MAX_BUFFER_SIZE: usize = 8;
+
+// Anchor's entry ties the account infos to a single lifetime, which the processor type can't name
+fn process_compressed_notes<'a, 'b, 'c>(
+    program_id: &'a Pubkey,
+    accounts: &'b [AccountInfo<'c>],
+    data: &[u8],
+) -> ProgramResult {
+    let accounts: &'c [AccountInfo<'c>] = unsafe { std::mem::transmute(accounts) };
+    compressed_notes::entry(program_id, accounts, data)
+}
+
+fn process_account_compression<'a, 'b, 'c>(
+    program_id: &'a Pubkey,
+    accounts: &'b [AccountInfo<'c>],
+    data: &[u8],
+) -> ProgramResult {
+    let accounts: &'c [AccountInfo<'c>] = unsafe { std::mem::transmute(accounts) };
+    #[cfg(feature = "localnet")]
+    let (program_id, accounts) = as_canonical(program_id, accounts);
+    spl_account_compression::entry(program_id, accounts, data)
+}
+
+// The crates.io build of spl-account-compression only accepts its canonical id, so localnet builds
+// run it as a build declared at the localnet id would run: with its own id and the owner of its
+// accounts swapped back to the canonical id
+#[cfg(feature = "localnet")]
+fn as_canonical<'c>(program_id: &Pubkey, accounts: &[AccountInfo<'c>]) -> (&'static Pubkey, &'c [AccountInfo<'c>]) {
+    static CANONICAL_ID: Pubkey = spl_account_compression::ID;
+    assert_eq!(*program_id, COMPRESSION_PROGRAM_ID);
+    let accounts = accounts
+        .iter()
+        .map(|account| {
+            let mut account = account.clone();
+            if *account.owner == COMPRESSION_PROGRAM_ID {
+                account.owner = &CANONICAL_ID;
+            }
+            account
+        })
+        .collect::<Vec<_>>();
+    (&CANONICAL_ID, accounts.leak())
+}
+
+// Stand-in stubs, only installed while swapping the stubs of solana-program-test for FullBudgetStubs
+struct DefaultStubs;
+
+impl SyscallStubs for DefaultStubs {}
+
+// The syscall stubs of solana-program-test, except that the full 1.4M compute budget remains. Natively run
+// programs aren't metered, so the stubs report no compute left, which check_update_budget rejects.
+struct FullBudgetStubs(Box<dyn SyscallStubs>);
+
+impl SyscallStubs for FullBudgetStubs {
+    fn sol_log(&self, message: &str) {
+        self.0.sol_log(message)
+    }
+    fn sol_log_compute_units(&self) {
+        self.0.sol_log_compute_units()
+    }
+    fn sol_remaining_compute_units(&self) -> u64 {
+        1_400_000
+    }
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        self.0.sol_invoke_signed(instruction, account_infos, signers_seeds)
+    }
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_clock_sysvar(var_addr)
+    }
+    fn sol_get_epoch_schedule_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_schedule_sysvar(var_addr)
+    }
+    fn sol_get_fees_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_fees_sysvar(var_addr)
+    }
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_rent_sysvar(var_addr)
+    }
+    fn sol_get_epoch_rewards_sysvar(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_epoch_rewards_sysvar(var_addr)
+    }
+    fn sol_get_last_restart_slot(&self, var_addr: *mut u8) -> u64 {
+        self.0.sol_get_last_restart_slot(var_addr)
+    }
+    unsafe fn sol_memcpy(&self, dst: *mut u8, src: *const u8, n: usize) {
+        self.0.sol_memcpy(dst, src, n)
+    }
+    unsafe fn sol_memmove(&self, dst: *mut u8, src: *const u8, n: usize) {
+        self.0.sol_memmove(dst, src, n)
+    }
+    unsafe fn sol_memcmp(&self, s1: *const u8, s2: *const u8, n: usize, result: *mut i32) {
+        self.0.sol_memcmp(s1, s2, n, result)
+    }
+    unsafe fn sol_memset(&self, s: *mut u8, c: u8, n: usize) {
+        self.0.sol_memset(s, c, n)
+    }
+    fn sol_get_return_data(&self) -> Option<(Pubkey, Vec<u8>)> {
+        self.0.sol_get_return_data()
+    }
+    fn sol_set_return_data(&self, data: &[u8]) {
+        self.0.sol_set_return_data(data)
+    }
+    fn sol_log_data(&self, fields: &[&[u8]]) {
+        self.0.sol_log_data(fields)
+    }
+    fn sol_get_processed_sibling_instruction(&self, index: usize) -> Option<Instruction> {
+        self.0.sol_get_processed_sibling_instruction(index)
+    }
+    fn sol_get_stack_height(&self) -> u64 {
+        self.0.sol_get_stack_height()
+    }
+}
+
+// Starts a test validator with the note, compression and noop programs loaded
+pub async fn start() -> ProgramTestContext {
+    let mut program_test =
+        ProgramTest::new("compressed_notes", compressed_notes::ID, processor!(process_compressed_notes));
+    program_test.add_program(
+        "spl_account_compression",
+        COMPRESSION_PROGRAM_ID,
+        processor!(process_account_compression),
+    );
+    program_test.add_program("spl_noop", spl_noop::ID, processor!(spl_noop::noop));
+    let context = program_test.start_with_context().await;
+
+    // The stubs of solana-program-test are installed by the first start
+    static FULL_BUDGET: Once = Once::new();
+    FULL_BUDGET.call_once(|| {
+        let stubs = set_syscall_stubs(Box::new(DefaultStubs));
+        set_syscall_stubs(Box::new(FullBudgetStubs(stubs)));
+    });
+    context
+}
+
+// A local copy of the leaves of a tree, to compute the roots and proofs the program is checked against
+pub struct LocalTree {
+    pub leaves: Vec<Node>,
+}
+
+impl LocalTree {
+    pub fn new() -> Self {
+        Self { leaves: vec![Node::default(); 1 << MAX_DEPTH] }
+    }
+
+    // The nodes of each level of the tree, from the leaves up to the root
+    fn levels(&self) -> Vec<Vec<Node>> {
+        let mut levels = vec![self.leaves.clone()];
+        while levels.last().unwrap().len() > 1 {
+            let parents = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| keccak::hashv(&[&pair[0], &pair[1]]).to_bytes())
+                .collect();
+            levels.push(parents);
+        }
+        levels
+    }
+
+    pub fn root(&self) -> Node {
+        self.levels().last().unwrap()[0]
+    }
+
+    // The proof of the leaf at the index, as the remaining accounts of an instruction proving it
+    pub fn proof_accounts(&self, index: usize) -> Vec<AccountMeta> {
+        self.levels()[..MAX_DEPTH]
+            .iter()
+            .enumerate()
+            .map(|(level, nodes)| AccountMeta::new_readonly(Pubkey::from(nodes[(index >> level) ^ 1]), false))
+            .collect()
+    }
+}
+
+// The leaf of a note, mirroring hash_note for a keccak tree and a note already in NFC
+pub fn note_leaf(note: &str, owner: &Pubkey, tree: &Pubkey) -> Node {
+    keccak::hashv(&[NOTE_LEAF_TAG, note.as_bytes(), owner.as_ref(), tree.as_ref()]).to_bytes()
+}
+
+// Reads the current root of the tree from its account
+pub async fn onchain_root(context: &mut ProgramTestContext, tree: &Pubkey) -> Node {
+    let account = context.banks_client.get_account(*tree).await.unwrap().unwrap();
+    let tree_bytes = &account.data[CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1..];
+    ConcurrentMerkleTree::<MAX_DEPTH, MAX_BUFFER_SIZE>::load_bytes(tree_bytes).unwrap().get_root()
+}
+
+pub async fn send(context: &mut ProgramTestContext, instructions: &[Instruction], signers: &[&Keypair]) {
+    let blockhash = context.banks_client.get_latest_blockhash().await.unwrap();
+    let mut all_signers = vec![&context.payer];
+    all_signers.extend_from_slice(signers);
+    let transaction =
+        Transaction::new_signed_with_payer(instructions, Some(&context.payer.pubkey()), &all_signers, blockhash);
+    context.banks_client.process_transaction(transaction).await.unwrap();
+}
+
+pub fn program_address(seed: &[u8], tree: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[seed, tree.as_ref()], &compressed_notes::ID).0
+}
+
+// The accounts of the note instructions for a note of the owner, with none of the optional accounts
+pub fn note_accounts(owner: &Pubkey, tree: &Pubkey) -> Vec<AccountMeta> {
+    compressed_notes::accounts::NoteAccounts {
+        owner: *owner,
+        tree_authority: tree_authority_pda(tree).0,
+        merkle_tree: *tree,
+        note_counter: program_address(b"counter", tree),
+        admin_config: program_address(b"admin", tree),
+        owner_quota: None,
+        recipient_quota: None,
+        allowed_owner: None,
+        latest_note: None,
+        treasury: None,
+        note_scratch: None,
+        log_wrapper: spl_noop::ID,
+        log_wrapper_mirror: None,
+        compression_program: COMPRESSION_PROGRAM_ID,
+        system_program: system_program::ID,
+    }
+    .to_account_metas(None)
+}
+
+// Allocates the tree account and creates a note tree in it
+pub async fn create_note_tree(context: &mut ProgramTestContext, tree: &Keypair) {
+    let payer = context.payer.pubkey();
+    let size = merkle_tree_account_size(MAX_DEPTH as u32, MAX_BUFFER_SIZE as u32, 0);
+    let allocate = system_instruction::create_account(
+        &payer,
+        &tree.pubkey(),
+        Rent::default().minimum_balance(size),
+        size as u64,
+        &COMPRESSION_PROGRAM_ID,
+    );
+    let create = Instruction {
+        program_id: compressed_notes::ID,
+        accounts: compressed_notes::accounts::CreateNoteTreeAccounts {
+            owner: payer,
+            tree_authority: tree_authority_pda(&tree.pubkey()).0,
+            merkle_tree: tree.pubkey(),
+            note_counter: program_address(b"counter", &tree.pubkey()),
+            admin_config: program_address(b"admin", &tree.pubkey()),
+            tree_registry: None,
+            log_wrapper: spl_noop::ID,
+            compression_program: COMPRESSION_PROGRAM_ID,
+            system_program: system_program::ID,
+        }
+        .to_account_metas(None),
+        data: compressed_notes::instruction::CreateNoteTree {
+            max_depth: MAX_DEPTH as u32,
+            max_buffer_size: MAX_BUFFER_SIZE as u32,
+            canopy_depth: 0,
+        }
+        .data(),
+    };
+    send(context, &[allocate, create], &[tree]).await;
+}
@@ -0,0 +1,47 @@
+// End-to-end tests of builds with the localnet feature, which expect the compression program at
+// LOCALNET_COMPRESSION_PROGRAM_ID. Run them with `cargo test --features localnet`.
+#![cfg(feature = "localnet")]
+
+mod common;
+
+use anchor_lang::InstructionData;
+use common::{create_note_tree, note_accounts, note_leaf, onchain_root, send, start, LocalTree};
+use compressed_notes::{COMPRESSION_PROGRAM_ID, LOCALNET_COMPRESSION_PROGRAM_ID};
+use solana_sdk::{
+    instruction::Instruction,
+    signature::{Keypair, Signer},
+};
+
+#[tokio::test]
+async fn localnet_build_accepts_the_local_compression_program() {
+    assert_eq!(COMPRESSION_PROGRAM_ID, LOCALNET_COMPRESSION_PROGRAM_ID);
+    assert_ne!(COMPRESSION_PROGRAM_ID, spl_account_compression::ID);
+
+    let mut context = start().await;
+    let owner = context.payer.pubkey();
+    let tree = Keypair::new();
+    create_note_tree(&mut context, &tree).await;
+    let tree = tree.pubkey();
+
+    // The tree lives in an account of the local compression program
+    let account = context.banks_client.get_account(tree).await.unwrap().unwrap();
+    assert_eq!(account.owner, LOCALNET_COMPRESSION_PROGRAM_ID);
+
+    let append = Instruction {
+        program_id: compressed_notes::ID,
+        accounts: note_accounts(&owner, &tree),
+        data: compressed_notes::instruction::AppendNote {
+            note: "local note".to_string(),
+            tags: vec![],
+            parent: None,
+            content_type: None,
+            nonce: None,
+        }
+        .data(),
+    };
+    send(&mut context, &[append], &[]).await;
+
+    let mut local = LocalTree::new();
+    local.leaves[0] = note_leaf("local note", &owner, &tree);
+    assert_eq!(onchain_root(&mut context, &tree).await, local.root());
+}
@@ -0,0 +1,65 @@
+// End-to-end tests of the note instructions against spl-account-compression, with both programs
+// run natively by solana-program-test. They exercise the real CPIs, so account ordering and seed
+// bugs that the unit tests can't see fail here.
+
+mod common;
+
+use anchor_lang::InstructionData;
+use common::{create_note_tree, note_accounts, note_leaf, onchain_root, send, start, LocalTree};
+use solana_sdk::{
+    instruction::Instruction,
+    signature::{Keypair, Signer},
+};
+
+#[tokio::test]
+async fn create_append_and_update_change_the_root() {
+    let mut context = start().await;
+    let owner = context.payer.pubkey();
+    let tree = Keypair::new();
+    create_note_tree(&mut context, &tree).await;
+    let tree = tree.pubkey();
+
+    let mut local = LocalTree::new();
+    assert_eq!(onchain_root(&mut context, &tree).await, local.root());
+
+    for (index, note) in ["first note", "second note"].into_iter().enumerate() {
+        let append = Instruction {
+            program_id: compressed_notes::ID,
+            accounts: note_accounts(&owner, &tree),
+            data: compressed_notes::instruction::AppendNote {
+                note: note.to_string(),
+                tags: vec![],
+                parent: None,
+                content_type: None,
+                nonce: None,
+            }
+            .data(),
+        };
+        send(&mut context, &[append], &[]).await;
+
+        local.leaves[index] = note_leaf(note, &owner, &tree);
+        assert_eq!(onchain_root(&mut context, &tree).await, local.root());
+    }
+
+    // Update the second note, whose proof includes the first note's leaf
+    let root = local.root();
+    let mut accounts = note_accounts(&owner, &tree);
+    accounts.extend(local.proof_accounts(1));
+    let update = Instruction {
+        program_id: compressed_notes::ID,
+        accounts,
+        data: compressed_notes::instruction::UpdateNote {
+            index: 1,
+            root,
+            old_note: "second note".to_string(),
+            new_note: "updated note".to_string(),
+        }
+        .data(),
+    };
+    send(&mut context, &[update], &[]).await;
+
+    local.leaves[1] = note_leaf("updated note", &owner, &tree);
+    let updated_root = onchain_root(&mut context, &tree).await;
+    assert_ne!(updated_root, root);
+    assert_eq!(updated_root, local.root());
+}
@@ -2,21 +2,53 @@ import * as anchor from "@coral-xyz/anchor"
 import { Program } from "@coral-xyz/anchor"
 import { CompressedNotes } from "../target/types/compressed_notes"
 import {
+  ComputeBudgetProgram,
+  Ed25519Program,
   Keypair,
   Transaction,
   PublicKey,
+  SYSVAR_INSTRUCTIONS_PUBKEY,
+  SystemProgram,
   sendAndConfirmTransaction,
   Connection,
 } from "@solana/web3.js"
 import {
   ValidDepthSizePair,
   createAllocTreeIx,
+  createInitEmptyMerkleTreeIx,
   SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
   SPL_NOOP_PROGRAM_ID,
   ConcurrentMerkleTreeAccount,
+  MerkleTree,
+  getConcurrentMerkleTreeAccountSize,
 } from "@solana/spl-account-compression"
-import { getHash, getNoteLog } from "./utils"
+import {
+  COMPRESSED_NOTE_LOG_TAG,
+  CURRENT_LOG_VERSION,
+  MESSAGE_LOG_TAG,
+  NOTE_LOG_TAG,
+  RAW_NOTE_LOG_TAG,
+  getApplicationData,
+  getArchivedHash,
+  getCompressedNoteLog,
+  getDeletedLeaf,
+  getDelegatedUpdateMessage,
+  getEmptyProof,
+  getExpiringHash,
+  getHash,
+  getMessageHash,
+  getMessageLog,
+  getMessagesAuthority,
+  getNoncedHash,
+  getNoteLog,
+  getRawLeaf,
+  getRawNoteLog,
+  getTitledHash,
+  getTransferLog,
+  getTreeAuthority,
+} from "./utils"
 import { assert } from "chai"
+import { readFileSync } from "fs"
 
 describe("compressed-notes", () => {
   const provider = anchor.AnchorProvider.env()
@@ -33,16 +65,121 @@ describe("compressed-notes", () => {
   const merkleTree = Keypair.generate()
 
   const firstNote = "hello world"
-  const secondNote = "0".repeat(917)
+  const secondNote = "0".repeat(512)
   const updatedNote = "updated note"
 
   // Derive the PDA to use as the tree authority for the merkle tree account
   // This is a PDA derived from the Note program, which allows the program to sign for appends instructions to the tree
-  const [treeAuthority] = PublicKey.findProgramAddressSync(
-    [merkleTree.publicKey.toBuffer()],
-    program.programId
+  const [treeAuthority] = getTreeAuthority(
+    program.programId,
+    merkleTree.publicKey
   )
 
+  // Accounts used by the note instructions for the given tree
+  function noteAccounts(tree: PublicKey) {
+    return {
+      merkleTree: tree,
+      treeAuthority: getTreeAuthority(program.programId, tree)[0],
+      noteCounter: PublicKey.findProgramAddressSync(
+        [Buffer.from("counter"), tree.toBuffer()],
+        program.programId
+      )[0],
+      adminConfig: PublicKey.findProgramAddressSync(
+        [Buffer.from("admin"), tree.toBuffer()],
+        program.programId
+      )[0],
+      ownerQuota: null,
+      recipientQuota: null,
+      allowedOwner: null,
+      treeRegistry: null,
+      latestNote: null,
+      treasury: null,
+      noteScratch: null,
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      logWrapperMirror: null,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    }
+  }
+
+  // Accounts used by update_note_delegated for a note of the wallet in the given tree
+  function delegatedUpdateAccounts(tree: PublicKey, delegate: PublicKey) {
+    return {
+      delegate,
+      merkleTree: tree,
+      treeAuthority: noteAccounts(tree).treeAuthority,
+      adminConfig: noteAccounts(tree).adminConfig,
+      delegateNonce: PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("delegate-nonce"),
+          tree.toBuffer(),
+          provider.publicKey.toBuffer(),
+        ],
+        program.programId
+      )[0],
+      latestNote: null,
+      instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      logWrapperMirror: null,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    }
+  }
+
+  // Proof nodes of a leaf against the root of the first change log, before any leaf was added
+  function emptyProof(maxDepth: number) {
+    return getEmptyProof(maxDepth).map((node) => ({
+      pubkey: new PublicKey(node),
+      isSigner: false,
+      isWritable: false,
+    }))
+  }
+
+  // Proof nodes of the leaf at an index, built from the leaves of a local copy of the tree.
+  // Nodes cached by the canopy are left out, as the program fills them in.
+  function leafProof(
+    leaves: Buffer[],
+    maxDepth: number,
+    index: number,
+    canopyDepth = 0
+  ) {
+    const { proof } = MerkleTree.sparseMerkleTreeFromLeaves(
+      leaves,
+      maxDepth
+    ).getProof(index)
+    return proof.slice(0, maxDepth - canopyDepth).map((node) => ({
+      pubkey: new PublicKey(node),
+      isSigner: false,
+      isWritable: false,
+    }))
+  }
+
+  // Allocates and initializes a new note tree, listing it in the registry if one is given
+  async function createNoteTree(
+    tree: Keypair,
+    maxDepthSizePair: ValidDepthSizePair,
+    canopyDepth: number,
+    treeRegistry: PublicKey | null = null
+  ) {
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      tree.publicKey,
+      wallet.publicKey,
+      maxDepthSizePair,
+      canopyDepth
+    )
+    const ix = await program.methods
+      .createNoteTree(
+        maxDepthSizePair.maxDepth,
+        maxDepthSizePair.maxBufferSize,
+        canopyDepth
+      )
+      .accounts({ ...noteAccounts(tree.publicKey), treeRegistry })
+      .instruction()
+    const tx = new Transaction().add(allocTreeIx, ix)
+    await sendAndConfirmTransaction(connection, tx, [wallet.payer, tree])
+  }
+
   it("Create Note Tree", async () => {
     const maxDepthSizePair: ValidDepthSizePair = {
       maxDepth: 3,
@@ -59,79 +196,4901 @@ describe("compressed-notes", () => {
     )
     // instruction to initialize the tree through the Note program
     const ix = await program.methods
-      .createNoteTree(maxDepthSizePair.maxDepth, maxDepthSizePair.maxBufferSize)
-      .accounts({
-        merkleTree: merkleTree.publicKey,
-        treeAuthority: treeAuthority,
-        logWrapper: SPL_NOOP_PROGRAM_ID,
-        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
-      })
+      .createNoteTree(
+        maxDepthSizePair.maxDepth,
+        maxDepthSizePair.maxBufferSize,
+        canopyDepth
+      )
+      .accounts(noteAccounts(merkleTree.publicKey))
       .instruction()
     const tx = new Transaction().add(allocTreeIx, ix)
     await sendAndConfirmTransaction(connection, tx, [wallet.payer, merkleTree])
   })
 
-  it("Add Note", async () => {
-    const txSignature = await program.methods
-      .appendNote(firstNote)
+  it("Create Note Tree On Initialized Tree Fails", async () => {
+    const initializedTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    // Initialize the tree directly, without the note tree accounts
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      initializedTree.publicKey,
+      wallet.publicKey,
+      maxDepthSizePair,
+      0
+    )
+    const initTreeIx = createInitEmptyMerkleTreeIx(
+      initializedTree.publicKey,
+      wallet.publicKey,
+      maxDepthSizePair
+    )
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(allocTreeIx, initTreeIx),
+      [wallet.payer, initializedTree]
+    )
+
+    try {
+      await program.methods
+        .createNoteTree(
+          maxDepthSizePair.maxDepth,
+          maxDepthSizePair.maxBufferSize,
+          0
+        )
+        .accounts(noteAccounts(initializedTree.publicKey))
+        .rpc()
+      assert.fail("Expected creating a tree twice to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TreeAlreadyInitialized")
+    }
+  })
+
+  it("Create Trees From Presets", async () => {
+    // Mirrors the tree presets in the program
+    const presets = [
+      {
+        create: () => program.methods.createSmallTree(),
+        maxDepth: 14,
+        maxBufferSize: 64,
+        canopy: 4,
+      },
+      {
+        create: () => program.methods.createMediumTree(),
+        maxDepth: 20,
+        maxBufferSize: 256,
+        canopy: 10,
+      },
+      {
+        create: () => program.methods.createLargeTree(),
+        maxDepth: 24,
+        maxBufferSize: 1024,
+        canopy: 14,
+      },
+    ]
+
+    for (const { create, maxDepth, maxBufferSize, canopy } of presets) {
+      const presetTree = Keypair.generate()
+      const allocTreeIx = await createAllocTreeIx(
+        connection,
+        presetTree.publicKey,
+        wallet.publicKey,
+        { maxDepth, maxBufferSize } as ValidDepthSizePair,
+        canopy
+      )
+      const accounts = noteAccounts(presetTree.publicKey)
+      const ix = await create()
+        .accounts({ ...accounts, treeRegistry: null })
+        .instruction()
+      await sendAndConfirmTransaction(
+        connection,
+        new Transaction().add(allocTreeIx, ix),
+        [wallet.payer, presetTree]
+      )
+
+      // The tree works, with proofs sized for the preset canopy. The only leaf
+      // has empty siblings, so its proof is the empty proof.
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+      const merkleTreeAccount =
+        await ConcurrentMerkleTreeAccount.fromAccountAddress(
+          connection,
+          presetTree.publicKey
+        )
+      assert(merkleTreeAccount.getMaxDepth() === maxDepth)
+      assert(merkleTreeAccount.getMaxBufferSize() === maxBufferSize)
+      await program.methods
+        .verifyNote(
+          0,
+          Array.from(merkleTreeAccount.getCurrentRoot()),
+          firstNote
+        )
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(maxDepth).slice(0, maxDepth - canopy))
+        .rpc()
+    }
+  })
+
+  it("Create Note Tree With Supported Config", async () => {
+    const supportedTree = Keypair.generate()
+    await createNoteTree(supportedTree, { maxDepth: 5, maxBufferSize: 8 }, 0)
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        supportedTree.publicKey
+      )
+    assert(merkleTreeAccount.getMaxDepth() === 5)
+    assert(merkleTreeAccount.getMaxBufferSize() === 8)
+  })
+
+  it("Create Note Tree With Unsupported Config Fails", async () => {
+    const unsupportedTree = Keypair.generate()
+    // Not a pair spl-account-compression can initialize
+    const maxDepthSizePair = {
+      maxDepth: 4,
+      maxBufferSize: 8,
+    } as ValidDepthSizePair
+
+    try {
+      await createNoteTree(unsupportedTree, maxDepthSizePair, 0)
+      assert.fail("Expected an unsupported tree config to fail")
+    } catch (err) {
+      // Sent as a raw transaction, so parse the anchor error from the logs
+      const anchorError = anchor.AnchorError.parse(err.logs)
+      assert(anchorError.error.errorCode.code === "UnsupportedTreeConfig")
+    }
+  })
+
+  it("Create Note Tree Deeper Than 31 Fails", async () => {
+    const deepTree = Keypair.generate()
+    // Leaf indices of a tree of depth 32 don't fit in a u32
+    const maxDepthSizePair = {
+      maxDepth: 32,
+      maxBufferSize: 8,
+    } as ValidDepthSizePair
+
+    try {
+      await createNoteTree(deepTree, maxDepthSizePair, 0)
+      assert.fail("Expected a tree of depth 32 to fail")
+    } catch (err) {
+      // Sent as a raw transaction, so parse the anchor error from the logs
+      const anchorError = anchor.AnchorError.parse(err.logs)
+      assert(anchorError.error.errorCode.code === "UnsupportedDepth")
+    }
+  })
+
+  it("Create Note Tree On Underfunded Account Fails", async () => {
+    const underfundedTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    const space = getConcurrentMerkleTreeAccountSize(
+      maxDepthSizePair.maxDepth,
+      maxDepthSizePair.maxBufferSize,
+      0
+    )
+    const minimumBalance =
+      await connection.getMinimumBalanceForRentExemption(space)
+    // Allocate the account one lamport short of rent exemption
+    const allocTreeIx = SystemProgram.createAccount({
+      fromPubkey: wallet.publicKey,
+      newAccountPubkey: underfundedTree.publicKey,
+      lamports: minimumBalance - 1,
+      space,
+      programId: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+    })
+    const ix = await program.methods
+      .createNoteTree(
+        maxDepthSizePair.maxDepth,
+        maxDepthSizePair.maxBufferSize,
+        0
+      )
+      .accounts(noteAccounts(underfundedTree.publicKey))
+      .instruction()
+
+    try {
+      await sendAndConfirmTransaction(
+        connection,
+        new Transaction().add(allocTreeIx, ix),
+        [wallet.payer, underfundedTree]
+      )
+      assert.fail("Expected an underfunded tree account to fail")
+    } catch (err) {
+      const anchorError = anchor.AnchorError.parse(err.logs)
+      assert(anchorError.error.errorCode.code === "InsufficientRent")
+      assert(err.logs.some((log) => log.includes("1 lamports short")))
+    }
+  })
+
+  it("Ensure Note Tree Creates Only If Missing", async () => {
+    const ensuredTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    const accounts = noteAccounts(ensuredTree.publicKey)
+
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      ensuredTree.publicKey,
+      wallet.publicKey,
+      maxDepthSizePair,
+      0
+    )
+    const ensureTreeIx = await program.methods
+      .ensureNoteTree(
+        maxDepthSizePair.maxDepth,
+        maxDepthSizePair.maxBufferSize,
+        0
+      )
+      .accounts(accounts)
+      .instruction()
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(allocTreeIx, ensureTreeIx),
+      [wallet.payer, ensuredTree]
+    )
+
+    const adminConfig = await program.account.adminConfig.fetch(
+      accounts.adminConfig
+    )
+    assert(adminConfig.admin.equals(provider.publicKey))
+
+    // Ensuring the tree again is a no-op
+    let listener: number
+    const event = new Promise<any>((resolve) => {
+      listener = program.addEventListener("NoteTreeEnsured", (event) =>
+        resolve(event)
+      )
+    })
+
+    await program.methods
+      .ensureNoteTree(
+        maxDepthSizePair.maxDepth,
+        maxDepthSizePair.maxBufferSize,
+        0
+      )
+      .accounts(accounts)
+      .rpc()
+
+    const noteTreeEnsured = await event
+    await program.removeEventListener(listener)
+    assert(noteTreeEnsured.tree.equals(ensuredTree.publicKey))
+    assert(noteTreeEnsured.created === false)
+
+    try {
+      await program.methods
+        .ensureNoteTree(maxDepthSizePair.maxDepth, 16, 0)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected mismatched tree parameters to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TreeParamsMismatch")
+    }
+  })
+
+  it("Ensure Note Tree On A Messages Tree Fails", async () => {
+    const messagesTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    const accounts = noteAccounts(messagesTree.publicKey)
+
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      messagesTree.publicKey,
+      wallet.publicKey,
+      maxDepthSizePair,
+      0
+    )
+    const createMessagesTreeIx = await program.methods
+      .createMessagesTree(
+        maxDepthSizePair.maxDepth,
+        maxDepthSizePair.maxBufferSize,
+        0
+      )
       .accounts({
-        merkleTree: merkleTree.publicKey,
-        treeAuthority: treeAuthority,
+        merkleTree: messagesTree.publicKey,
+        treeAuthority: getMessagesAuthority(
+          program.programId,
+          messagesTree.publicKey
+        )[0],
         logWrapper: SPL_NOOP_PROGRAM_ID,
         compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
       })
+      .instruction()
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(allocTreeIx, createMessagesTreeIx),
+      [wallet.payer, messagesTree]
+    )
+
+    // The tree has the authority of a messages tree rather than a note tree
+    try {
+      await program.methods
+        .ensureNoteTree(
+          maxDepthSizePair.maxDepth,
+          maxDepthSizePair.maxBufferSize,
+          0
+        )
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected ensuring a messages tree to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NotANoteTree")
+    }
+    assert(
+      (await connection.getAccountInfo(accounts.adminConfig)) === null,
+      "Expected no admin config to be created"
+    )
+  })
+
+  it("Max Populated Accounts Fit Their LEN", async () => {
+    const maxU64 = new anchor.BN("18446744073709551615")
+    const maxKey = new PublicKey(Buffer.alloc(32, 0xff))
+    // Mirrors the LEN of each account in the program
+    const accounts = [
+      {
+        name: "NoteCounter",
+        len: 24,
+        data: { count: maxU64, nextIndex: maxU64 },
+      },
+      {
+        name: "NoteFilter",
+        len: 8 + 1024,
+        data: { bits: Array(1024).fill(0xff) },
+      },
+      {
+        name: "AdminConfig",
+        len: 117,
+        data: {
+          admin: maxKey,
+          quotaEnabled: true,
+          hashAlgo: 0xff,
+          allowlistEnabled: true,
+          paused: true,
+          feeLamports: maxU64,
+          treasury: maxKey,
+          renounced: true,
+          logMirror: maxKey,
+        },
+      },
+      { name: "AllowedOwner", len: 8, data: {} },
+      { name: "OwnerQuota", len: 16, data: { count: maxU64 } },
+      { name: "DelegateNonce", len: 16, data: { nonce: maxU64 } },
+      {
+        name: "LatestNote",
+        len: 56,
+        data: {
+          leaf: Array(32).fill(0xff),
+          index: maxU64,
+          updatedAt: new anchor.BN("9223372036854775807"),
+        },
+      },
+      {
+        name: "TreeRegistry",
+        len: 8 + 4 + 32 * 32,
+        data: { trees: Array(32).fill(maxKey) },
+      },
+    ]
+
+    for (const { name, len, data } of accounts) {
+      const encoded = await program.coder.accounts.encode(name, data)
+      assert(encoded.length <= len, `${name} is larger than its LEN`)
+    }
+
+    // The accounts of a created tree are allocated with exactly their LEN
+    const { noteCounter, adminConfig } = noteAccounts(merkleTree.publicKey)
+    const counterInfo = await connection.getAccountInfo(noteCounter)
+    const adminConfigInfo = await connection.getAccountInfo(adminConfig)
+    assert(counterInfo.data.length === 24)
+    assert(adminConfigInfo.data.length === 117)
+  })
+
+  it("Add Note", async () => {
+    const txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(merkleTree.publicKey))
       .rpc()
 
     const noteLog = await getNoteLog(connection, txSignature)
-    const hash = getHash(firstNote, provider.publicKey)
+    const hash = getHash(firstNote, provider.publicKey, merkleTree.publicKey)
 
     assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
     assert(firstNote === noteLog.note)
+    assert(noteLog.createdAt.toNumber() > 0)
+    assert(noteLog.index.toNumber() === 0)
   })
 
-  it("Add Max Size Note", async () => {
-    // Size of note is limited by max transaction size of 1232 bytes, minus additional data required for the instruction
-    const txSignature = await program.methods
-      .appendNote(secondNote)
-      .accounts({
-        merkleTree: merkleTree.publicKey,
-        treeAuthority: treeAuthority,
-        logWrapper: SPL_NOOP_PROGRAM_ID,
-        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
-      })
+  it("Create, Append And Update Track The Local Tree", async () => {
+    const e2eTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    const { maxDepth } = maxDepthSizePair
+    await createNoteTree(e2eTree, maxDepthSizePair, 0)
+    const accounts = noteAccounts(e2eTree.publicKey)
+
+    const leafOf = (note: string) =>
+      Buffer.from(getHash(note, provider.publicKey, e2eTree.publicKey), "hex")
+    const currentRoot = async () =>
+      (
+        await ConcurrentMerkleTreeAccount.fromAccountAddress(
+          connection,
+          e2eTree.publicKey
+        )
+      ).getCurrentRoot()
+
+    // A new tree has the root of an empty local tree
+    const leaves: Buffer[] = []
+    let root = await currentRoot()
+    assert(
+      root.equals(MerkleTree.sparseMerkleTreeFromLeaves([], maxDepth).root)
+    )
+
+    for (const note of [firstNote, secondNote]) {
+      await program.methods
+        .appendNote(note, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+      leaves.push(leafOf(note))
+
+      const newRoot = await currentRoot()
+      assert(!newRoot.equals(root))
+      assert(
+        newRoot.equals(
+          MerkleTree.sparseMerkleTreeFromLeaves(leaves, maxDepth).root
+        )
+      )
+      root = newRoot
+    }
+
+    // Update the second note with a proof built from the local leaves
+    await program.methods
+      .updateNote(1, Array.from(root), secondNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepth, 1))
       .rpc()
+    leaves[1] = leafOf(updatedNote)
 
-    const noteLog = await getNoteLog(connection, txSignature)
-    const hash = getHash(secondNote, provider.publicKey)
+    const newRoot = await currentRoot()
+    assert(!newRoot.equals(root))
+    assert(
+      newRoot.equals(
+        MerkleTree.sparseMerkleTreeFromLeaves(leaves, maxDepth).root
+      )
+    )
+  })
 
-    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
-    assert(secondNote === noteLog.note)
+  it("Add Note To A System Owned Account Fails", async () => {
+    // A funded account that belongs to the system program, not the compression program
+    const fakeTree = Keypair.generate()
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(
+        SystemProgram.transfer({
+          fromPubkey: wallet.publicKey,
+          toPubkey: fakeTree.publicKey,
+          lamports: await connection.getMinimumBalanceForRentExemption(0),
+        })
+      ),
+      [wallet.payer]
+    )
+
+    // The other accounts are those of a real tree, so only the tree owner is wrong
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts({
+          ...noteAccounts(merkleTree.publicKey),
+          merkleTree: fakeTree.publicKey,
+          treeAuthority: getTreeAuthority(
+            program.programId,
+            fakeTree.publicKey
+          )[0],
+        })
+        .rpc()
+      assert.fail("Expected append to a system owned account to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "WrongAccountOwner")
+      assert(err.error.origin === "merkle_tree")
+    }
   })
 
-  it("Update First Note", async () => {
+  it("Add Note To A Tree Of Another Authority Fails", async () => {
+    // A tree initialized with the compression program directly, owned by the wallet
+    const foreignTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      foreignTree.publicKey,
+      wallet.publicKey,
+      maxDepthSizePair,
+      0
+    )
+    const initTreeIx = createInitEmptyMerkleTreeIx(
+      foreignTree.publicKey,
+      wallet.publicKey,
+      maxDepthSizePair
+    )
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(allocTreeIx, initTreeIx),
+      [wallet.payer, foreignTree]
+    )
+
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts({
+          ...noteAccounts(merkleTree.publicKey),
+          merkleTree: foreignTree.publicKey,
+          treeAuthority: getTreeAuthority(
+            program.programId,
+            foreignTree.publicKey
+          )[0],
+        })
+        .rpc()
+      assert.fail("Expected append to a tree of another authority to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NotOurTree")
+      assert(err.error.origin === "merkle_tree")
+    }
+  })
+
+  it("Preflight Reports The First Miswired Account", async () => {
+    const preflightTree = Keypair.generate()
+    await createNoteTree(preflightTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(preflightTree.publicKey)
+
+    await program.methods.preflight().accounts(accounts).rpc()
+
+    const misconfigurations = [
+      {
+        accounts: {
+          ...accounts,
+          treeAuthority: noteAccounts(merkleTree.publicKey).treeAuthority,
+        },
+        code: "ConstraintSeeds",
+        origin: "tree_authority",
+      },
+      {
+        // The wallet belongs to the system program, not the compression program
+        accounts: {
+          ...accounts,
+          merkleTree: wallet.publicKey,
+          treeAuthority: getTreeAuthority(
+            program.programId,
+            wallet.publicKey
+          )[0],
+        },
+        code: "WrongAccountOwner",
+        origin: "merkle_tree",
+      },
+      {
+        accounts: {
+          ...accounts,
+          noteCounter: noteAccounts(merkleTree.publicKey).noteCounter,
+        },
+        code: "ConstraintSeeds",
+        origin: "note_counter",
+      },
+      {
+        accounts: { ...accounts, logWrapper: SystemProgram.programId },
+        code: "InvalidProgramId",
+        origin: "log_wrapper",
+      },
+      {
+        accounts: { ...accounts, compressionProgram: SPL_NOOP_PROGRAM_ID },
+        code: "InvalidProgramId",
+        origin: "compression_program",
+      },
+    ]
+    for (const { accounts: miswired, code, origin } of misconfigurations) {
+      try {
+        await program.methods.preflight().accounts(miswired).rpc()
+        assert.fail(`Expected preflight to fail with ${code}`)
+      } catch (err) {
+        assert(err instanceof anchor.AnchorError)
+        assert(err.error.errorCode.code === code)
+        assert(err.error.origin === origin)
+      }
+    }
+  })
+
+  it("Add Note With Another Compression Program Fails", async () => {
+    // The CPIs are built for the canonical id, so no other program is accepted
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts({
+          ...noteAccounts(merkleTree.publicKey),
+          compressionProgram: SPL_NOOP_PROGRAM_ID,
+        })
+        .rpc()
+      assert.fail("Expected append with another compression program to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "InvalidProgramId")
+      assert(err.error.origin === "compression_program")
+    }
+  })
+
+  it("Add Empty Or Whitespace Note Fails", async () => {
+    const accounts = noteAccounts(merkleTree.publicKey)
+    for (const note of ["", " \n\t "]) {
+      try {
+        await program.methods
+          .appendNote(note, [], null, null, null)
+          .accounts(accounts)
+          .rpc()
+        assert.fail("Expected an empty note to fail")
+      } catch (err) {
+        assert(err instanceof anchor.AnchorError)
+        assert(err.error.errorCode.code === "EmptyNote")
+      }
+
+      try {
+        await program.methods
+          .batchAppendNotes([firstNote, note])
+          .accounts(accounts)
+          .rpc()
+        assert.fail("Expected a batch with an empty note to fail")
+      } catch (err) {
+        assert(err instanceof anchor.AnchorError)
+        assert(err.error.errorCode.code === "EmptyNote")
+      }
+    }
+  })
+
+  it("Update To An Empty Or Whitespace Note Fails", async () => {
+    const updateTree = Keypair.generate()
+    await createNoteTree(updateTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(updateTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
     const merkleTreeAccount =
       await ConcurrentMerkleTreeAccount.fromAccountAddress(
         connection,
-        merkleTree.publicKey
+        updateTree.publicKey
       )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
 
-    const rootKey = merkleTreeAccount.tree.changeLogs[0].root
-    const root = Array.from(rootKey.toBuffer())
+    for (const note of ["", " \n\t "]) {
+      try {
+        await program.methods
+          .updateNote(0, root, firstNote, note)
+          .accounts(accounts)
+          .remainingAccounts(emptyProof(3))
+          .rpc()
+        assert.fail("Expected an update to an empty note to fail")
+      } catch (err) {
+        assert(err instanceof anchor.AnchorError)
+        assert(err.error.errorCode.code === "EmptyNote")
+      }
+    }
+  })
 
-    const txSignature = await program.methods
+  it("Note Log Records Its Tree", async () => {
+    const logTree = Keypair.generate()
+    await createNoteTree(logTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(logTree.publicKey)
+
+    let txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    let noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.version === CURRENT_LOG_VERSION)
+    assert(noteLog.tree.equals(logTree.publicKey))
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        logTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+    txSignature = await program.methods
       .updateNote(0, root, firstNote, updatedNote)
-      .accounts({
-        merkleTree: merkleTree.publicKey,
-        treeAuthority: treeAuthority,
-        logWrapper: SPL_NOOP_PROGRAM_ID,
-        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
-      })
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+    noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.tree.equals(logTree.publicKey))
+  })
+
+  it("Latest Note Caches The Second Append", async () => {
+    const latestTree = Keypair.generate()
+    await createNoteTree(latestTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const [latestNote] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("latest"),
+        latestTree.publicKey.toBuffer(),
+        wallet.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+    const accounts = { ...noteAccounts(latestTree.publicKey), latestNote }
+
+    // The first append creates the cache
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
       .rpc()
+    let cached = await program.account.latestNote.fetch(latestNote)
+    assert(cached.index.toNumber() === 0)
 
+    const txSignature = await program.methods
+      .appendNote(secondNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
     const noteLog = await getNoteLog(connection, txSignature)
-    const hash = getHash(updatedNote, provider.publicKey)
+    cached = await program.account.latestNote.fetch(latestNote)
+    assert(cached.index.toNumber() === 1)
+    assert(Buffer.from(cached.leaf).equals(Buffer.from(noteLog.leafNode)))
+  })
 
-    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
-    assert(updatedNote === noteLog.note)
+  it("Latest Note Caches Unique Appends And Transfers", async () => {
+    const latestTree = Keypair.generate()
+    await createNoteTree(latestTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const [latestNote] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("latest"),
+        latestTree.publicKey.toBuffer(),
+        wallet.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+    const [noteFilter] = PublicKey.findProgramAddressSync(
+      [Buffer.from("filter"), latestTree.publicKey.toBuffer()],
+      program.programId
+    )
+    await program.methods
+      .createNoteFilter()
+      .accounts({ merkleTree: latestTree.publicKey, noteFilter })
+      .rpc()
+    const accounts = {
+      ...noteAccounts(latestTree.publicKey),
+      latestNote,
+      noteFilter,
+    }
+
+    await program.methods.appendUniqueNote(firstNote).accounts(accounts).rpc()
+    let cached = await program.account.latestNote.fetch(latestNote)
+    assert(cached.index.toNumber() === 0)
+    assert(
+      Buffer.from(cached.leaf).toString("hex") ===
+        getHash(firstNote, provider.publicKey, latestTree.publicKey)
+    )
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        latestTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // A transfer is an update of the leaf, so it is cached and emitted as one
+    const newOwner = Keypair.generate().publicKey
+    let listener: number
+    const updated = new Promise<any>((resolve) => {
+      listener = program.addEventListener("NoteUpdated", (event) =>
+        resolve(event)
+      )
+    })
+    await program.methods
+      .transferNote(0, root, firstNote, newOwner)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+    const noteUpdated = await updated
+    await program.removeEventListener(listener)
+
+    const hash = getHash(firstNote, newOwner, latestTree.publicKey)
+    assert(noteUpdated.owner.equals(newOwner))
+    assert(hash === Buffer.from(noteUpdated.leaf).toString("hex"))
+    cached = await program.account.latestNote.fetch(latestNote)
+    assert(hash === Buffer.from(cached.leaf).toString("hex"))
+  })
+
+  it("Logs Start With The Current Log Version And Their Tag", async () => {
+    const versionTree = Keypair.generate()
+    await createNoteTree(versionTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(versionTree.publicKey)
+
+    const txSignatures = [
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts(accounts)
+        .rpc(),
+      await program.methods
+        .appendNoteBytes(Buffer.from(firstNote))
+        .accounts(accounts)
+        .rpc(),
+    ]
+    // Followed by the tag of the kind of log
+    const tags = [NOTE_LOG_TAG, RAW_NOTE_LOG_TAG]
+    for (const [i, txSignature] of txSignatures.entries()) {
+      const [data] = await getApplicationData(connection, txSignature)
+      assert(data[0] === CURRENT_LOG_VERSION)
+      assert(data.subarray(1, 9).equals(tags[i]))
+    }
+  })
+
+  it("Note Log Is Mirrored To The Second Log Wrapper", async () => {
+    const mirrorTree = Keypair.generate()
+    await createNoteTree(mirrorTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(mirrorTree.publicKey)
+    await program.methods
+      .setLogMirror(SPL_NOOP_PROGRAM_ID)
+      .accounts(accounts)
+      .rpc()
+
+    let txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    assert((await getApplicationData(connection, txSignature)).length === 1)
+
+    txSignature = await program.methods
+      .appendNote(secondNote, [], null, null, null)
+      .accounts({ ...accounts, logWrapperMirror: SPL_NOOP_PROGRAM_ID })
+      .rpc()
+    const [data, mirroredData] = await getApplicationData(
+      connection,
+      txSignature
+    )
+    assert(mirroredData !== undefined)
+    assert(data.equals(mirroredData))
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.note === secondNote)
+
+    // Every instruction that logs mirrors its logs, deletes included
+    const leaves = [firstNote, secondNote].map((note) =>
+      Buffer.from(
+        getHash(note, provider.publicKey, mirrorTree.publicKey),
+        "hex"
+      )
+    )
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        mirrorTree.publicKey
+      )
+    txSignature = await program.methods
+      .deleteNote(0, Array.from(merkleTreeAccount.getCurrentRoot()), firstNote)
+      .accounts({ ...accounts, logWrapperMirror: SPL_NOOP_PROGRAM_ID })
+      .remainingAccounts(leafProof(leaves, 3, 0))
+      .rpc()
+    const deleteData = await getApplicationData(connection, txSignature)
+    assert(deleteData.length === 2)
+    assert(deleteData[0].equals(deleteData[1]))
+  })
+
+  it("Log Mirror Must Be The Mirror Set By The Admin", async () => {
+    const mirrorTree = Keypair.generate()
+    await createNoteTree(mirrorTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(mirrorTree.publicKey)
+
+    // No mirror is set for a new tree, not even the noop program
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts({ ...accounts, logWrapperMirror: SPL_NOOP_PROGRAM_ID })
+        .rpc()
+      assert.fail("Expected a mirror before one is set to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "LogMirrorNotAllowed")
+    }
+
+    await program.methods
+      .setLogMirror(SPL_NOOP_PROGRAM_ID)
+      .accounts(accounts)
+      .rpc()
+
+    // Executable, but not the mirror that was set
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts({
+          ...accounts,
+          logWrapperMirror: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+      assert.fail("Expected a mirror that isn't the one set to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "LogMirrorNotAllowed")
+    }
+
+    // Not a program at all
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts({ ...accounts, logWrapperMirror: provider.publicKey })
+        .rpc()
+      assert.fail("Expected a mirror that isn't executable to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ConstraintExecutable")
+    }
+  })
+
+  it("Same Note With Two Nonces Has Two Leaves", async () => {
+    const nonceTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(nonceTree, { maxDepth, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(nonceTree.publicKey)
+
+    const nonces = [new anchor.BN(1), new anchor.BN(2)]
+    const leaves: Buffer[] = []
+    for (const nonce of nonces) {
+      const txSignature = await program.methods
+        .appendNote(firstNote, [], null, null, nonce)
+        .accounts(accounts)
+        .rpc()
+      const noteLog = await getNoteLog(connection, txSignature)
+      const hash = getNoncedHash(
+        firstNote,
+        provider.publicKey,
+        nonceTree.publicKey,
+        nonce
+      )
+      assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+      assert(noteLog.nonce.eq(nonce))
+      leaves.push(Buffer.from(noteLog.leafNode))
+    }
+    assert(!leaves[0].equals(leaves[1]))
+
+    // The logged nonce is all that's needed to update the note later
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        nonceTree.publicKey
+      )
+    const txSignature = await program.methods
+      .updateNoncedNote(
+        1,
+        Array.from(merkleTreeAccount.getCurrentRoot()),
+        firstNote,
+        updatedNote,
+        nonces[1]
+      )
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepth, 1))
+      .rpc()
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(Buffer.from(noteLog.prevLeaf).equals(leaves[1]))
+    assert(noteLog.nonce.eq(nonces[1]))
+  })
+
+  it("Nonced Note Can Be Verified, Transferred And Deleted", async () => {
+    const nonceTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(nonceTree, { maxDepth, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(nonceTree.publicKey)
+    const currentRoot = async () =>
+      Array.from(
+        (
+          await ConcurrentMerkleTreeAccount.fromAccountAddress(
+            connection,
+            nonceTree.publicKey
+          )
+        ).getCurrentRoot()
+      )
+
+    const nonces = [new anchor.BN(7), new anchor.BN(8)]
+    const leaves: Buffer[] = []
+    for (const nonce of nonces) {
+      await program.methods
+        .appendNote(firstNote, [], null, null, nonce)
+        .accounts(accounts)
+        .rpc()
+      leaves.push(
+        Buffer.from(
+          getNoncedHash(
+            firstNote,
+            provider.publicKey,
+            nonceTree.publicKey,
+            nonce
+          ),
+          "hex"
+        )
+      )
+    }
+
+    await program.methods
+      .verifyNoncedNote(0, await currentRoot(), firstNote, nonces[0])
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepth, 0))
+      .rpc()
+
+    // The second note keeps its nonce under the new owner
+    const newOwner = Keypair.generate().publicKey
+    let txSignature = await program.methods
+      .transferNoncedNote(
+        1,
+        await currentRoot(),
+        firstNote,
+        newOwner,
+        nonces[1]
+      )
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepth, 1))
+      .rpc()
+    let noteLog = await getNoteLog(connection, txSignature)
+    leaves[1] = Buffer.from(
+      getNoncedHash(firstNote, newOwner, nonceTree.publicKey, nonces[1]),
+      "hex"
+    )
+    assert(Buffer.from(noteLog.leafNode).equals(leaves[1]))
+    assert(noteLog.nonce.eq(nonces[1]))
+
+    txSignature = await program.methods
+      .deleteNoncedNote(0, await currentRoot(), firstNote, nonces[0])
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepth, 0))
+      .rpc()
+    noteLog = await getNoteLog(connection, txSignature)
+    assert(Buffer.from(noteLog.prevLeaf).equals(leaves[0]))
+    assert(noteLog.nonce.eq(nonces[0]))
+
+    const noteCounter = await program.account.noteCounter.fetch(
+      accounts.noteCounter
+    )
+    assert(noteCounter.count.toNumber() === 1)
+  })
+
+  it("Reply Note Logs Its Parent", async () => {
+    const threadTree = Keypair.generate()
+    await createNoteTree(threadTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(threadTree.publicKey)
+
+    let txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    let noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.parentIndex === null)
+
+    txSignature = await program.methods
+      .appendNote(secondNote, [], new anchor.BN(0), null, null)
+      .accounts(accounts)
+      .rpc()
+    noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.index.toNumber() === 1)
+    assert(noteLog.parentIndex.toNumber() === 0)
+
+    // A reply can't be to a note that hasn't been appended yet
+    try {
+      await program.methods
+        .appendNote(updatedNote, [], new anchor.BN(2), null, null)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected a reply to a later note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "InvalidParentIndex")
+    }
+  })
+
+  it("Note Log Round Trips Its Content Type", async () => {
+    const typedTree = Keypair.generate()
+    await createNoteTree(typedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(typedTree.publicKey)
+
+    let txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    let noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.contentType === "text/plain")
+
+    txSignature = await program.methods
+      .appendNote(secondNote, [], null, "text/markdown", null)
+      .accounts(accounts)
+      .rpc()
+    noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.contentType === "text/markdown")
+
+    try {
+      await program.methods
+        .appendNote(updatedNote, [], null, "x".repeat(33), null)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected an oversized content type to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ContentTypeTooLong")
+    }
+  })
+
+  it("Note Log Links To The Leaf It Replaced", async () => {
+    const chainTree = Keypair.generate()
+    await createNoteTree(chainTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(chainTree.publicKey)
+
+    let txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    const appendLog = await getNoteLog(connection, txSignature)
+    assert(Buffer.from(appendLog.prevLeaf).equals(Buffer.alloc(32)))
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        chainTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+    txSignature = await program.methods
+      .updateNote(0, root, firstNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+    const updateLog = await getNoteLog(connection, txSignature)
+    assert(
+      Buffer.from(updateLog.prevLeaf).equals(Buffer.from(appendLog.leafNode))
+    )
+  })
+
+  it("Add Max Size Note", async () => {
+    // Size of note is limited by MAX_NOTE_LEN of 512 bytes
+    const txSignature = await program.methods
+      .appendNote(secondNote, [], null, null, null)
+      .accounts(noteAccounts(merkleTree.publicKey))
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    const hash = getHash(secondNote, provider.publicKey, merkleTree.publicKey)
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(secondNote === noteLog.note)
+    assert(noteLog.index.toNumber() === 1)
+  })
+
+  it("Largest Note Log Fits Max Note Log Size", async () => {
+    // Mirrors max_note_log_size in the program
+    const maxNoteLogSize = 1017
+
+    const logTree = Keypair.generate()
+    await createNoteTree(logTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    // The largest log an append can write, with a max size note, max size tags
+    // and a max size content type and a nonce, in reply to an earlier note
+    const accounts = noteAccounts(logTree.publicKey)
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    const tags = Array.from({ length: 5 }, (_, i) => `${i}`.repeat(32))
+    const txSignature = await program.methods
+      .appendNote(
+        secondNote,
+        tags,
+        new anchor.BN(0),
+        "x".repeat(32),
+        new anchor.BN(1)
+      )
+      .accounts(accounts)
+      .rpc()
+
+    const [noteLogData] = await getApplicationData(connection, txSignature)
+    assert(noteLogData.length <= maxNoteLogSize)
+  })
+
+  it("Add Note Over Max Size Fails", async () => {
+    try {
+      await program.methods
+        .appendNote("0".repeat(513), [], null, null, null)
+        .accounts(noteAccounts(merkleTree.publicKey))
+        .rpc()
+      assert.fail("Expected note over max size to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NoteTooLong")
+    }
+  })
+
+  it("Update First Note", async () => {
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        merkleTree.publicKey
+      )
+
+    const rootKey = merkleTreeAccount.tree.changeLogs[0].root
+    const root = Array.from(rootKey.toBuffer())
+
+    const txSignature = await program.methods
+      .updateNote(0, root, firstNote, updatedNote)
+      .accounts(noteAccounts(merkleTree.publicKey))
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    const hash = getHash(updatedNote, provider.publicKey, merkleTree.publicKey)
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(updatedNote === noteLog.note)
+  })
+
+  it("Update With Identical Note Fails", async () => {
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        merkleTree.publicKey
+      )
+
+    const rootKey = merkleTreeAccount.tree.changeLogs[0].root
+    const root = Array.from(rootKey.toBuffer())
+
+    try {
+      await program.methods
+        .updateNote(0, root, updatedNote, updatedNote)
+        .accounts(noteAccounts(merkleTree.publicKey))
+        .rpc()
+      assert.fail("Expected update with identical notes to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NotesAreIdentical")
+    }
+  })
+
+  it("Delete Updated Note", async () => {
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        merkleTree.publicKey
+      )
+
+    const rootKey = merkleTreeAccount.tree.changeLogs[0].root
+    const root = Array.from(rootKey.toBuffer())
+
+    const txSignature = await program.methods
+      .deleteNote(0, root, updatedNote)
+      .accounts(noteAccounts(merkleTree.publicKey))
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    const deletedLeaf = getDeletedLeaf(merkleTree.publicKey)
+
+    assert(deletedLeaf === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert("" === noteLog.note)
+  })
+
+  it("Delete Note Twice Fails With NoteAlreadyDeleted", async () => {
+    const deleteTree = Keypair.generate()
+    await createNoteTree(deleteTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(deleteTree.publicKey)
+    const currentRoot = async () =>
+      Array.from(
+        (
+          await ConcurrentMerkleTreeAccount.fromAccountAddress(
+            connection,
+            deleteTree.publicKey
+          )
+        ).getCurrentRoot()
+      )
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    await program.methods
+      .deleteNote(0, await currentRoot(), firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    try {
+      await program.methods
+        .deleteNote(0, await currentRoot(), firstNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected deleting the note again to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NoteAlreadyDeleted")
+    }
+  })
+
+  it("Deleted Leaf Differs Per Tree", async () => {
+    const deletedLeaves = []
+    for (let i = 0; i < 2; i++) {
+      const deleteTree = Keypair.generate()
+      await createNoteTree(deleteTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+      const accounts = noteAccounts(deleteTree.publicKey)
+
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+      const merkleTreeAccount =
+        await ConcurrentMerkleTreeAccount.fromAccountAddress(
+          connection,
+          deleteTree.publicKey
+        )
+      const root = Array.from(
+        merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+      )
+      const txSignature = await program.methods
+        .deleteNote(0, root, firstNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+
+      const noteLog = await getNoteLog(connection, txSignature)
+      const deletedLeaf = Buffer.from(noteLog.leafNode).toString("hex")
+      assert(deletedLeaf === getDeletedLeaf(deleteTree.publicKey))
+      assert(!Buffer.from(noteLog.leafNode).equals(Buffer.alloc(32)))
+      deletedLeaves.push(deletedLeaf)
+    }
+
+    assert(deletedLeaves[0] !== deletedLeaves[1])
+  })
+
+  it("Expiring Note Can Be Accessed Until It Expires", async () => {
+    const expiringTree = Keypair.generate()
+    await createNoteTree(expiringTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(expiringTree.publicKey)
+    const verifyAccounts = {
+      owner: provider.publicKey,
+      merkleTree: expiringTree.publicKey,
+      adminConfig: accounts.adminConfig,
+    }
+    // The clock the program reads expiries against
+    const chainTime = async () =>
+      connection.getBlockTime(await connection.getSlot())
+
+    const expiresAt = new anchor.BN((await chainTime()) + 5)
+    const txSignature = await program.methods
+      .appendExpiringNote(firstNote, expiresAt)
+      .accounts(accounts)
+      .rpc()
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.expiresAt.eq(expiresAt))
+    assert(
+      Buffer.from(noteLog.leafNode).toString("hex") ===
+        getExpiringHash(
+          firstNote,
+          provider.publicKey,
+          expiringTree.publicKey,
+          expiresAt
+        )
+    )
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        expiringTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // Before the expiry the note verifies, and can't be deleted by others
+    await program.methods
+      .verifyExpiringNote(0, root, firstNote, expiresAt)
+      .accounts(verifyAccounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+    const cleaner = Keypair.generate()
+    const deleteAccounts = {
+      ...accounts,
+      caller: cleaner.publicKey,
+      owner: provider.publicKey,
+    }
+    try {
+      await program.methods
+        .deleteExpiredNote(0, root, firstNote, expiresAt)
+        .accounts(deleteAccounts)
+        .remainingAccounts(emptyProof(3))
+        .signers([cleaner])
+        .rpc()
+      assert.fail("Expected deleting a live note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NoteNotExpired")
+    }
+
+    while ((await chainTime()) <= expiresAt.toNumber()) {
+      await new Promise((resolve) => setTimeout(resolve, 1000))
+    }
+
+    // After the expiry the note can no longer be verified or updated
+    try {
+      await program.methods
+        .verifyExpiringNote(0, root, firstNote, expiresAt)
+        .accounts(verifyAccounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected verifying an expired note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NoteExpired")
+    }
+    try {
+      await program.methods
+        .updateExpiringNote(0, root, firstNote, updatedNote, expiresAt)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected updating an expired note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NoteExpired")
+    }
+
+    // Anyone can delete it
+    const deleteSignature = await program.methods
+      .deleteExpiredNote(0, root, firstNote, expiresAt)
+      .accounts(deleteAccounts)
+      .remainingAccounts(emptyProof(3))
+      .signers([cleaner])
+      .rpc()
+    const deleteLog = await getNoteLog(connection, deleteSignature)
+    assert(
+      Buffer.from(deleteLog.leafNode).toString("hex") ===
+        getDeletedLeaf(expiringTree.publicKey)
+    )
+  })
+
+  it("Append At Refills A Deleted Slot", async () => {
+    const slotTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(slotTree, { maxDepth, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(slotTree.publicKey)
+    const leafOf = (note: string) =>
+      Buffer.from(getHash(note, provider.publicKey, slotTree.publicKey), "hex")
+    const currentRoot = async () =>
+      Array.from(
+        (
+          await ConcurrentMerkleTreeAccount.fromAccountAddress(
+            connection,
+            slotTree.publicKey
+          )
+        ).getCurrentRoot()
+      )
+
+    const leaves = [leafOf(firstNote), leafOf(secondNote)]
+    for (const note of [firstNote, secondNote]) {
+      await program.methods
+        .appendNote(note, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+    }
+
+    // A slot holding a note can't be refilled
+    try {
+      await program.methods
+        .appendAt(1, await currentRoot(), updatedNote)
+        .accounts(accounts)
+        .remainingAccounts(leafProof(leaves, maxDepth, 1))
+        .rpc()
+      assert.fail("Expected append into an occupied slot to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "SlotOccupied")
+    }
+
+    await program.methods
+      .deleteNote(0, await currentRoot(), firstNote)
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepth, 0))
+      .rpc()
+    leaves[0] = Buffer.from(getDeletedLeaf(slotTree.publicKey), "hex")
+
+    const txSignature = await program.methods
+      .appendAt(0, await currentRoot(), updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepth, 0))
+      .rpc()
+    leaves[0] = leafOf(updatedNote)
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.index.toNumber() === 0)
+    assert(noteLog.note === updatedNote)
+
+    // The slot is reused, so the tree holds the same number of leaves
+    const root = Buffer.from(await currentRoot())
+    assert(
+      root.equals(MerkleTree.sparseMerkleTreeFromLeaves(leaves, maxDepth).root)
+    )
+    const counter = await program.account.noteCounter.fetch(
+      accounts.noteCounter
+    )
+    assert(counter.count.toNumber() === 2)
+    assert(counter.nextIndex.toNumber() === 2)
+  })
+
+  it("Migrate Note To A Larger Tree", async () => {
+    const sourceTree = Keypair.generate()
+    const destTree = Keypair.generate()
+    await createNoteTree(sourceTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    await createNoteTree(destTree, { maxDepth: 5, maxBufferSize: 8 }, 0)
+    const source = noteAccounts(sourceTree.publicKey)
+    const dest = noteAccounts(destTree.publicKey)
+    const currentRoot = async (tree: PublicKey) =>
+      (
+        await ConcurrentMerkleTreeAccount.fromAccountAddress(connection, tree)
+      ).getCurrentRoot()
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(source)
+      .rpc()
+    const root = Array.from(await currentRoot(sourceTree.publicKey))
+    await program.methods
+      .migrateNote(0, root, firstNote)
+      .accounts({
+        sourceTreeAuthority: source.treeAuthority,
+        sourceMerkleTree: sourceTree.publicKey,
+        sourceNoteCounter: source.noteCounter,
+        sourceAdminConfig: source.adminConfig,
+        sourceOwnerQuota: null,
+        destTreeAuthority: dest.treeAuthority,
+        destMerkleTree: destTree.publicKey,
+        destNoteCounter: dest.noteCounter,
+        destAdminConfig: dest.adminConfig,
+        destOwnerQuota: null,
+        destAllowedOwner: null,
+        destLatestNote: null,
+        destTreasury: null,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        logWrapperMirror: null,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    // The note is tombstoned in the source tree
+    const deletedLeaf = Buffer.from(getDeletedLeaf(sourceTree.publicKey), "hex")
+    assert(
+      (await currentRoot(sourceTree.publicKey)).equals(
+        MerkleTree.sparseMerkleTreeFromLeaves([deletedLeaf], 3).root
+      )
+    )
+
+    // And exists in the destination tree, hashed with the destination tree key
+    const destRoot = Array.from(await currentRoot(destTree.publicKey))
+    await program.methods
+      .verifyNote(0, destRoot, firstNote)
+      .accounts(dest)
+      .remainingAccounts(emptyProof(5))
+      .rpc()
+
+    const sourceCounter = await program.account.noteCounter.fetch(
+      source.noteCounter
+    )
+    const destCounter = await program.account.noteCounter.fetch(
+      dest.noteCounter
+    )
+    assert(sourceCounter.count.toNumber() === 0)
+    assert(destCounter.count.toNumber() === 1)
+  })
+
+  it("Transfer Second Note", async () => {
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        merkleTree.publicKey
+      )
+
+    const rootKey = merkleTreeAccount.tree.changeLogs[0].root
+    const root = Array.from(rootKey.toBuffer())
+
+    const newOwner = Keypair.generate().publicKey
+
+    const txSignature = await program.methods
+      .transferNote(1, root, secondNote, newOwner)
+      .accounts(noteAccounts(merkleTree.publicKey))
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    const hash = getHash(secondNote, newOwner, merkleTree.publicKey)
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(noteLog.owner.equals(newOwner))
+  })
+
+  it("Transfer Note Logs A Transfer Log", async () => {
+    const transferTree = Keypair.generate()
+    await createNoteTree(transferTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(transferTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        transferTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    const newOwner = Keypair.generate().publicKey
+    const txSignature = await program.methods
+      .transferNote(0, root, firstNote, newOwner)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    const transferLog = await getTransferLog(connection, txSignature)
+    const hash = getHash(firstNote, newOwner, transferTree.publicKey)
+    assert(transferLog.version === CURRENT_LOG_VERSION)
+    assert(transferLog.from.equals(provider.publicKey))
+    assert(transferLog.to.equals(newOwner))
+    assert(transferLog.index.toNumber() === 0)
+    assert(hash === Buffer.from(transferLog.leaf).toString("hex"))
+
+    // The NoteLog of the new leaf is still logged alongside it
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.owner.equals(newOwner))
+  })
+
+  it("Add Note Emits Event", async () => {
+    let listener: number
+    const event = new Promise<any>((resolve) => {
+      listener = program.addEventListener("NoteAppended", (event) =>
+        resolve(event)
+      )
+    })
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(merkleTree.publicKey))
+      .rpc()
+
+    const noteAppended = await event
+    await program.removeEventListener(listener)
+    const hash = getHash(firstNote, provider.publicKey, merkleTree.publicKey)
+
+    assert(noteAppended.tree.equals(merkleTree.publicKey))
+    assert(noteAppended.index.toNumber() === 2)
+    assert(noteAppended.owner.equals(provider.publicKey))
+    assert(hash === Buffer.from(noteAppended.leaf).toString("hex"))
+  })
+
+  it("Update And Delete Note Emit Events", async () => {
+    const eventTree = Keypair.generate()
+    await createNoteTree(eventTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(eventTree.publicKey)
+    const currentRoot = async () =>
+      Array.from(
+        (
+          await ConcurrentMerkleTreeAccount.fromAccountAddress(
+            connection,
+            eventTree.publicKey
+          )
+        ).getCurrentRoot()
+      )
+    const nextEvent = (name: string) => {
+      let listener: number
+      const event = new Promise<any>((resolve) => {
+        listener = program.addEventListener(name, (event) => resolve(event))
+      })
+      return event.then(async (event) => {
+        await program.removeEventListener(listener)
+        return event
+      })
+    }
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const updated = nextEvent("NoteUpdated")
+    await program.methods
+      .updateNote(0, await currentRoot(), firstNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+    const noteUpdated = await updated
+    const updatedHash = getHash(
+      updatedNote,
+      provider.publicKey,
+      eventTree.publicKey
+    )
+    assert(noteUpdated.tree.equals(eventTree.publicKey))
+    assert(noteUpdated.index.toNumber() === 0)
+    assert(noteUpdated.owner.equals(provider.publicKey))
+    assert(updatedHash === Buffer.from(noteUpdated.leaf).toString("hex"))
+
+    const deleted = nextEvent("NoteDeleted")
+    await program.methods
+      .deleteNote(0, await currentRoot(), updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+    const noteDeleted = await deleted
+    // The event carries the leaf of the note that was deleted
+    assert(noteDeleted.tree.equals(eventTree.publicKey))
+    assert(noteDeleted.index.toNumber() === 0)
+    assert(noteDeleted.owner.equals(provider.publicKey))
+    assert(updatedHash === Buffer.from(noteDeleted.leaf).toString("hex"))
+  })
+
+  it("Update Note With Canopy", async () => {
+    const canopyTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 20,
+      maxBufferSize: 64,
+    }
+    const canopyDepth = 10
+    const accounts = noteAccounts(canopyTree.publicKey)
+
+    await createNoteTree(canopyTree, maxDepthSizePair, canopyDepth)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        canopyTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // Only the nodes below the canopy are needed in the proof
+    const leaf = Buffer.from(
+      getHash(firstNote, provider.publicKey, canopyTree.publicKey),
+      "hex"
+    )
+    const remainingAccounts = leafProof(
+      [leaf],
+      maxDepthSizePair.maxDepth,
+      0,
+      canopyDepth
+    )
+
+    const txSignature = await program.methods
+      .updateNote(0, root, firstNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(remainingAccounts)
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    const hash = getHash(updatedNote, provider.publicKey, canopyTree.publicKey)
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+  })
+
+  it("Update Note Without Enough Compute Fails", async () => {
+    const budgetTree = Keypair.generate()
+    await createNoteTree(budgetTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(budgetTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        budgetTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    try {
+      await program.methods
+        .updateNote(0, root, firstNote, updatedNote)
+        .accounts(accounts)
+        .preInstructions([
+          ComputeBudgetProgram.setComputeUnitLimit({ units: 20_000 }),
+        ])
+        .rpc()
+      assert.fail("Expected update without enough compute to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofTooExpensive")
+    }
+  })
+
+  it("Update Note With Wrong Old Note Fails", async () => {
+    const proofTree = Keypair.generate()
+    await createNoteTree(proofTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(proofTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        proofTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    try {
+      await program.methods
+        .updateNote(0, root, "not the first note", updatedNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected update with the wrong old note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+  })
+
+  it("Update Note By A Non-Owner Fails", async () => {
+    const ownedTree = Keypair.generate()
+    await createNoteTree(ownedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(ownedTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        ownedTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // The other signer knows the note and the owner, but the leaf is hashed
+    // with the signer's key, so it doesn't match the owner's leaf
+    const otherSigner = Keypair.generate()
+    try {
+      await program.methods
+        .updateNote(0, root, firstNote, updatedNote)
+        .accounts({ ...accounts, owner: otherSigner.publicKey })
+        .remainingAccounts(emptyProof(3))
+        .signers([otherSigner])
+        .rpc()
+      assert.fail("Expected an update by a non-owner to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+
+    // The owner can still update the note against the same root
+    await program.methods
+      .updateNote(0, root, firstNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+  })
+
+  it("Update Note With Too Few Proof Nodes Fails", async () => {
+    const proofTree = Keypair.generate()
+    await createNoteTree(proofTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(proofTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        proofTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    try {
+      await program.methods
+        .updateNote(0, root, firstNote, updatedNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3).slice(0, 2))
+        .rpc()
+      assert.fail("Expected update with too few proof nodes to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofLengthMismatch")
+    }
+  })
+
+  it("Proof Length Must Match The Canopy", async () => {
+    const canopyTree = Keypair.generate()
+    const maxDepth = 5
+    const canopyDepth = 2
+    await createNoteTree(
+      canopyTree,
+      { maxDepth, maxBufferSize: 8 },
+      canopyDepth
+    )
+    const accounts = noteAccounts(canopyTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    const leaves = [
+      Buffer.from(
+        getHash(firstNote, provider.publicKey, canopyTree.publicKey),
+        "hex"
+      ),
+    ]
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        canopyTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // The canopy caches the top levels, so one node too many or too few fails
+    const fullProof = leafProof(leaves, maxDepth, 0)
+    for (const proof of [
+      fullProof.slice(0, maxDepth - canopyDepth + 1),
+      fullProof.slice(0, maxDepth - canopyDepth - 1),
+    ]) {
+      try {
+        await program.methods
+          .verifyNote(0, root, firstNote)
+          .accounts(accounts)
+          .remainingAccounts(proof)
+          .rpc()
+        assert.fail("Expected a proof of the wrong length to fail")
+      } catch (err) {
+        assert(err instanceof anchor.AnchorError)
+        assert(err.error.errorCode.code === "ProofLengthMismatch")
+      }
+    }
+
+    await program.methods
+      .verifyNote(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepth, 0, canopyDepth))
+      .rpc()
+  })
+
+  it("Update Note With A Full Proof Set", async () => {
+    const proofTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(proofTree, { maxDepth, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(proofTree.publicKey)
+
+    const notes = ["first", "second", "third"]
+    await program.methods.batchAppendNotes(notes).accounts(accounts).rpc()
+    const leaves = notes.map((note) =>
+      Buffer.from(getHash(note, provider.publicKey, proofTree.publicKey), "hex")
+    )
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        proofTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // The nodes are read from the leaf up, so reordering them breaks the proof
+    const proof = leafProof(leaves, maxDepth, 2)
+    try {
+      await program.methods
+        .updateNote(2, root, notes[2], updatedNote)
+        .accounts(accounts)
+        .remainingAccounts([...proof].reverse())
+        .rpc()
+      assert.fail("Expected update with reordered proof nodes to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+
+    await program.methods
+      .updateNote(2, root, notes[2], updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(proof)
+      .rpc()
+  })
+
+  it("Update Note Replaces The Leaf It Verified", async () => {
+    const replaceTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(replaceTree, { maxDepth, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(replaceTree.publicKey)
+    const leafOf = (note: string) =>
+      Buffer.from(
+        getHash(note, provider.publicKey, replaceTree.publicKey),
+        "hex"
+      )
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    let merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        replaceTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    const txSignature = await program.methods
+      .updateNote(0, root, firstNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    // The verified leaf is logged as the replaced leaf, the leaf of the old note
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(Buffer.from(noteLog.prevLeaf).equals(leafOf(firstNote)))
+
+    // Only that leaf was replaced, leaving a tree of just the new note
+    merkleTreeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      replaceTree.publicKey
+    )
+    const expectedRoot = MerkleTree.sparseMerkleTreeFromLeaves(
+      [leafOf(updatedNote)],
+      maxDepth
+    ).root
+    assert(merkleTreeAccount.getCurrentRoot().equals(expectedRoot))
+  })
+
+  it("Update Note Returns The New Root", async () => {
+    const rootTree = Keypair.generate()
+    await createNoteTree(rootTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(rootTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    let merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        rootTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    const txSignature = await program.methods
+      .updateNote(0, root, firstNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    // The return data is logged as base64 after the program id
+    const tx = await connection.getTransaction(txSignature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    })
+    const returnLog = tx.meta.logMessages.find((log) =>
+      log.startsWith(`Program return: ${program.programId.toBase58()}`)
+    )
+    const newRoot = Buffer.from(returnLog.split(" ")[3], "base64")
+
+    merkleTreeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      rootTree.publicKey
+    )
+    assert(newRoot.equals(merkleTreeAccount.getCurrentRoot()))
+    assert(!newRoot.equals(Buffer.from(root)))
+  })
+
+  it("Update Note Past The Last Leaf Fails", async () => {
+    const rangeTree = Keypair.generate()
+    await createNoteTree(rangeTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(rangeTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        rangeTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // Only index 0 has been appended
+    try {
+      await program.methods
+        .updateNote(1, root, firstNote, updatedNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected update past the last leaf to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "IndexOutOfRange")
+    }
+  })
+
+  it("Update Note Against Stale Root", async () => {
+    const staleTree = Keypair.generate()
+    await createNoteTree(staleTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(staleTree.publicKey)
+
+    // The root of the empty tree stays in the buffer of 8 for the next 7 changes
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        staleTree.publicKey
+      )
+    const emptyRoot = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    const notes = Array.from({ length: 7 }, (_, i) => `note ${i}`)
+    await program.methods.batchAppendNotes(notes).accounts(accounts).rpc()
+
+    // Within the buffer window, the update is fast-forwarded to the current root
+    await program.methods
+      .updateNote(0, emptyRoot, notes[0], updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    // The 8th change pushed the empty root out of the buffer
+    try {
+      await program.methods
+        .updateNote(0, emptyRoot, updatedNote, firstNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected update against a root beyond the buffer to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "RootNotInBuffer")
+    }
+
+    // A bad proof against a root still in the buffer is a proof mismatch
+    const currentTree = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      staleTree.publicKey
+    )
+    const currentRoot = Array.from(currentTree.getCurrentRoot())
+    try {
+      await program.methods
+        .updateNote(0, currentRoot, updatedNote, firstNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected update with a bad proof to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+  })
+
+  it("Update Note If Unchanged Fails After Intervening Update", async () => {
+    const casTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    await createNoteTree(casTree, maxDepthSizePair, 0)
+    const accounts = noteAccounts(casTree.publicKey)
+
+    let txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    // The leaf the first client read the note at
+    const readLeaf = (await getNoteLog(connection, txSignature)).leafNode
+
+    let merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        casTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    // A second client updates the note in between
+    txSignature = await program.methods
+      .updateNote(0, root, firstNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+    const updatedLeaf = Buffer.from(
+      (await getNoteLog(connection, txSignature)).leafNode
+    )
+
+    try {
+      await program.methods
+        .updateNoteIfUnchanged(0, root, Array.from(readLeaf), "lost update")
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected update of a modified note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ConcurrentModification")
+    }
+
+    // After reloading the note, the update goes through
+    merkleTreeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      casTree.publicKey
+    )
+    const currentRoot = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    txSignature = await program.methods
+      .updateNoteIfUnchanged(
+        0,
+        currentRoot,
+        Array.from(updatedLeaf),
+        "reloaded update"
+      )
+      .accounts(accounts)
+      .remainingAccounts(leafProof([updatedLeaf], maxDepthSizePair.maxDepth, 0))
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert("reloaded update" === noteLog.note)
+  })
+
+  it("Close Empty Note Tree", async () => {
+    const emptyTree = Keypair.generate()
+    await createNoteTree(emptyTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    await program.methods
+      .closeNoteTree()
+      .accounts(noteAccounts(emptyTree.publicKey))
+      .rpc()
+
+    const accountInfo = await connection.getAccountInfo(emptyTree.publicKey)
+    assert(accountInfo === null)
+  })
+
+  it("Close Non Empty Note Tree Fails", async () => {
+    try {
+      await program.methods
+        .closeNoteTree()
+        .accounts(noteAccounts(merkleTree.publicKey))
+        .rpc()
+      assert.fail("Expected closing a non empty tree to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TreeNotEmpty")
+    }
+  })
+
+  it("Close Note Tree With Every Note Deleted Fails", async () => {
+    const deletedTree = Keypair.generate()
+    await createNoteTree(deletedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(deletedTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        deletedTree.publicKey
+      )
+    await program.methods
+      .deleteNote(0, Array.from(merkleTreeAccount.getCurrentRoot()), firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    // The deleted leaf is still in the tree, so it isn't empty
+    try {
+      await program.methods.closeNoteTree().accounts(accounts).rpc()
+      assert.fail("Expected closing a tree with a deleted note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TreeNotEmpty")
+    }
+  })
+
+  it("Transfer Admin", async () => {
+    const adminTree = Keypair.generate()
+    await createNoteTree(adminTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(adminTree.publicKey)
+
+    const newAdmin = Keypair.generate()
+    await program.methods
+      .transferAdmin(newAdmin.publicKey)
+      .accounts(accounts)
+      .rpc()
+
+    const adminConfig = await program.account.adminConfig.fetch(
+      accounts.adminConfig
+    )
+    assert(adminConfig.admin.equals(newAdmin.publicKey))
+
+    // The previous admin can no longer close the tree
+    try {
+      await program.methods.closeNoteTree().accounts(accounts).rpc()
+      assert.fail("Expected the previous admin to be unauthorized")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "Unauthorized")
+    }
+
+    await program.methods
+      .closeNoteTree()
+      .accounts({ ...accounts, admin: newAdmin.publicKey })
+      .signers([newAdmin])
+      .rpc()
+
+    const accountInfo = await connection.getAccountInfo(adminTree.publicKey)
+    assert(accountInfo === null)
+  })
+
+  it("Transfer Admin By Non Admin Fails", async () => {
+    const adminTree = Keypair.generate()
+    await createNoteTree(adminTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    const attacker = Keypair.generate()
+    try {
+      await program.methods
+        .transferAdmin(attacker.publicKey)
+        .accounts({
+          ...noteAccounts(adminTree.publicKey),
+          admin: attacker.publicKey,
+        })
+        .signers([attacker])
+        .rpc()
+      assert.fail("Expected a non admin to be unauthorized")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "Unauthorized")
+    }
+  })
+
+  it("Transfer Admin To The System Program Fails", async () => {
+    const adminTree = Keypair.generate()
+    await createNoteTree(adminTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(adminTree.publicKey)
+
+    // The default key and the system program are the same key
+    for (const newAdmin of [PublicKey.default, SystemProgram.programId]) {
+      try {
+        await program.methods.transferAdmin(newAdmin).accounts(accounts).rpc()
+        assert.fail("Expected a transfer to a key no one can sign for to fail")
+      } catch (err) {
+        assert(err instanceof anchor.AnchorError)
+        assert(err.error.errorCode.code === "InvalidNewAdmin")
+      }
+    }
+
+    const adminConfig = await program.account.adminConfig.fetch(
+      accounts.adminConfig
+    )
+    assert(adminConfig.admin.equals(provider.publicKey))
+  })
+
+  it("Tree Registry Lists Created Trees", async () => {
+    const [treeRegistry] = PublicKey.findProgramAddressSync(
+      [Buffer.from("registry"), wallet.publicKey.toBuffer()],
+      program.programId
+    )
+    await program.methods
+      .createTreeRegistry()
+      .accounts({ admin: wallet.publicKey, treeRegistry })
+      .rpc()
+
+    const firstTree = Keypair.generate()
+    const secondTree = Keypair.generate()
+    const maxDepthSizePair = { maxDepth: 3, maxBufferSize: 8 }
+    await createNoteTree(firstTree, maxDepthSizePair, 0, treeRegistry)
+    await createNoteTree(secondTree, maxDepthSizePair, 0, treeRegistry)
+
+    let registry = await program.account.treeRegistry.fetch(treeRegistry)
+    assert.deepEqual(
+      registry.trees.map((tree) => tree.toBase58()),
+      [firstTree.publicKey.toBase58(), secondTree.publicKey.toBase58()]
+    )
+
+    await program.methods
+      .deregisterTree()
+      .accounts({
+        admin: wallet.publicKey,
+        merkleTree: firstTree.publicKey,
+        treeRegistry,
+      })
+      .rpc()
+
+    registry = await program.account.treeRegistry.fetch(treeRegistry)
+    assert.deepEqual(
+      registry.trees.map((tree) => tree.toBase58()),
+      [secondTree.publicKey.toBase58()]
+    )
+  })
+
+  it("Grow Registry Keeps Listed Trees", async () => {
+    // The registry created above, listing one tree
+    const [treeRegistry] = PublicKey.findProgramAddressSync(
+      [Buffer.from("registry"), wallet.publicKey.toBuffer()],
+      program.programId
+    )
+    const registryBefore = await program.account.treeRegistry.fetch(
+      treeRegistry
+    )
+    const listedTrees = registryBefore.trees.map((tree) => tree.toBase58())
+
+    await program.methods
+      .growRegistry(4)
+      .accounts({ admin: wallet.publicKey, treeRegistry })
+      .rpc()
+
+    // Room for the listed tree and 4 more, after the discriminator and vec length
+    let accountInfo = await connection.getAccountInfo(treeRegistry)
+    assert(accountInfo.data.length === 8 + 4 + 32 * 5)
+    let registry = await program.account.treeRegistry.fetch(treeRegistry)
+    assert.deepEqual(
+      registry.trees.map((tree) => tree.toBase58()),
+      listedTrees
+    )
+
+    // Creating a tree uses the reserved room rather than shrinking the registry
+    const newTree = Keypair.generate()
+    await createNoteTree(
+      newTree,
+      { maxDepth: 3, maxBufferSize: 8 },
+      0,
+      treeRegistry
+    )
+    accountInfo = await connection.getAccountInfo(treeRegistry)
+    assert(accountInfo.data.length === 8 + 4 + 32 * 5)
+    registry = await program.account.treeRegistry.fetch(treeRegistry)
+    assert.deepEqual(
+      registry.trees.map((tree) => tree.toBase58()),
+      [...listedTrees, newTree.publicKey.toBase58()]
+    )
+
+    // Removing a tree keeps the reserved room too
+    await program.methods
+      .deregisterTree()
+      .accounts({
+        admin: wallet.publicKey,
+        merkleTree: newTree.publicKey,
+        treeRegistry,
+      })
+      .rpc()
+    accountInfo = await connection.getAccountInfo(treeRegistry)
+    assert(accountInfo.data.length === 8 + 4 + 32 * 5)
+    registry = await program.account.treeRegistry.fetch(treeRegistry)
+    assert.deepEqual(
+      registry.trees.map((tree) => tree.toBase58()),
+      listedTrees
+    )
+
+    try {
+      await program.methods
+        .growRegistry(32)
+        .accounts({ admin: wallet.publicKey, treeRegistry })
+        .rpc()
+      assert.fail("Expected growing past the max number of trees to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "RegistryFull")
+    }
+  })
+
+  it("Sha256 Tree Hashes Leaves With Sha256", async () => {
+    const sha256Tree = Keypair.generate()
+    await createNoteTree(sha256Tree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(sha256Tree.publicKey)
+
+    await program.methods
+      .setHashAlgo(1)
+      .accounts({
+        admin: wallet.publicKey,
+        merkleTree: sha256Tree.publicKey,
+        adminConfig: accounts.adminConfig,
+      })
+      .rpc()
+
+    const txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    const keccakHash = getHash(
+      firstNote,
+      provider.publicKey,
+      sha256Tree.publicKey
+    )
+    const sha256Hash = getHash(
+      firstNote,
+      provider.publicKey,
+      sha256Tree.publicKey,
+      "sha256"
+    )
+
+    assert(keccakHash !== sha256Hash)
+    assert(sha256Hash === Buffer.from(noteLog.leafNode).toString("hex"))
+
+    // The tree has a leaf now, so the hash can't be changed back
+    try {
+      await program.methods
+        .setHashAlgo(0)
+        .accounts({
+          admin: wallet.publicKey,
+          merkleTree: sha256Tree.publicKey,
+          adminConfig: accounts.adminConfig,
+        })
+        .rpc()
+      assert.fail("Expected changing the hash of a non empty tree to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TreeNotEmpty")
+    }
+  })
+
+  it("Note And Message Leaves Never Collide", async () => {
+    const collisionTree = Keypair.generate()
+    await createNoteTree(collisionTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    // Without the domain tags, a note of the message followed by the sender, owned by the
+    // recipient, would hash to the same leaf as the message
+    const sender = Keypair.generate().publicKey
+    const data = Buffer.concat([Buffer.from(firstNote), sender.toBuffer()])
+
+    const txSignature = await program.methods
+      .appendNoteBytes(data)
+      .accounts(noteAccounts(collisionTree.publicKey))
+      .rpc()
+    const rawNoteLog = await getRawNoteLog(connection, txSignature)
+    const messageHash = getMessageHash(
+      firstNote,
+      sender,
+      provider.publicKey,
+      collisionTree.publicKey
+    )
+
+    assert(rawNoteLog.version === CURRENT_LOG_VERSION)
+    assert(Buffer.from(rawNoteLog.tag).equals(RAW_NOTE_LOG_TAG))
+    assert(messageHash !== Buffer.from(rawNoteLog.leafNode).toString("hex"))
+  })
+
+  it("Same Note Hashes Differently Across Trees", async () => {
+    const otherTree = Keypair.generate()
+    await createNoteTree(otherTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    const txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(otherTree.publicKey))
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    const leaf = Buffer.from(noteLog.leafNode).toString("hex")
+
+    assert(leaf === getHash(firstNote, provider.publicKey, otherTree.publicKey))
+    assert(
+      leaf !== getHash(firstNote, provider.publicKey, merkleTree.publicKey)
+    )
+  })
+
+  it("Batch Append Notes", async () => {
+    const batchTree = Keypair.generate()
+    await createNoteTree(batchTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    const accounts = noteAccounts(batchTree.publicKey)
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const notes = ["first", "second", "third"]
+    const txSignature = await program.methods
+      .batchAppendNotes(notes)
+      .accounts(accounts)
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        batchTree.publicKey
+      )
+    assert(merkleTreeAccount.tree.rightMostPath.index === notes.length + 1)
+
+    // The return data is the start index as a u64, then the count as a u32
+    const tx = await connection.getTransaction(txSignature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    })
+    const returnLog = tx.meta.logMessages.find((log) =>
+      log.startsWith(`Program return: ${program.programId.toBase58()}`)
+    )
+    const returnData = Buffer.from(returnLog.split(" ")[3], "base64")
+    const startIndex = new anchor.BN(returnData.subarray(0, 8), "le")
+    assert(startIndex.toNumber() === 1)
+    assert(returnData.readUInt32LE(8) === notes.length)
+  })
+
+  it("Append Note Returns Its Index And Leaf", async () => {
+    const returnTree = Keypair.generate()
+    await createNoteTree(returnTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(returnTree.publicKey)
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const txSignature = await program.methods
+      .appendNote(secondNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    // The return data is the index as a u64, then the 32 byte leaf
+    const tx = await connection.getTransaction(txSignature, {
+      commitment: "confirmed",
+      maxSupportedTransactionVersion: 0,
+    })
+    const returnLog = tx.meta.logMessages.find((log) =>
+      log.startsWith(`Program return: ${program.programId.toBase58()}`)
+    )
+    const returnData = Buffer.from(returnLog.split(" ")[3], "base64")
+    assert(returnData.length === 40)
+    const index = new anchor.BN(returnData.subarray(0, 8), "le")
+    const leaf = returnData.subarray(8)
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(index.toNumber() === 1)
+    assert(index.eq(noteLog.index))
+    assert(leaf.equals(Buffer.from(noteLog.leafNode)))
+  })
+
+  it("Update Notes Updates All Notes", async () => {
+    const bulkTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    await createNoteTree(bulkTree, maxDepthSizePair, 0)
+    const accounts = noteAccounts(bulkTree.publicKey)
+
+    const notes = ["first", "second", "third"]
+    await program.methods.batchAppendNotes(notes).accounts(accounts).rpc()
+
+    const leafOf = (note: string) =>
+      Buffer.from(getHash(note, provider.publicKey, bulkTree.publicKey), "hex")
+
+    // Every proof is taken at the root before the batch
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        bulkTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    const newNotes = notes.map((note) => `${note} updated`)
+    const items = notes.map((note, index) => ({
+      index,
+      root,
+      oldNote: note,
+      newNote: newNotes[index],
+    }))
+    const remainingAccounts = notes.flatMap((_, index) =>
+      leafProof(notes.map(leafOf), maxDepthSizePair.maxDepth, index)
+    )
+
+    await program.methods
+      .updateNotes(items)
+      .accounts(accounts)
+      .remainingAccounts(remainingAccounts)
+      .preInstructions([
+        ComputeBudgetProgram.setComputeUnitLimit({ units: 600_000 }),
+      ])
+      .rpc()
+
+    // The root only matches if every leaf was replaced
+    const { root: expectedRoot } = MerkleTree.sparseMerkleTreeFromLeaves(
+      newNotes.map(leafOf),
+      maxDepthSizePair.maxDepth
+    )
+    const updatedAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        bulkTree.publicKey
+      )
+    assert(updatedAccount.getCurrentRoot().equals(expectedRoot))
+  })
+
+  it("Verify Note", async () => {
+    const verifyTree = Keypair.generate()
+    await createNoteTree(verifyTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(verifyTree.publicKey))
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        verifyTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+    const accounts = {
+      owner: provider.publicKey,
+      merkleTree: verifyTree.publicKey,
+      adminConfig: noteAccounts(verifyTree.publicKey).adminConfig,
+    }
+
+    await program.methods
+      .verifyNote(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    try {
+      await program.methods
+        .verifyNote(0, root, updatedNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected verifying a different note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+
+    // Only one note was appended, so there is no leaf at index 1 to verify
+    try {
+      await program.methods
+        .verifyNote(1, root, firstNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected verifying past the last leaf to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "IndexOutOfRange")
+    }
+  })
+
+  it("Note Exists Returns One", async () => {
+    const existsTree = Keypair.generate()
+    await createNoteTree(existsTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(existsTree.publicKey))
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        existsTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+    const accounts = {
+      owner: provider.publicKey,
+      merkleTree: existsTree.publicKey,
+      adminConfig: noteAccounts(existsTree.publicKey).adminConfig,
+    }
+
+    // The return data is logged as base64 after the program id
+    const { raw } = await program.methods
+      .noteExists(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .simulate()
+    const returnLog = raw.find((log) =>
+      log.startsWith(`Program return: ${program.programId.toBase58()}`)
+    )
+    const returnData = Buffer.from(returnLog.split(" ")[3], "base64")
+    assert(returnData.equals(Buffer.from([1])))
+
+    try {
+      await program.methods
+        .noteExists(0, root, updatedNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected a note that doesn't exist to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+  })
+
+  it("Verify Notes Returns A Mask Of The Notes That Exist", async () => {
+    const pageTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(pageTree, { maxDepth, maxBufferSize: 8 }, 0)
+
+    const notes = ["first", "second", "third"]
+    await program.methods
+      .batchAppendNotes(notes)
+      .accounts(noteAccounts(pageTree.publicKey))
+      .rpc()
+    const leaves = notes.map((note) =>
+      Buffer.from(getHash(note, provider.publicKey, pageTree.publicKey), "hex")
+    )
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        pageTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // The proofs are concatenated, each item pointing at the start of its own
+    const items = notes.map((note, index) => ({
+      index,
+      root,
+      note: index === 1 ? updatedNote : note,
+      proofOffset: index * maxDepth,
+    }))
+    const proofs = notes.flatMap((_, index) =>
+      leafProof(leaves, maxDepth, index)
+    )
+
+    const verified = await program.methods
+      .verifyNotes(items)
+      .accounts({
+        owner: provider.publicKey,
+        merkleTree: pageTree.publicKey,
+        adminConfig: noteAccounts(pageTree.publicKey).adminConfig,
+      })
+      .remainingAccounts(proofs)
+      .view()
+
+    // Only the second item was given a note that isn't in the tree
+    assert(verified === 0b101)
+  })
+
+  it("Verify And Get Owner Only Returns The Owner Of The Note", async () => {
+    const ownerTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(ownerTree, { maxDepth, maxBufferSize: 8 }, 0)
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(ownerTree.publicKey))
+      .rpc()
+    const leaf = Buffer.from(
+      getHash(firstNote, provider.publicKey, ownerTree.publicKey),
+      "hex"
+    )
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        ownerTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+    const accounts = {
+      owner: provider.publicKey,
+      merkleTree: ownerTree.publicKey,
+      adminConfig: noteAccounts(ownerTree.publicKey).adminConfig,
+    }
+
+    const owner = await program.methods
+      .verifyAndGetOwner(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(leafProof([leaf], maxDepth, 0))
+      .view()
+    assert(owner.equals(provider.publicKey))
+
+    try {
+      await program.methods
+        .verifyAndGetOwner(0, root, firstNote)
+        .accounts({ ...accounts, owner: Keypair.generate().publicKey })
+        .remainingAccounts(leafProof([leaf], maxDepth, 0))
+        .rpc()
+      assert.fail("Expected the wrong candidate owner to fail verification")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+  })
+
+  it("Composed And Decomposed Notes Hash To The Same Leaf", async () => {
+    const unicodeTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(unicodeTree, { maxDepth, maxBufferSize: 8 }, 0)
+
+    // "café" in NFC, with a single "é", and in NFD, with "e" and a combining accent
+    const nfcNote = "caf\u00e9"
+    const nfdNote = "cafe\u0301"
+    assert(nfcNote !== nfdNote)
+
+    const accounts = noteAccounts(unicodeTree.publicKey)
+    const txSignature = await program.methods
+      .appendNote(nfdNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    // The note is hashed and logged in NFC
+    const noteLog = await getNoteLog(connection, txSignature)
+    const leaf = Buffer.from(
+      getHash(nfcNote, provider.publicKey, unicodeTree.publicKey),
+      "hex"
+    )
+    assert(Buffer.from(noteLog.leafNode).equals(leaf))
+    assert(noteLog.note === nfcNote)
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        unicodeTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+    for (const note of [nfcNote, nfdNote]) {
+      await program.methods
+        .verifyNote(0, root, note)
+        .accounts(accounts)
+        .remainingAccounts(leafProof([leaf], maxDepth, 0))
+        .rpc()
+    }
+  })
+
+  it("Title And Note Are Normalized Apart", async () => {
+    const unicodeTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(unicodeTree, { maxDepth, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(unicodeTree.publicKey)
+
+    // The combining accent starting the note doesn't compose with the "e"
+    // ending the title, since the end of the title is hashed between them
+    const txSignature = await program.methods
+      .appendTitledNote("cafe", "\u0301 au lait")
+      .accounts(accounts)
+      .rpc()
+    const noteLog = await getNoteLog(connection, txSignature)
+    const hash = getTitledHash(
+      "cafe",
+      "\u0301 au lait",
+      provider.publicKey,
+      unicodeTree.publicKey
+    )
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(
+      hash !==
+        getHash("caf\u00e9 au lait", provider.publicKey, unicodeTree.publicKey)
+    )
+  })
+
+  it("Titled Note Hash Marks The End Of The Title", async () => {
+    const titledTree = Keypair.generate()
+    await createNoteTree(titledTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(titledTree.publicKey)
+
+    // The same text split differently into a title and note
+    const leaves: string[] = []
+    for (const [title, note] of [
+      ["ab", "c"],
+      ["a", "bc"],
+      ["", "abc"],
+    ]) {
+      const txSignature = await program.methods
+        .appendTitledNote(title, note)
+        .accounts(accounts)
+        .rpc()
+      const noteLog = await getNoteLog(connection, txSignature)
+      const leaf = Buffer.from(noteLog.leafNode).toString("hex")
+      assert(
+        leaf ===
+          getTitledHash(title, note, provider.publicKey, titledTree.publicKey)
+      )
+      leaves.push(leaf)
+    }
+    assert(new Set(leaves).size === leaves.length)
+    // An empty title hashes the same as an untitled note
+    assert(
+      leaves[2] === getHash("abc", provider.publicKey, titledTree.publicKey)
+    )
+  })
+
+  it("Tree Authority Pda Matches The Tree", async () => {
+    const authorityTree = Keypair.generate()
+    await createNoteTree(authorityTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    // The tree was created with the authority Anchor checked for the create accounts
+    const [authority, bump] = getTreeAuthority(
+      program.programId,
+      authorityTree.publicKey
+    )
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        authorityTree.publicKey
+      )
+    assert(merkleTreeAccount.getAuthority().equals(authority))
+    assert(
+      PublicKey.createProgramAddressSync(
+        [authorityTree.publicKey.toBuffer(), Buffer.from([bump])],
+        program.programId
+      ).equals(authority)
+    )
+
+    // And Anchor accepts it as the tree authority of the note accounts
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts({
+        ...noteAccounts(authorityTree.publicKey),
+        treeAuthority: authority,
+      })
+      .rpc()
+  })
+
+  it("Emit Stats Matches The Tree", async () => {
+    const statsTree = Keypair.generate()
+    await createNoteTree(statsTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(statsTree.publicKey)
+    for (const note of [firstNote, secondNote, updatedNote]) {
+      await program.methods
+        .appendNote(note, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+    }
+
+    let listener: number
+    const event = new Promise<any>((resolve) => {
+      listener = program.addEventListener("TreeStats", (event) =>
+        resolve(event)
+      )
+    })
+    await program.methods
+      .emitStats()
+      .accounts({ merkleTree: statsTree.publicKey })
+      .rpc()
+    const stats = await event
+    await program.removeEventListener(listener)
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        statsTree.publicKey
+      )
+    assert(stats.tree.equals(statsTree.publicKey))
+    assert(Buffer.from(stats.root).equals(merkleTreeAccount.getCurrentRoot()))
+    assert(stats.rightmostIndex.toNumber() === 3)
+    assert(stats.maxDepth === 3)
+    assert(stats.bufferSize.eq(merkleTreeAccount.tree.bufferSize))
+    assert(stats.authority.equals(merkleTreeAccount.getAuthority()))
+    assert(stats.authority.equals(accounts.treeAuthority))
+  })
+
+  it("Get Tree Config Returns Creation Parameters", async () => {
+    const configTree = Keypair.generate()
+    await createNoteTree(configTree, { maxDepth: 5, maxBufferSize: 8 }, 2)
+
+    const { raw } = await program.methods
+      .getTreeConfig()
+      .accounts({ merkleTree: configTree.publicKey })
+      .simulate()
+    const returnLog = raw.find((log) =>
+      log.startsWith(`Program return: ${program.programId.toBase58()}`)
+    )
+    const config = Buffer.from(returnLog.split(" ")[3], "base64")
+    assert(config.length === 12)
+    assert(config.readUInt32LE(0) === 5)
+    assert(config.readUInt32LE(4) === 8)
+    assert(config.readUInt32LE(8) === 2)
+
+    // An account too short to hold a tree header
+    const truncatedTree = Keypair.generate()
+    const space = 16
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(
+        SystemProgram.createAccount({
+          fromPubkey: wallet.publicKey,
+          newAccountPubkey: truncatedTree.publicKey,
+          lamports: await connection.getMinimumBalanceForRentExemption(space),
+          space,
+          programId: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+      ),
+      [wallet.payer, truncatedTree]
+    )
+    try {
+      await program.methods
+        .getTreeConfig()
+        .accounts({ merkleTree: truncatedTree.publicKey })
+        .rpc()
+      assert.fail("Expected a truncated tree account to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "MalformedTreeAccount")
+    }
+  })
+
+  it("Compute Leaf Matches Appended Leaf", async () => {
+    const leafTree = Keypair.generate()
+    await createNoteTree(leafTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    const { raw } = await program.methods
+      .computeLeaf(firstNote)
+      .accounts({
+        owner: provider.publicKey,
+        merkleTree: leafTree.publicKey,
+        adminConfig: noteAccounts(leafTree.publicKey).adminConfig,
+      })
+      .simulate()
+    const returnLog = raw.find((log) =>
+      log.startsWith(`Program return: ${program.programId.toBase58()}`)
+    )
+    const leaf = Buffer.from(returnLog.split(" ")[3], "base64")
+
+    const txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(leafTree.publicKey))
+      .rpc()
+    const noteLog = await getNoteLog(connection, txSignature)
+
+    assert(leaf.equals(Buffer.from(noteLog.leafNode)))
+  })
+
+  it("Simulate Update Previews The Updated Root", async () => {
+    const previewTree = Keypair.generate()
+    await createNoteTree(previewTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(previewTree.publicKey)
+    const leaves = [firstNote, secondNote].map((note) =>
+      Buffer.from(
+        getHash(note, provider.publicKey, previewTree.publicKey),
+        "hex"
+      )
+    )
+    for (const note of [firstNote, secondNote]) {
+      await program.methods
+        .appendNote(note, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+    }
+    const currentRoot = async () =>
+      (
+        await ConcurrentMerkleTreeAccount.fromAccountAddress(
+          connection,
+          previewTree.publicKey
+        )
+      ).getCurrentRoot()
+    const root = Array.from(await currentRoot())
+
+    const { raw } = await program.methods
+      .simulateUpdate(1, root, secondNote, updatedNote)
+      .accounts({
+        owner: provider.publicKey,
+        merkleTree: previewTree.publicKey,
+        adminConfig: accounts.adminConfig,
+      })
+      .remainingAccounts(leafProof(leaves, 3, 1))
+      .simulate()
+    const returnLog = raw.find((log) =>
+      log.startsWith(`Program return: ${program.programId.toBase58()}`)
+    )
+    const previewRoot = Buffer.from(returnLog.split(" ")[3], "base64")
+
+    // The preview doesn't modify the tree
+    assert(Buffer.from(root).equals(await currentRoot()))
+
+    await program.methods
+      .updateNote(1, root, secondNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, 3, 1))
+      .rpc()
+    assert(previewRoot.equals(await currentRoot()))
+
+    // An update to the same note is rejected as by update_note
+    leaves[1] = Buffer.from(
+      getHash(updatedNote, provider.publicKey, previewTree.publicKey),
+      "hex"
+    )
+    const updatedRoot = Array.from(await currentRoot())
+    try {
+      await program.methods
+        .simulateUpdate(1, updatedRoot, updatedNote, updatedNote)
+        .accounts({
+          owner: provider.publicKey,
+          merkleTree: previewTree.publicKey,
+          adminConfig: accounts.adminConfig,
+        })
+        .remainingAccounts(leafProof(leaves, 3, 1))
+        .rpc()
+      assert.fail("Expected previewing an identical note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NotesAreIdentical")
+    }
+  })
+
+  it("Reemit Note Log Of Appended Note", async () => {
+    const reemitTree = Keypair.generate()
+    await createNoteTree(reemitTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    let txSignature = await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(reemitTree.publicKey))
+      .rpc()
+    const appendLog = await getNoteLog(connection, txSignature)
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        reemitTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+    const accounts = {
+      owner: provider.publicKey,
+      merkleTree: reemitTree.publicKey,
+      adminConfig: noteAccounts(reemitTree.publicKey).adminConfig,
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      logWrapperMirror: null,
+    }
+
+    txSignature = await program.methods
+      .reemitNoteLog(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+    const reemittedLog = await getNoteLog(connection, txSignature)
+
+    assert(
+      Buffer.from(reemittedLog.leafNode).equals(
+        Buffer.from(appendLog.leafNode)
+      )
+    )
+    assert(reemittedLog.owner.equals(provider.publicKey))
+    assert(reemittedLog.note === firstNote)
+    assert(reemittedLog.index.toNumber() === 0)
+
+    // A note that isn't in the tree can't be logged
+    try {
+      await program.methods
+        .reemitNoteLog(0, root, updatedNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected re-emitting a note not in the tree to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+  })
+
+  it("Prove Note Ownership Returns Leaf", async () => {
+    const provenTree = Keypair.generate()
+    await createNoteTree(provenTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(provenTree.publicKey))
+      .rpc()
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        provenTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    // Simulates the instruction and reads the leaf from its return data
+    const leaf = await program.methods
+      .proveNoteOwnership(0, root, firstNote)
+      .accounts({
+        owner: provider.publicKey,
+        merkleTree: provenTree.publicKey,
+        adminConfig: noteAccounts(provenTree.publicKey).adminConfig,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts(emptyProof(3))
+      .view()
+    const hash = getHash(firstNote, provider.publicKey, provenTree.publicKey)
+
+    assert(hash === Buffer.from(leaf).toString("hex"))
+  })
+
+  it("Append Unique Note Rejects Duplicates", async () => {
+    const uniqueTree = Keypair.generate()
+    await createNoteTree(uniqueTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    const [noteFilter] = PublicKey.findProgramAddressSync(
+      [Buffer.from("filter"), uniqueTree.publicKey.toBuffer()],
+      program.programId
+    )
+    await program.methods
+      .createNoteFilter()
+      .accounts({ merkleTree: uniqueTree.publicKey, noteFilter })
+      .rpc()
+
+    const accounts = { ...noteAccounts(uniqueTree.publicKey), noteFilter }
+    await program.methods.appendUniqueNote(firstNote).accounts(accounts).rpc()
+
+    try {
+      await program.methods
+        .appendUniqueNote(firstNote)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected duplicate note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "DuplicateNote")
+    }
+
+    // append_note doesn't use the filter
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(uniqueTree.publicKey))
+      .rpc()
+  })
+
+  it("Note Counter Tracks Appends And Deletes", async () => {
+    const countedTree = Keypair.generate()
+    await createNoteTree(countedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(countedTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    await program.methods
+      .appendNote(secondNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    let noteCounter = await program.account.noteCounter.fetch(
+      accounts.noteCounter
+    )
+    assert(noteCounter.count.toNumber() === 2)
+    assert(noteCounter.nextIndex.toNumber() === 2)
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        countedTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    await program.methods
+      .deleteNote(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    noteCounter = await program.account.noteCounter.fetch(accounts.noteCounter)
+    assert(noteCounter.count.toNumber() === 1)
+    assert(noteCounter.nextIndex.toNumber() === 2)
+  })
+
+  it("Add And Update Titled Note", async () => {
+    const titledTree = Keypair.generate()
+    await createNoteTree(titledTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(titledTree.publicKey)
+
+    const title = "title"
+    const newTitle = "new title"
+
+    let txSignature = await program.methods
+      .appendTitledNote(title, firstNote)
+      .accounts(accounts)
+      .rpc()
+
+    let noteLog = await getNoteLog(connection, txSignature)
+    let hash = getTitledHash(
+      title,
+      firstNote,
+      provider.publicKey,
+      titledTree.publicKey
+    )
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(title === noteLog.title)
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        titledTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    txSignature = await program.methods
+      .updateTitledNote(0, root, title, firstNote, newTitle, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    noteLog = await getNoteLog(connection, txSignature)
+    hash = getTitledHash(
+      newTitle,
+      firstNote,
+      provider.publicKey,
+      titledTree.publicKey
+    )
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(newTitle === noteLog.title)
+  })
+
+  it("Append Then Tag Note", async () => {
+    const tagTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    await createNoteTree(tagTree, maxDepthSizePair, 0)
+
+    // The proof of the first leaf only depends on the empty leaves around it
+    const leaf = Buffer.from(
+      getHash(firstNote, provider.publicKey, tagTree.publicKey),
+      "hex"
+    )
+    const remainingAccounts = leafProof([leaf], maxDepthSizePair.maxDepth, 0)
+
+    const title = "title"
+    const txSignature = await program.methods
+      .appendThenTag(firstNote, title)
+      .accounts(noteAccounts(tagTree.publicKey))
+      .remainingAccounts(remainingAccounts)
+      .rpc()
+
+    // The last log is the titled note
+    const noteLog = await getNoteLog(connection, txSignature)
+    const hash = getTitledHash(
+      title,
+      firstNote,
+      provider.publicKey,
+      tagTree.publicKey
+    )
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(title === noteLog.title)
+    assert(noteLog.index.toNumber() === 0)
+  })
+
+  it("Archive And Unarchive Note", async () => {
+    const archiveTree = Keypair.generate()
+    await createNoteTree(archiveTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(archiveTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const activeHash = getHash(
+      firstNote,
+      provider.publicKey,
+      archiveTree.publicKey
+    )
+    const archivedHash = getArchivedHash(
+      firstNote,
+      provider.publicKey,
+      archiveTree.publicKey
+    )
+    assert(activeHash !== archivedHash)
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        archiveTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    let txSignature = await program.methods
+      .archiveNote(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    let noteLog = await getNoteLog(connection, txSignature)
+    assert(archivedHash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(noteLog.status === 1)
+
+    txSignature = await program.methods
+      .unarchiveNote(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    noteLog = await getNoteLog(connection, txSignature)
+    assert(activeHash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(noteLog.status === 0)
+
+    txSignature = await program.methods
+      .archiveNote(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    noteLog = await getNoteLog(connection, txSignature)
+    assert(archivedHash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(noteLog.status === 1)
+
+    // The note is archived already, so its active leaf no longer proves
+    try {
+      await program.methods
+        .archiveNote(0, root, firstNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected archiving an archived note to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+  })
+
+  it("Add Tagged Note", async () => {
+    const taggedTree = Keypair.generate()
+    await createNoteTree(taggedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(taggedTree.publicKey)
+
+    const tags = ["work", "todo"]
+    const txSignature = await program.methods
+      .appendNote(firstNote, tags, null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    // Tags aren't part of the leaf hash
+    const hash = getHash(firstNote, provider.publicKey, taggedTree.publicKey)
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert.deepEqual(noteLog.tags, tags)
+
+    try {
+      await program.methods
+        .appendNote(firstNote, ["a", "b", "c", "d", "e", "f"], null, null, null)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected too many tags to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TooManyTags")
+    }
+
+    try {
+      await program.methods
+        .appendNote(firstNote, ["0".repeat(33)], null, null, null)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected an oversized tag to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TagTooLong")
+    }
+  })
+
+  it("Add Note Without Log", async () => {
+    const nologTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    await createNoteTree(nologTree, maxDepthSizePair, 0)
+
+    const txSignature = await program.methods
+      .appendNoteNolog(firstNote)
+      .accounts(noteAccounts(nologTree.publicKey))
+      .rpc()
+
+    // No NoteLog is logged, only the change log of the compression program
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog === undefined)
+
+    // The leaf is still appended
+    const leaf = Buffer.from(
+      getHash(firstNote, provider.publicKey, nologTree.publicKey),
+      "hex"
+    )
+    const { root } = MerkleTree.sparseMerkleTreeFromLeaves(
+      [leaf],
+      maxDepthSizePair.maxDepth
+    )
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        nologTree.publicKey
+      )
+    assert(merkleTreeAccount.getCurrentRoot().equals(root))
+  })
+
+  it("Add Compressed Note", async () => {
+    const compressedTree = Keypair.generate()
+    await createNoteTree(compressedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    // Run-length encode the note as (count, byte) pairs
+    const note = Buffer.from("a".repeat(300) + "b".repeat(20))
+    const pairs: number[] = []
+    for (let i = 0; i < note.length; ) {
+      let count = 1
+      while (
+        i + count < note.length &&
+        note[i + count] === note[i] &&
+        count < 255
+      ) {
+        count++
+      }
+      pairs.push(count, note[i])
+      i += count
+    }
+    const compressed = Buffer.from(pairs)
+
+    const txSignature = await program.methods
+      .appendCompressedNote(compressed, 0)
+      .accounts(noteAccounts(compressedTree.publicKey))
+      .rpc()
+
+    const noteLog = await getCompressedNoteLog(connection, txSignature)
+    assert(Buffer.from(noteLog.tag).equals(COMPRESSED_NOTE_LOG_TAG))
+    assert(noteLog.tree.equals(compressedTree.publicKey))
+    assert(noteLog.algo === 0)
+    assert(noteLog.data.equals(compressed))
+    // The compressed bytes are hashed as they are
+    const hash = getHash(
+      compressed,
+      provider.publicKey,
+      compressedTree.publicKey
+    )
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+
+    // Inflating the logged bytes gives back the note
+    const inflated: number[] = []
+    for (let i = 0; i < noteLog.data.length; i += 2) {
+      inflated.push(...Array(noteLog.data[i]).fill(noteLog.data[i + 1]))
+    }
+    assert(Buffer.from(inflated).equals(note))
+
+    try {
+      await program.methods
+        .appendCompressedNote(compressed, 7)
+        .accounts(noteAccounts(compressedTree.publicKey))
+        .rpc()
+      assert.fail("Expected an unknown compression algorithm to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "InvalidCompressionAlgo")
+    }
+  })
+
+  it("Add Precomputed Leaf", async () => {
+    const leafTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    await createNoteTree(leafTree, maxDepthSizePair, 0)
+
+    // Hashed off-chain, the note itself is never sent
+    const leaf = Buffer.from(
+      getHash(firstNote, provider.publicKey, leafTree.publicKey),
+      "hex"
+    )
+    const txSignature = await program.methods
+      .appendLeaf(Array.from(leaf))
+      .accounts(noteAccounts(leafTree.publicKey))
+      .rpc()
+
+    // The leaf is wrapped, bound to the signer and tree, before it is appended
+    const rawLeaf = Buffer.from(
+      getRawLeaf(leaf, provider.publicKey, leafTree.publicKey),
+      "hex"
+    )
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(Buffer.from(noteLog.leafNode).equals(rawLeaf))
+    assert(noteLog.owner.equals(provider.publicKey))
+    assert(noteLog.note === "")
+
+    const { root } = MerkleTree.sparseMerkleTreeFromLeaves(
+      [rawLeaf],
+      maxDepthSizePair.maxDepth
+    )
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        leafTree.publicKey
+      )
+    assert(merkleTreeAccount.getCurrentRoot().equals(root))
+  })
+
+  it("Delete Precomputed Leaf", async () => {
+    const leafTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(leafTree, { maxDepth, maxBufferSize: 8 }, 0)
+
+    const [ownerQuota] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("quota"),
+        leafTree.publicKey.toBuffer(),
+        provider.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+    const accounts = { ...noteAccounts(leafTree.publicKey), ownerQuota }
+    await program.methods.setOwnerQuota(true).accounts(accounts).rpc()
+    await program.methods
+      .createOwnerQuota()
+      .accounts({
+        owner: provider.publicKey,
+        merkleTree: leafTree.publicKey,
+        ownerQuota,
+      })
+      .rpc()
+
+    const leaf = Buffer.from(
+      getHash(firstNote, provider.publicKey, leafTree.publicKey),
+      "hex"
+    )
+    await program.methods
+      .appendLeaf(Array.from(leaf))
+      .accounts(accounts)
+      .rpc()
+    let quota = await program.account.ownerQuota.fetch(ownerQuota)
+    assert(quota.count.toNumber() === 1)
+
+    const rawLeaf = Buffer.from(
+      getRawLeaf(leaf, provider.publicKey, leafTree.publicKey),
+      "hex"
+    )
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        leafTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // The leaf is given as it was hashed off-chain, not wrapped
+    const txSignature = await program.methods
+      .deleteLeaf(0, root, Array.from(leaf))
+      .accounts(accounts)
+      .remainingAccounts(leafProof([rawLeaf], maxDepth, 0))
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(Buffer.from(noteLog.prevLeaf).equals(rawLeaf))
+    assert(
+      Buffer.from(noteLog.leafNode).toString("hex") ===
+        getDeletedLeaf(leafTree.publicKey)
+    )
+    quota = await program.account.ownerQuota.fetch(ownerQuota)
+    assert(quota.count.toNumber() === 0)
+  })
+
+  it("Precomputed Leaf Can't Forge A Note Of Another Owner", async () => {
+    const forgeryTree = Keypair.generate()
+    const maxDepth = 3
+    await createNoteTree(forgeryTree, { maxDepth, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(forgeryTree.publicKey)
+
+    // The wallet appends the leaf the victim's note would have
+    const victim = Keypair.generate().publicKey
+    const forgedLeaf = Buffer.from(
+      getHash(firstNote, victim, forgeryTree.publicKey),
+      "hex"
+    )
+    await program.methods
+      .appendLeaf(Array.from(forgedLeaf))
+      .accounts(accounts)
+      .rpc()
+    const rawLeaf = Buffer.from(
+      getRawLeaf(forgedLeaf, provider.publicKey, forgeryTree.publicKey),
+      "hex"
+    )
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        forgeryTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+    try {
+      await program.methods
+        .verifyAndGetOwner(0, root, firstNote)
+        .accounts({
+          owner: victim,
+          merkleTree: forgeryTree.publicKey,
+          adminConfig: accounts.adminConfig,
+        })
+        .remainingAccounts(leafProof([rawLeaf], maxDepth, 0))
+        .rpc()
+      assert.fail("Expected the forged leaf not to verify as the victim's note")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+  })
+
+  it("Owner Quota Limits Notes Until One Is Deleted", async () => {
+    const quotaTree = Keypair.generate()
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 5,
+      maxBufferSize: 8,
+    }
+    await createNoteTree(quotaTree, maxDepthSizePair, 0)
+
+    const [ownerQuota] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("quota"),
+        quotaTree.publicKey.toBuffer(),
+        provider.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+    const accounts = { ...noteAccounts(quotaTree.publicKey), ownerQuota }
+
+    await program.methods.setOwnerQuota(true).accounts(accounts).rpc()
+
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts(noteAccounts(quotaTree.publicKey))
+        .rpc()
+      assert.fail("Expected append without a quota account to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "OwnerQuotaMissing")
+    }
+
+    await program.methods
+      .createOwnerQuota()
+      .accounts({
+        owner: provider.publicKey,
+        merkleTree: quotaTree.publicKey,
+        ownerQuota,
+      })
+      .rpc()
+
+    // Fill the quota of 16 notes
+    const notes = Array.from({ length: 16 }, (_, i) => `note ${i}`)
+    await program.methods
+      .batchAppendNotes(notes.slice(0, 8))
+      .accounts(accounts)
+      .rpc()
+    await program.methods
+      .batchAppendNotes(notes.slice(8))
+      .accounts(accounts)
+      .rpc()
+
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected append past the quota to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "QuotaExceeded")
+    }
+
+    // More changes were made than the buffer holds, so delete against the current root
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        quotaTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+    const leaves = notes.map((note) =>
+      Buffer.from(
+        getHash(note, provider.publicKey, quotaTree.publicKey),
+        "hex"
+      )
+    )
+
+    await program.methods
+      .deleteNote(0, root, notes[0])
+      .accounts(accounts)
+      .remainingAccounts(leafProof(leaves, maxDepthSizePair.maxDepth, 0))
+      .rpc()
+
+    // Deleting the note freed a slot
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const quota = await program.account.ownerQuota.fetch(ownerQuota)
+    assert(quota.count.toNumber() === 16)
+  })
+
+  it("Owner Quota Can Only Be Turned On For An Empty Tree", async () => {
+    const quotaTree = Keypair.generate()
+    await createNoteTree(quotaTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(quotaTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    // The note was never counted, so deleting it would take the quota below zero
+    try {
+      await program.methods.setOwnerQuota(true).accounts(accounts).rpc()
+      assert.fail("Expected turning on quotas for a tree with notes to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TreeNotEmpty")
+    }
+
+    // Turning them off is always allowed
+    await program.methods.setOwnerQuota(false).accounts(accounts).rpc()
+  })
+
+  it("Transfer Note Moves It To The New Owner's Quota", async () => {
+    const quotaTree = Keypair.generate()
+    await createNoteTree(quotaTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    const newOwner = Keypair.generate().publicKey
+    const [ownerQuota, recipientQuota] = [provider.publicKey, newOwner].map(
+      (owner) =>
+        PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("quota"),
+            quotaTree.publicKey.toBuffer(),
+            owner.toBuffer(),
+          ],
+          program.programId
+        )[0]
+    )
+    const accounts = { ...noteAccounts(quotaTree.publicKey), ownerQuota }
+
+    await program.methods.setOwnerQuota(true).accounts(accounts).rpc()
+    for (const [owner, quota] of [
+      [provider.publicKey, ownerQuota],
+      [newOwner, recipientQuota],
+    ]) {
+      await program.methods
+        .createOwnerQuota()
+        .accounts({
+          owner,
+          merkleTree: quotaTree.publicKey,
+          ownerQuota: quota,
+        })
+        .rpc()
+    }
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        quotaTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+
+    // Without the new owner's quota, receiving notes would get around it
+    try {
+      await program.methods
+        .transferNote(0, root, firstNote, newOwner)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected transfer without the recipient quota to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "OwnerQuotaMissing")
+    }
+
+    try {
+      await program.methods
+        .transferNote(0, root, firstNote, newOwner)
+        .accounts({ ...accounts, recipientQuota: ownerQuota })
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected transfer with the sender's quota to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "WrongRecipientQuota")
+    }
+
+    await program.methods
+      .transferNote(0, root, firstNote, newOwner)
+      .accounts({ ...accounts, recipientQuota })
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    const senderQuota = await program.account.ownerQuota.fetch(ownerQuota)
+    const newOwnerQuota = await program.account.ownerQuota.fetch(
+      recipientQuota
+    )
+    assert(senderQuota.count.toNumber() === 0)
+    assert(newOwnerQuota.count.toNumber() === 1)
+  })
+
+  it("Allowlist Limits Appends To Allowed Owners", async () => {
+    const allowlistTree = Keypair.generate()
+    await createNoteTree(allowlistTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+
+    const blockedOwner = Keypair.generate()
+    const [allowedOwner] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("allowed"),
+        allowlistTree.publicKey.toBuffer(),
+        provider.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+    const accounts = { ...noteAccounts(allowlistTree.publicKey), allowedOwner }
+
+    await program.methods.setAllowlist(true).accounts(accounts).rpc()
+    await program.methods
+      .addToAllowlist()
+      .accounts({
+        owner: provider.publicKey,
+        merkleTree: allowlistTree.publicKey,
+        adminConfig: accounts.adminConfig,
+        allowedOwner,
+      })
+      .rpc()
+
+    // An allowed owner can append
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    // An owner without an allowlist entry can't
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts({
+          ...noteAccounts(allowlistTree.publicKey),
+          owner: blockedOwner.publicKey,
+        })
+        .signers([blockedOwner])
+        .rpc()
+      assert.fail("Expected append by a blocked owner to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "OwnerNotAllowed")
+    }
+
+    // Removing the owner closes their entry, and they can no longer append
+    await program.methods
+      .removeFromAllowlist()
+      .accounts({
+        owner: provider.publicKey,
+        merkleTree: allowlistTree.publicKey,
+        adminConfig: accounts.adminConfig,
+        allowedOwner,
+      })
+      .rpc()
+    try {
+      await program.methods
+        .appendNote(firstNote, [], null, null, null)
+        .accounts(noteAccounts(allowlistTree.publicKey))
+        .rpc()
+      assert.fail("Expected append by a removed owner to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "OwnerNotAllowed")
+    }
+  })
+
+  it("Renounced Admin Can No Longer Administer The Tree", async () => {
+    const lockedTree = Keypair.generate()
+    await createNoteTree(lockedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(lockedTree.publicKey)
+
+    await program.methods.renounceAdmin().accounts(accounts).rpc()
+    const adminConfig = await program.account.adminConfig.fetch(
+      accounts.adminConfig
+    )
+    assert(adminConfig.renounced)
+    assert(adminConfig.admin.equals(SystemProgram.programId))
+
+    // The former admin is rejected like any other key
+    try {
+      await program.methods.setPaused(true).accounts(accounts).rpc()
+      assert.fail("Expected an admin action after renouncing to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "Unauthorized")
+    }
+
+    // Appends are unaffected
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+  })
+
+  it("Paused Tree Rejects Appends Until Unpaused", async () => {
+    const pausedTree = Keypair.generate()
+    await createNoteTree(pausedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(pausedTree.publicKey)
+
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+    await program.methods.setPaused(true).accounts(accounts).rpc()
+
+    try {
+      await program.methods
+        .appendNote(secondNote, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected append to a paused tree to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProgramPaused")
+    }
+
+    // Verifying stays allowed while paused
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        pausedTree.publicKey
+      )
+    const root = Array.from(merkleTreeAccount.getCurrentRoot())
+    await program.methods
+      .verifyNote(0, root, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    await program.methods.setPaused(false).accounts(accounts).rpc()
+    await program.methods
+      .appendNote(secondNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+  })
+
+  it("Append Fee Is Paid To The Treasury", async () => {
+    const feeTree = Keypair.generate()
+    await createNoteTree(feeTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(feeTree.publicKey)
+
+    // Without a fee, appends don't need a treasury
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    // The fee covers rent exemption, so it can fund a new treasury account
+    const treasury = Keypair.generate().publicKey
+    const feeLamports = await connection.getMinimumBalanceForRentExemption(0)
+    await program.methods
+      .setFee(new anchor.BN(feeLamports), treasury)
+      .accounts(accounts)
+      .rpc()
+
+    try {
+      await program.methods
+        .appendNote(secondNote, [], null, null, null)
+        .accounts(accounts)
+        .rpc()
+      assert.fail("Expected append without the treasury to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TreasuryMissing")
+    }
+
+    await program.methods
+      .appendNote(secondNote, [], null, null, null)
+      .accounts({ ...accounts, treasury })
+      .rpc()
+    assert((await connection.getBalance(treasury)) === feeLamports)
+
+    // Setting the fee back to zero stops charging
+    await program.methods
+      .setFee(new anchor.BN(0), treasury)
+      .accounts(accounts)
+      .rpc()
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts({ ...accounts, treasury })
+      .rpc()
+    assert((await connection.getBalance(treasury)) === feeLamports)
+  })
+
+  it("Append Unique Note Pays The Append Fee", async () => {
+    const feeTree = Keypair.generate()
+    await createNoteTree(feeTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const [noteFilter] = PublicKey.findProgramAddressSync(
+      [Buffer.from("filter"), feeTree.publicKey.toBuffer()],
+      program.programId
+    )
+    await program.methods
+      .createNoteFilter()
+      .accounts({ merkleTree: feeTree.publicKey, noteFilter })
+      .rpc()
+    const accounts = { ...noteAccounts(feeTree.publicKey), noteFilter }
+
+    const treasury = Keypair.generate().publicKey
+    const feeLamports = await connection.getMinimumBalanceForRentExemption(0)
+    await program.methods
+      .setFee(new anchor.BN(feeLamports), treasury)
+      .accounts(accounts)
+      .rpc()
+
+    try {
+      await program.methods.appendUniqueNote(firstNote).accounts(accounts).rpc()
+      assert.fail("Expected append without the treasury to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "TreasuryMissing")
+    }
+
+    await program.methods
+      .appendUniqueNote(firstNote)
+      .accounts({ ...accounts, treasury })
+      .rpc()
+    assert((await connection.getBalance(treasury)) === feeLamports)
+  })
+
+  it("Append Note For An Owner Charges The Payer The Append Fee", async () => {
+    const feeTree = Keypair.generate()
+    await createNoteTree(feeTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(feeTree.publicKey)
+
+    const treasury = Keypair.generate().publicKey
+    const feeLamports = await connection.getMinimumBalanceForRentExemption(0)
+    await program.methods
+      .setFee(new anchor.BN(feeLamports), treasury)
+      .accounts(accounts)
+      .rpc()
+
+    const relayer = Keypair.generate()
+    const airdropSignature = await connection.requestAirdrop(
+      relayer.publicKey,
+      anchor.web3.LAMPORTS_PER_SOL
+    )
+    await connection.confirmTransaction(airdropSignature)
+
+    await program.methods
+      .appendNoteFor(firstNote, Keypair.generate().publicKey)
+      .accounts({ ...accounts, payer: relayer.publicKey, treasury })
+      .signers([relayer])
+      .rpc()
+    assert((await connection.getBalance(treasury)) === feeLamports)
+    // The relayer paid both the append fee and the transaction fee
+    assert(
+      (await connection.getBalance(relayer.publicKey)) <=
+        anchor.web3.LAMPORTS_PER_SOL - feeLamports
+    )
+  })
+
+  it("Fee Overflowing A u64 Fails Gracefully", async () => {
+    const overflowTree = Keypair.generate()
+    await createNoteTree(overflowTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(overflowTree.publicKey)
+    const treasury = Keypair.generate().publicKey
+
+    // The fee for a single note is u64::MAX, so the fee for two overflows
+    await program.methods
+      .setFee(new anchor.BN("18446744073709551615"), treasury)
+      .accounts(accounts)
+      .rpc()
+
+    try {
+      await program.methods
+        .batchAppendNotes([firstNote, firstNote])
+        .accounts({ ...accounts, treasury })
+        .rpc()
+      assert.fail("Expected a fee overflowing a u64 to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ArithmeticOverflow")
+    }
+  })
+
+  it("Add And Update Byte Note", async () => {
+    const bytesTree = Keypair.generate()
+    await createNoteTree(bytesTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(bytesTree.publicKey)
+
+    // Not valid UTF-8, and includes a null byte
+    const data = Buffer.from([0xff, 0x00, 0xfe, 0x01])
+    const newData = Buffer.from([0x00, 0x00, 0xc3])
+
+    let txSignature = await program.methods
+      .appendNoteBytes(data)
+      .accounts(accounts)
+      .rpc()
+
+    let rawNoteLog = await getRawNoteLog(connection, txSignature)
+    let hash = getHash(data, provider.publicKey, bytesTree.publicKey)
+
+    assert(hash === Buffer.from(rawNoteLog.leafNode).toString("hex"))
+    assert(rawNoteLog.tree.equals(bytesTree.publicKey))
+    assert(rawNoteLog.data.equals(data))
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        bytesTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    txSignature = await program.methods
+      .updateNoteBytes(0, root, data, newData)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    rawNoteLog = await getRawNoteLog(connection, txSignature)
+    hash = getHash(newData, provider.publicKey, bytesTree.publicKey)
+
+    assert(hash === Buffer.from(rawNoteLog.leafNode).toString("hex"))
+    assert(rawNoteLog.tree.equals(bytesTree.publicKey))
+    assert(rawNoteLog.data.equals(newData))
+  })
+
+  it("Delegate Updates Note With Owner Signature", async () => {
+    const delegatedTree = Keypair.generate()
+    await createNoteTree(delegatedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(delegatedTree.publicKey))
+      .rpc()
+
+    const oldLeaf = getHash(
+      firstNote,
+      provider.publicKey,
+      delegatedTree.publicKey
+    )
+    const newLeaf = getHash(
+      updatedNote,
+      provider.publicKey,
+      delegatedTree.publicKey
+    )
+    const message = getDelegatedUpdateMessage(
+      delegatedTree.publicKey,
+      0,
+      Buffer.from(oldLeaf, "hex"),
+      Buffer.from(newLeaf, "hex"),
+      0
+    )
+    // The owner signs the update, the signature is stored after the public key
+    const ed25519Ix = Ed25519Program.createInstructionWithPrivateKey({
+      privateKey: wallet.payer.secretKey,
+      message,
+    })
+    const ownerSig = Array.from(ed25519Ix.data.subarray(48, 112))
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        delegatedTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    const delegate = Keypair.generate()
+    await program.methods
+      .updateNoteDelegated(
+        0,
+        root,
+        firstNote,
+        updatedNote,
+        ownerSig,
+        provider.publicKey
+      )
+      .accounts(
+        delegatedUpdateAccounts(delegatedTree.publicKey, delegate.publicKey)
+      )
+      .remainingAccounts(emptyProof(3))
+      .preInstructions([ed25519Ix])
+      .signers([delegate])
+      .rpc()
+
+    const updatedAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        delegatedTree.publicKey
+      )
+    const { root: expectedRoot } = MerkleTree.sparseMerkleTreeFromLeaves(
+      [Buffer.from(newLeaf, "hex")],
+      3
+    )
+    // The updated leaf is still bound to the owner
+    assert(updatedAccount.getCurrentRoot().equals(expectedRoot))
+  })
+
+  it("Delegated Update Signature Can't Be Replayed", async () => {
+    const delegatedTree = Keypair.generate()
+    await createNoteTree(delegatedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const accounts = noteAccounts(delegatedTree.publicKey)
+    const currentRoot = async () =>
+      Array.from(
+        (
+          await ConcurrentMerkleTreeAccount.fromAccountAddress(
+            connection,
+            delegatedTree.publicKey
+          )
+        ).getCurrentRoot()
+      )
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(accounts)
+      .rpc()
+
+    const leafOf = (note: string) =>
+      Buffer.from(
+        getHash(note, provider.publicKey, delegatedTree.publicKey),
+        "hex"
+      )
+    const ed25519Ix = Ed25519Program.createInstructionWithPrivateKey({
+      privateKey: wallet.payer.secretKey,
+      message: getDelegatedUpdateMessage(
+        delegatedTree.publicKey,
+        0,
+        leafOf(firstNote),
+        leafOf(updatedNote),
+        0
+      ),
+    })
+    const ownerSig = Array.from(ed25519Ix.data.subarray(48, 112))
+    const delegate = Keypair.generate()
+    const delegatedUpdate = async () =>
+      program.methods
+        .updateNoteDelegated(
+          0,
+          await currentRoot(),
+          firstNote,
+          updatedNote,
+          ownerSig,
+          provider.publicKey
+        )
+        .accounts(
+          delegatedUpdateAccounts(delegatedTree.publicKey, delegate.publicKey)
+        )
+        .remainingAccounts(emptyProof(3))
+        .preInstructions([ed25519Ix])
+        .signers([delegate])
+        .rpc()
+
+    await delegatedUpdate()
+    const { delegateNonce } = delegatedUpdateAccounts(
+      delegatedTree.publicKey,
+      delegate.publicKey
+    )
+    const { nonce } = await program.account.delegateNonce.fetch(delegateNonce)
+    assert(nonce.toNumber() === 1)
+
+    // The owner changes the note back, so the old leaf and root are as they were when signed
+    await program.methods
+      .updateNote(0, await currentRoot(), updatedNote, firstNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    try {
+      await delegatedUpdate()
+      assert.fail("Expected the replayed signature to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "InvalidOwnerSignature")
+    }
+  })
+
+  it("Delegated Update With Forged Signature Fails", async () => {
+    const delegatedTree = Keypair.generate()
+    await createNoteTree(delegatedTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    await program.methods
+      .appendNote(firstNote, [], null, null, null)
+      .accounts(noteAccounts(delegatedTree.publicKey))
+      .rpc()
+
+    const message = getDelegatedUpdateMessage(
+      delegatedTree.publicKey,
+      0,
+      Buffer.from(
+        getHash(firstNote, provider.publicKey, delegatedTree.publicKey),
+        "hex"
+      ),
+      Buffer.from(
+        getHash(updatedNote, provider.publicKey, delegatedTree.publicKey),
+        "hex"
+      ),
+      0
+    )
+    // The delegate signs the update themselves instead of the owner
+    const delegate = Keypair.generate()
+    const ed25519Ix = Ed25519Program.createInstructionWithPrivateKey({
+      privateKey: delegate.secretKey,
+      message,
+    })
+    const forgedSig = Array.from(ed25519Ix.data.subarray(48, 112))
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        delegatedTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    try {
+      await program.methods
+        .updateNoteDelegated(
+          0,
+          root,
+          firstNote,
+          updatedNote,
+          forgedSig,
+          provider.publicKey
+        )
+        .accounts(
+          delegatedUpdateAccounts(delegatedTree.publicKey, delegate.publicKey)
+        )
+        .remainingAccounts(emptyProof(3))
+        .preInstructions([ed25519Ix])
+        .signers([delegate])
+        .rpc()
+      assert.fail("Expected update with a forged signature to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "InvalidOwnerSignature")
+    }
+  })
+
+  it("Upgrade Admin Config From Its First Layout", async () => {
+    // Loaded by the validator from tests/fixtures, with only the discriminator and admin
+    const legacyAdmin = Keypair.fromSecretKey(
+      Uint8Array.from(
+        JSON.parse(readFileSync("tests/fixtures/legacy-admin.json", "utf8"))
+      )
+    )
+    const legacyTree = new PublicKey(
+      "8Yk2YhAmEzvneoGP346C5s2sPQT48FRNDb5MibZDVKoD"
+    )
+    const [adminConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("admin"), legacyTree.toBuffer()],
+      program.programId
+    )
+    let accountInfo = await connection.getAccountInfo(adminConfig)
+    assert(accountInfo.data.length === 8 + 32)
+
+    const airdropSignature = await connection.requestAirdrop(
+      legacyAdmin.publicKey,
+      anchor.web3.LAMPORTS_PER_SOL
+    )
+    await connection.confirmTransaction(airdropSignature)
+
+    try {
+      await program.methods
+        .upgradeAdminConfig()
+        .accounts({ admin: provider.publicKey, merkleTree: legacyTree })
+        .rpc()
+      assert.fail("Expected an upgrade by another admin to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "Unauthorized")
+    }
+
+    await program.methods
+      .upgradeAdminConfig()
+      .accounts({ admin: legacyAdmin.publicKey, merkleTree: legacyTree })
+      .signers([legacyAdmin])
+      .rpc()
+
+    accountInfo = await connection.getAccountInfo(adminConfig)
+    assert(accountInfo.data.length === 117)
+    const config = await program.account.adminConfig.fetch(adminConfig)
+    assert(config.admin.equals(legacyAdmin.publicKey))
+    assert(config.hashAlgo === 0)
+    assert(!config.quotaEnabled)
+    assert(!config.allowlistEnabled)
+    assert(!config.paused)
+    assert(config.feeLamports.toNumber() === 0)
+    assert(config.treasury.equals(PublicKey.default))
+    assert(!config.renounced)
+    assert(config.logMirror.equals(PublicKey.default))
+  })
+
+  it("Relayer Appends Note For Owner", async () => {
+    const relayer = Keypair.generate()
+    const noteOwner = Keypair.generate().publicKey
+
+    const airdropSignature = await connection.requestAirdrop(
+      relayer.publicKey,
+      anchor.web3.LAMPORTS_PER_SOL
+    )
+    await connection.confirmTransaction(airdropSignature)
+
+    const txSignature = await program.methods
+      .appendNoteFor(firstNote, noteOwner)
+      .accounts({
+        ...noteAccounts(merkleTree.publicKey),
+        payer: relayer.publicKey,
+      })
+      .signers([relayer])
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    const hash = getHash(firstNote, noteOwner, merkleTree.publicKey)
+
+    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    // The note belongs to its owner, and the relayer is recorded as appending it
+    assert(noteLog.owner.equals(noteOwner))
+    assert(noteLog.appendedBy.equals(relayer.publicKey))
+
+    // A note appended by its owner records the owner as both
+    const ownLog = await getNoteLog(
+      connection,
+      await program.methods
+        .appendNote(secondNote, [], null, null, null)
+        .accounts(noteAccounts(merkleTree.publicKey))
+        .rpc()
+    )
+    assert(ownLog.owner.equals(provider.publicKey))
+    assert(ownLog.appendedBy.equals(provider.publicKey))
+  })
+
+  it("Send And Update Message", async () => {
+    const messagesTree = Keypair.generate()
+    const recipient = Keypair.generate().publicKey
+    const maxDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      messagesTree.publicKey,
+      wallet.publicKey,
+      maxDepthSizePair,
+      0
+    )
+    const createMessagesTreeIx = await program.methods
+      .createMessagesTree(
+        maxDepthSizePair.maxDepth,
+        maxDepthSizePair.maxBufferSize,
+        0
+      )
+      .accounts({
+        merkleTree: messagesTree.publicKey,
+        treeAuthority: getMessagesAuthority(
+          program.programId,
+          messagesTree.publicKey
+        )[0],
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .instruction()
+    await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(allocTreeIx, createMessagesTreeIx),
+      [wallet.payer, messagesTree]
+    )
+
+    const accounts = {
+      recipient,
+      merkleTree: messagesTree.publicKey,
+      treeAuthority: getMessagesAuthority(
+        program.programId,
+        messagesTree.publicKey
+      )[0],
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+    }
+
+    let txSignature = await program.methods
+      .appendMessage(firstNote)
+      .accounts(accounts)
+      .rpc()
+
+    let messageLog = await getMessageLog(connection, txSignature)
+    let hash = getMessageHash(
+      firstNote,
+      provider.publicKey,
+      recipient,
+      messagesTree.publicKey
+    )
+
+    assert(hash === Buffer.from(messageLog.leafNode).toString("hex"))
+    assert(messageLog.version === CURRENT_LOG_VERSION)
+    assert(Buffer.from(messageLog.tag).equals(MESSAGE_LOG_TAG))
+    assert(messageLog.sender.equals(provider.publicKey))
+    assert(messageLog.recipient.equals(recipient))
+    assert(firstNote === messageLog.message)
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        messagesTree.publicKey
+      )
+    const root = Array.from(
+      merkleTreeAccount.tree.changeLogs[0].root.toBuffer()
+    )
+
+    txSignature = await program.methods
+      .updateMessage(0, root, firstNote, updatedNote)
+      .accounts(accounts)
+      .remainingAccounts(emptyProof(3))
+      .rpc()
+
+    messageLog = await getMessageLog(connection, txSignature)
+    hash = getMessageHash(
+      updatedNote,
+      provider.publicKey,
+      recipient,
+      messagesTree.publicKey
+    )
+
+    assert(hash === Buffer.from(messageLog.leafNode).toString("hex"))
+    assert(updatedNote === messageLog.message)
+
+    // The message was updated, so its old leaf no longer proves
+    try {
+      await program.methods
+        .updateMessage(0, root, firstNote, updatedNote)
+        .accounts(accounts)
+        .remainingAccounts(emptyProof(3))
+        .rpc()
+      assert.fail("Expected updating a replaced message to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ProofVerificationFailed")
+    }
+  })
+
+  it("Append Message With Spoofed Log Wrapper Fails", async () => {
+    // Account validation fails before the tree is touched, so it needn't exist
+    const messagesTree = Keypair.generate().publicKey
+
+    try {
+      await program.methods
+        .appendMessage(firstNote)
+        .accounts({
+          recipient: Keypair.generate().publicKey,
+          merkleTree: messagesTree,
+          treeAuthority: getMessagesAuthority(
+            program.programId,
+            messagesTree
+          )[0],
+          // Any program other than noop, which could capture the logged data
+          logWrapper: SystemProgram.programId,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+      assert.fail("Expected a log wrapper other than noop to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "InvalidProgramId")
+      assert(err.error.origin === "log_wrapper")
+    }
+  })
+
+  it("Append Message With Mismatched Tree Authority Fails", async () => {
+    const messagesTree = Keypair.generate().publicKey
+    // The authority of a different tree
+    const otherTree = Keypair.generate().publicKey
+
+    try {
+      await program.methods
+        .appendMessage(firstNote)
+        .accounts({
+          recipient: Keypair.generate().publicKey,
+          merkleTree: messagesTree,
+          treeAuthority: getMessagesAuthority(program.programId, otherTree)[0],
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+      assert.fail("Expected the authority of another tree to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ConstraintSeeds")
+      assert(err.error.origin === "tree_authority")
+    }
+  })
+
+  it("Append Message To A Note Tree Fails", async () => {
+    const accounts = {
+      recipient: Keypair.generate().publicKey,
+      merkleTree: merkleTree.publicKey,
+      treeAuthority: getMessagesAuthority(
+        program.programId,
+        merkleTree.publicKey
+      )[0],
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+    }
+
+    // A note tree isn't signed for by its messages authority
+    try {
+      await program.methods.appendMessage(firstNote).accounts(accounts).rpc()
+      assert.fail("Expected appending a message to a note tree to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "NotOurTree")
+    }
+
+    // Nor can its note tree authority be passed in its place
+    try {
+      await program.methods
+        .appendMessage(firstNote)
+        .accounts({ ...accounts, treeAuthority })
+        .rpc()
+      assert.fail("Expected the note tree authority to fail")
+    } catch (err) {
+      assert(err instanceof anchor.AnchorError)
+      assert(err.error.errorCode.code === "ConstraintSeeds")
+      assert(err.error.origin === "tree_authority")
+    }
+  })
+
+  it("Append A Long Note Assembled Across Two Writes", async () => {
+    const scratchTree = Keypair.generate()
+    await createNoteTree(scratchTree, { maxDepth: 3, maxBufferSize: 8 }, 0)
+    const [noteScratch] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("scratch"),
+        scratchTree.publicKey.toBuffer(),
+        provider.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+
+    // 2KB of note, too long for a single transaction
+    const firstChunk = Buffer.alloc(1024, "a")
+    const secondChunk = Buffer.alloc(1024, "b")
+    for (const chunk of [firstChunk, secondChunk]) {
+      await program.methods
+        .writeNoteScratch(chunk)
+        .accounts({
+          owner: provider.publicKey,
+          merkleTree: scratchTree.publicKey,
+          noteScratch,
+        })
+        .rpc()
+    }
+
+    const txSignature = await program.methods
+      .appendNoteFromAccount()
+      .accounts({ ...noteAccounts(scratchTree.publicKey), noteScratch })
+      .rpc()
+
+    const note = Buffer.concat([firstChunk, secondChunk])
+    const rawNoteLog = await getRawNoteLog(connection, txSignature)
+    const hash = getHash(note, provider.publicKey, scratchTree.publicKey)
+    assert(hash === Buffer.from(rawNoteLog.leafNode).toString("hex"))
+    assert(rawNoteLog.data.equals(note))
+
+    const merkleTreeAccount =
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        scratchTree.publicKey
+      )
+    const leaf = merkleTreeAccount.tree.rightMostPath.leaf.toBuffer()
+    assert(leaf.toString("hex") === hash)
+
+    // The scratch account is closed once the note is appended
+    assert((await connection.getAccountInfo(noteScratch)) === null)
   })
 })
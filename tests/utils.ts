@@ -3,23 +3,86 @@ import {
   deserializeApplicationDataEvent,
 } from "@solana/spl-account-compression"
 import { Connection, PublicKey } from "@solana/web3.js"
+import { BN } from "@coral-xyz/anchor"
 import { bs58 } from "@coral-xyz/anchor/dist/cjs/utils/bytes"
 import { deserialize } from "borsh"
 import { keccak256 } from "js-sha3"
+import { createHash } from "crypto"
+
+// Domain tags the program prepends to the leaf preimages
+export const NOTE_LEAF_TAG = Buffer.from("note:v1")
+export const TITLED_NOTE_LEAF_TAG = Buffer.from("titled-note:v1")
+export const MESSAGE_LEAF_TAG = Buffer.from("message:v1")
+export const DELETED_LEAF_TAG = Buffer.from("deleted:v1")
+export const EXPIRING_NOTE_LEAF_TAG = Buffer.from("expiring-note:v1")
+export const NONCED_NOTE_LEAF_TAG = Buffer.from("nonced-note:v1")
+export const RAW_LEAF_TAG = Buffer.from("leaf:v1")
+
+// Version of the log layout, the first byte of every log the program writes
+export const CURRENT_LOG_VERSION = 18
+
+// Tags following the version byte of every log, one per kind of log
+export const NOTE_LOG_TAG = Buffer.from("note-log")
+export const RAW_NOTE_LOG_TAG = Buffer.from("raw-note")
+export const COMPRESSED_NOTE_LOG_TAG = Buffer.from("zip-note")
+export const MESSAGE_LOG_TAG = Buffer.from("message\0")
+export const TRANSFER_LOG_TAG = Buffer.from("transfer")
 
 class NoteLog {
+  version: number
+  tag: Uint8Array
   leafNode: Uint8Array
   owner: PublicKey
+  tree: PublicKey
   note: string
+  createdAt: BN
+  index: BN
+  title: string
+  tags: string[]
+  status: number
+  prevLeaf: Uint8Array
+  parentIndex: BN | null
+  contentType: string
+  expiresAt: BN | null
+  appendedBy: PublicKey
+  nonce: BN | null
 
   constructor(properties: {
+    version: number
+    tag: Uint8Array
     leafNode: Uint8Array
     owner: Uint8Array
+    tree: Uint8Array
     note: string
+    createdAt: BN
+    index: BN
+    title: string
+    tags: string[]
+    status: number
+    prevLeaf: Uint8Array
+    parentIndex: BN | null
+    contentType: string
+    expiresAt: BN | null
+    appendedBy: Uint8Array
+    nonce: BN | null
   }) {
+    this.version = properties.version
+    this.tag = properties.tag
     this.leafNode = properties.leafNode
     this.owner = new PublicKey(properties.owner)
+    this.tree = new PublicKey(properties.tree)
     this.note = properties.note
+    this.createdAt = properties.createdAt
+    this.index = properties.index
+    this.title = properties.title
+    this.tags = properties.tags
+    this.status = properties.status
+    this.prevLeaf = properties.prevLeaf
+    this.parentIndex = properties.parentIndex
+    this.contentType = properties.contentType
+    this.expiresAt = properties.expiresAt
+    this.appendedBy = new PublicKey(properties.appendedBy)
+    this.nonce = properties.nonce
   }
 }
 
@@ -30,27 +93,462 @@ const NoteLogBorshSchema = new Map([
     {
       kind: "struct",
       fields: [
+        ["version", "u8"],
+        ["tag", [8]], // NOTE_LOG_TAG
         ["leafNode", [32]], // Array of 32 `u8`
         ["owner", [32]], // Pubkey
+        ["tree", [32]], // Pubkey
         ["note", "string"],
+        ["createdAt", "u64"], // i64 on-chain, always positive
+        ["index", "u64"],
+        ["title", "string"],
+        ["tags", ["string"]], // Vec<String>
+        ["status", "u8"], // 0 = active, 1 = archived
+        ["prevLeaf", [32]], // The replaced leaf, zero for appends
+        ["parentIndex", { kind: "option", type: "u64" }], // The note replied to
+        ["contentType", "string"], // MIME hint, "text/plain" by default
+        ["expiresAt", { kind: "option", type: "u64" }], // i64 on-chain, always positive
+        ["appendedBy", [32]], // Pubkey of the signer, the owner unless relayed
+        ["nonce", { kind: "option", type: "u64" }], // Set for nonced notes
       ],
     },
   ],
 ])
 
-export function getHash(note: string, owner: PublicKey) {
-  const noteBuffer = Buffer.from(note)
+// The leaf of a note, hashed with the hash selected for the tree, keccak by default
+export function getHash(
+  note: string | Buffer,
+  owner: PublicKey,
+  tree: PublicKey,
+  hashAlgo: "keccak" | "sha256" = "keccak"
+) {
+  const noteBuffer = typeof note === "string" ? Buffer.from(note) : note
   const publicKeyBuffer = Buffer.from(owner.toBytes())
-  const concatenatedBuffer = Buffer.concat([noteBuffer, publicKeyBuffer])
+  const treeBuffer = Buffer.from(tree.toBytes())
+  const concatenatedBuffer = Buffer.concat([
+    NOTE_LEAF_TAG,
+    noteBuffer,
+    publicKeyBuffer,
+    treeBuffer,
+  ])
   const concatenatedUint8Array = new Uint8Array(
     concatenatedBuffer.buffer,
     concatenatedBuffer.byteOffset,
     concatenatedBuffer.byteLength
   )
+  if (hashAlgo === "sha256") {
+    return createHash("sha256").update(concatenatedUint8Array).digest("hex")
+  }
   return keccak256(concatenatedUint8Array)
 }
 
-export async function getNoteLog(connection: Connection, txSignature: string) {
+// The tree authority pda of a merkle tree and its bump, mirroring tree_authority_pda in the program
+export function getTreeAuthority(programId: PublicKey, tree: PublicKey) {
+  return PublicKey.findProgramAddressSync([tree.toBuffer()], programId)
+}
+
+// The authority pda of a messages tree and its bump, mirroring messages_authority_pda in the program
+export function getMessagesAuthority(programId: PublicKey, tree: PublicKey) {
+  return PublicKey.findProgramAddressSync(
+    [Buffer.from("messages"), tree.toBuffer()],
+    programId
+  )
+}
+
+// The leaf written in place of a deleted note in the tree
+export function getDeletedLeaf(tree: PublicKey) {
+  const concatenatedBuffer = Buffer.concat([
+    DELETED_LEAF_TAG,
+    Buffer.from(tree.toBytes()),
+  ])
+  return keccak256(
+    new Uint8Array(
+      concatenatedBuffer.buffer,
+      concatenatedBuffer.byteOffset,
+      concatenatedBuffer.byteLength
+    )
+  )
+}
+
+// The leaf append_leaf appends for a leaf hashed off-chain, bound to the signer and tree
+export function getRawLeaf(leaf: Buffer, owner: PublicKey, tree: PublicKey) {
+  const concatenatedBuffer = Buffer.concat([
+    RAW_LEAF_TAG,
+    leaf,
+    Buffer.from(owner.toBytes()),
+    Buffer.from(tree.toBytes()),
+  ])
+  return keccak256(
+    new Uint8Array(
+      concatenatedBuffer.buffer,
+      concatenatedBuffer.byteOffset,
+      concatenatedBuffer.byteLength
+    )
+  )
+}
+
+// The message an owner signs to let a delegate replace the old leaf with the new one
+export function getDelegatedUpdateMessage(
+  tree: PublicKey,
+  index: number,
+  oldLeaf: Buffer,
+  newLeaf: Buffer,
+  nonce: number
+) {
+  const indexBuffer = Buffer.alloc(4)
+  indexBuffer.writeUInt32LE(index)
+  const concatenatedBuffer = Buffer.concat([
+    Buffer.from("update_note_delegated"),
+    Buffer.from(tree.toBytes()),
+    indexBuffer,
+    oldLeaf,
+    newLeaf,
+    new BN(nonce).toArrayLike(Buffer, "le", 8),
+  ])
+  return Buffer.from(keccak256.digest(concatenatedBuffer))
+}
+
+// The proof of any leaf in an empty tree, ordered from the leaf up
+export function getEmptyProof(maxDepth: number) {
+  const proof: Buffer[] = []
+  let node = Buffer.alloc(32)
+  for (let level = 0; level < maxDepth; level++) {
+    proof.push(node)
+    node = Buffer.from(keccak256.digest(Buffer.concat([node, node])))
+  }
+  return proof
+}
+
+class RawNoteLog {
+  version: number
+  tag: Uint8Array
+  leafNode: Uint8Array
+  owner: PublicKey
+  tree: PublicKey
+  data: Buffer
+  createdAt: BN
+  index: BN
+
+  constructor(properties: {
+    version: number
+    tag: Uint8Array
+    leafNode: Uint8Array
+    owner: Uint8Array
+    tree: Uint8Array
+    data: number[]
+    createdAt: BN
+    index: BN
+  }) {
+    this.version = properties.version
+    this.tag = properties.tag
+    this.leafNode = properties.leafNode
+    this.owner = new PublicKey(properties.owner)
+    this.tree = new PublicKey(properties.tree)
+    this.data = Buffer.from(properties.data)
+    this.createdAt = properties.createdAt
+    this.index = properties.index
+  }
+}
+
+// A map that describes the RawNote structure for Borsh deserialization
+const RawNoteLogBorshSchema = new Map([
+  [
+    RawNoteLog,
+    {
+      kind: "struct",
+      fields: [
+        ["version", "u8"],
+        ["tag", [8]], // RAW_NOTE_LOG_TAG
+        ["leafNode", [32]], // Array of 32 `u8`
+        ["owner", [32]], // Pubkey
+        ["tree", [32]], // Pubkey
+        ["data", ["u8"]], // Vec<u8>
+        ["createdAt", "u64"], // i64 on-chain, always positive
+        ["index", "u64"],
+      ],
+    },
+  ],
+])
+
+class CompressedNoteLog {
+  version: number
+  tag: Uint8Array
+  leafNode: Uint8Array
+  owner: PublicKey
+  tree: PublicKey
+  algo: number
+  data: Buffer
+  createdAt: BN
+  index: BN
+
+  constructor(properties: {
+    version: number
+    tag: Uint8Array
+    leafNode: Uint8Array
+    owner: Uint8Array
+    tree: Uint8Array
+    algo: number
+    data: number[]
+    createdAt: BN
+    index: BN
+  }) {
+    this.version = properties.version
+    this.tag = properties.tag
+    this.leafNode = properties.leafNode
+    this.owner = new PublicKey(properties.owner)
+    this.tree = new PublicKey(properties.tree)
+    this.algo = properties.algo
+    this.data = Buffer.from(properties.data)
+    this.createdAt = properties.createdAt
+    this.index = properties.index
+  }
+}
+
+// A map that describes the CompressedNote structure for Borsh deserialization
+const CompressedNoteLogBorshSchema = new Map([
+  [
+    CompressedNoteLog,
+    {
+      kind: "struct",
+      fields: [
+        ["version", "u8"],
+        ["tag", [8]], // COMPRESSED_NOTE_LOG_TAG
+        ["leafNode", [32]], // Array of 32 `u8`
+        ["owner", [32]], // Pubkey
+        ["tree", [32]], // Pubkey
+        ["algo", "u8"], // 0 = rle, 1 = zstd
+        ["data", ["u8"]], // Vec<u8>
+        ["createdAt", "u64"], // i64 on-chain, always positive
+        ["index", "u64"],
+      ],
+    },
+  ],
+])
+
+// The leaf of a titled note, hashing the title after its length as a u32.
+// An empty title hashes the same as getHash.
+export function getTitledHash(
+  title: string,
+  note: string,
+  owner: PublicKey,
+  tree: PublicKey
+) {
+  if (title === "") {
+    return getHash(note.normalize("NFC"), owner, tree)
+  }
+  const titleBuffer = Buffer.from(title.normalize("NFC"))
+  const titleLength = Buffer.alloc(4)
+  titleLength.writeUInt32LE(titleBuffer.length)
+  const concatenatedBuffer = Buffer.concat([
+    TITLED_NOTE_LEAF_TAG,
+    titleLength,
+    titleBuffer,
+    Buffer.from(note.normalize("NFC")),
+    Buffer.from(owner.toBytes()),
+    Buffer.from(tree.toBytes()),
+  ])
+  return keccak256(
+    new Uint8Array(
+      concatenatedBuffer.buffer,
+      concatenatedBuffer.byteOffset,
+      concatenatedBuffer.byteLength
+    )
+  )
+}
+
+// The leaf of an archived note, hashing the archived status after the tree
+export function getArchivedHash(
+  note: string,
+  owner: PublicKey,
+  tree: PublicKey
+) {
+  const concatenatedBuffer = Buffer.concat([
+    NOTE_LEAF_TAG,
+    Buffer.from(note),
+    Buffer.from(owner.toBytes()),
+    Buffer.from(tree.toBytes()),
+    Buffer.from([1]),
+  ])
+  return keccak256(
+    new Uint8Array(
+      concatenatedBuffer.buffer,
+      concatenatedBuffer.byteOffset,
+      concatenatedBuffer.byteLength
+    )
+  )
+}
+
+// The leaf of a note with an expiry, hashing the expiry as an i64 after the tree
+export function getExpiringHash(
+  note: string,
+  owner: PublicKey,
+  tree: PublicKey,
+  expiresAt: BN
+) {
+  const concatenatedBuffer = Buffer.concat([
+    EXPIRING_NOTE_LEAF_TAG,
+    Buffer.from(note),
+    Buffer.from(owner.toBytes()),
+    Buffer.from(tree.toBytes()),
+    expiresAt.toArrayLike(Buffer, "le", 8),
+  ])
+  return keccak256(
+    new Uint8Array(
+      concatenatedBuffer.buffer,
+      concatenatedBuffer.byteOffset,
+      concatenatedBuffer.byteLength
+    )
+  )
+}
+
+// The leaf of a note with a nonce, hashing the nonce as a u64 after the tree
+export function getNoncedHash(
+  note: string,
+  owner: PublicKey,
+  tree: PublicKey,
+  nonce: BN
+) {
+  const concatenatedBuffer = Buffer.concat([
+    NONCED_NOTE_LEAF_TAG,
+    Buffer.from(note),
+    Buffer.from(owner.toBytes()),
+    Buffer.from(tree.toBytes()),
+    nonce.toArrayLike(Buffer, "le", 8),
+  ])
+  return keccak256(
+    new Uint8Array(
+      concatenatedBuffer.buffer,
+      concatenatedBuffer.byteOffset,
+      concatenatedBuffer.byteLength
+    )
+  )
+}
+
+class TransferLog {
+  version: number
+  tag: Uint8Array
+  from: PublicKey
+  to: PublicKey
+  index: BN
+  leaf: Uint8Array
+  ts: BN
+
+  constructor(properties: {
+    version: number
+    tag: Uint8Array
+    from: Uint8Array
+    to: Uint8Array
+    index: BN
+    leaf: Uint8Array
+    ts: BN
+  }) {
+    this.version = properties.version
+    this.tag = properties.tag
+    this.from = new PublicKey(properties.from)
+    this.to = new PublicKey(properties.to)
+    this.index = properties.index
+    this.leaf = properties.leaf
+    this.ts = properties.ts
+  }
+}
+
+// A map that describes the TransferLog structure for Borsh deserialization
+const TransferLogBorshSchema = new Map([
+  [
+    TransferLog,
+    {
+      kind: "struct",
+      fields: [
+        ["version", "u8"],
+        ["tag", [8]], // TRANSFER_LOG_TAG
+        ["from", [32]], // Pubkey
+        ["to", [32]], // Pubkey
+        ["index", "u64"],
+        ["leaf", [32]], // Array of 32 `u8`
+        ["ts", "u64"], // i64 on-chain, always positive
+      ],
+    },
+  ],
+])
+
+class MessageLog {
+  version: number
+  tag: Uint8Array
+  leafNode: Uint8Array
+  sender: PublicKey
+  recipient: PublicKey
+  message: string
+  createdAt: BN
+  index: BN
+
+  constructor(properties: {
+    version: number
+    tag: Uint8Array
+    leafNode: Uint8Array
+    sender: Uint8Array
+    recipient: Uint8Array
+    message: string
+    createdAt: BN
+    index: BN
+  }) {
+    this.version = properties.version
+    this.tag = properties.tag
+    this.leafNode = properties.leafNode
+    this.sender = new PublicKey(properties.sender)
+    this.recipient = new PublicKey(properties.recipient)
+    this.message = properties.message
+    this.createdAt = properties.createdAt
+    this.index = properties.index
+  }
+}
+
+// A map that describes the Message structure for Borsh deserialization
+const MessageLogBorshSchema = new Map([
+  [
+    MessageLog,
+    {
+      kind: "struct",
+      fields: [
+        ["version", "u8"],
+        ["tag", [8]], // MESSAGE_LOG_TAG
+        ["leafNode", [32]], // Array of 32 `u8`
+        ["sender", [32]], // Pubkey
+        ["recipient", [32]], // Pubkey
+        ["message", "string"],
+        ["createdAt", "u64"], // i64 on-chain, always positive
+        ["index", "u64"],
+      ],
+    },
+  ],
+])
+
+export function getMessageHash(
+  message: string,
+  sender: PublicKey,
+  recipient: PublicKey,
+  tree: PublicKey
+) {
+  const concatenatedBuffer = Buffer.concat([
+    MESSAGE_LEAF_TAG,
+    Buffer.from(message),
+    Buffer.from(sender.toBytes()),
+    Buffer.from(recipient.toBytes()),
+    Buffer.from(tree.toBytes()),
+  ])
+  return keccak256(
+    new Uint8Array(
+      concatenatedBuffer.buffer,
+      concatenatedBuffer.byteOffset,
+      concatenatedBuffer.byteLength
+    )
+  )
+}
+
+// Returns the application data logged through the noop program, last first
+export async function getApplicationData(
+  connection: Connection,
+  txSignature: string
+) {
   // Confirm the transaction, otherwise the getTransaction sometimes returns null
   const latestBlockHash = await connection.getLatestBlockhash()
   await connection.confirmTransaction({
@@ -76,7 +574,7 @@ export async function getNoteLog(connection: Connection, txSignature: string) {
       ].toBase58() === SPL_NOOP_PROGRAM_ID.toBase58()
   )
 
-  let noteLog: NoteLog
+  const applicationData: Buffer[] = []
   for (let i = noopInnerIx.length - 1; i >= 0; i--) {
     try {
       // Try to decode and deserialize the instruction data
@@ -85,20 +583,69 @@ export async function getNoteLog(connection: Connection, txSignature: string) {
       )
 
       // Get the application data
-      const applicationData = applicationDataEvent.fields[0].applicationData
-
-      // Deserialize the application data into NoteLog instance
-      noteLog = deserialize(
-        NoteLogBorshSchema,
-        NoteLog,
-        Buffer.from(applicationData)
+      applicationData.push(
+        Buffer.from(applicationDataEvent.fields[0].applicationData)
       )
-
-      if (noteLog !== undefined) {
-        break
-      }
     } catch (__) {}
   }
 
-  return noteLog
+  return applicationData
+}
+
+// Returns the first log with the tag, last logged first. Every log starts with the version byte
+// followed by the tag of its kind, so logs are told apart without trying to parse each of them
+async function getLogOfTag(
+  connection: Connection,
+  txSignature: string,
+  tag: Buffer
+) {
+  for (const data of await getApplicationData(connection, txSignature)) {
+    if (data.subarray(1, 9).equals(tag)) {
+      return data
+    }
+  }
+}
+
+export async function getNoteLog(connection: Connection, txSignature: string) {
+  const data = await getLogOfTag(connection, txSignature, NOTE_LOG_TAG)
+  return data && deserialize(NoteLogBorshSchema, NoteLog, data)
+}
+
+export async function getRawNoteLog(
+  connection: Connection,
+  txSignature: string
+) {
+  const data = await getLogOfTag(connection, txSignature, RAW_NOTE_LOG_TAG)
+  return data && deserialize(RawNoteLogBorshSchema, RawNoteLog, data)
+}
+
+export async function getCompressedNoteLog(
+  connection: Connection,
+  txSignature: string
+) {
+  const data = await getLogOfTag(
+    connection,
+    txSignature,
+    COMPRESSED_NOTE_LOG_TAG
+  )
+  return (
+    data &&
+    deserialize(CompressedNoteLogBorshSchema, CompressedNoteLog, data)
+  )
+}
+
+export async function getTransferLog(
+  connection: Connection,
+  txSignature: string
+) {
+  const data = await getLogOfTag(connection, txSignature, TRANSFER_LOG_TAG)
+  return data && deserialize(TransferLogBorshSchema, TransferLog, data)
+}
+
+export async function getMessageLog(
+  connection: Connection,
+  txSignature: string
+) {
+  const data = await getLogOfTag(connection, txSignature, MESSAGE_LOG_TAG)
+  return data && deserialize(MessageLogBorshSchema, MessageLog, data)
 }